@@ -2,6 +2,7 @@ $(document).ready(function() {
 
     var ws;
     var macAddrMap;
+    var macIps = {};
 
     function mkForce(nodes, links, width, height) {
         return d3.layout.force()
@@ -137,13 +138,37 @@ $(document).ready(function() {
             })
             .attr("dx", 12)
             .attr("dy", ".35em")
-            .text(function(d) {
-                var alias = macAddrMap[d.addr];
-                return alias ? alias : d.addr;
-            });
+            .text(nodeLabel);
 
     };
 
+    //MACs are labelled with the IPv4 addresses ARP has bound to them.
+    function nodeLabel(d) {
+        var alias = macAddrMap[d.addr];
+        var label = alias ? alias : d.addr;
+        var ips = macIps[d.addr];
+        return ips ? label + " (" + Object.keys(ips).join(", ") + ")" : label;
+    }
+
+    function loadArp(msg) {
+        var b = msg.binding;
+        for(var mac in macIps) {
+            delete macIps[mac][b.ip];
+            if(Object.keys(macIps[mac]).length === 0) {
+                delete macIps[mac];
+            }
+        }
+        macIps[b.mac] = macIps[b.mac] || {};
+        macIps[b.mac][b.ip] = true;
+        types.mac.chart.selectAll(".nodetext").text(nodeLabel);
+    }
+
+    //Newest first, only the last few kept.
+    function loadAlert(msg) {
+        $('<div class="alert alert-danger">').text(msg.msg).prependTo('#alerts');
+        $('#alerts .alert').slice(5).remove();
+    }
+
     function updateNode(c, from, to) {
         var updateLinks = false;
         var index = c.nodeMap[from.addr];
@@ -176,7 +201,7 @@ $(document).ready(function() {
 
         var route = msg.route;
 
-        var linkKey = route.a.addr+"_"+route.b.addr;
+        var linkKey = msg.iface+"_"+route.a.addr+"_"+route.b.addr;
         var oldLinkNode = c.linkNodes[linkKey];
         if(oldLinkNode) {
             var oldA = c.nodes[c.nodeMap[oldLinkNode.a.addr]];
@@ -225,6 +250,10 @@ $(document).ready(function() {
             //console.log(msg);
             if(msg.typ === undefined) {
                 macAddrMap = msg;
+            } else if(msg.typ === 'arp') {
+                loadArp(msg);
+            } else if(msg.typ === 'alert') {
+                loadAlert(msg);
             } else {
                 loadUpdate(msg);
             }
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use arp::{ArpPkt, ArpTable};
+use ether::MacAddr;
+use ip::IP4Addr;
+use pkt_graph::Iface;
+
+/// What counts as suspicious ARP traffic, from the [alerts] and known-macs tables
+/// of the config file.
+#[derive(Clone, Debug)]
+pub struct AlertConf {
+    /// How many IPs one MAC can claim on an interface before it's reported.
+    pub max_ips_per_mac: usize,
+    /// How long, in seconds of capture time, an IP still counts as claimed after
+    /// the MAC was last seen at it.
+    pub binding_age: i64,
+    /// Gratuitous ARPs per second on an interface that make a storm.
+    pub gratuitous_rate: u64,
+    /// The only IPs the MACs listed here should turn up at.
+    pub known_ips: HashMap<MacAddr, Vec<IP4Addr>>
+}
+
+impl Default for AlertConf {
+    fn default() -> AlertConf {
+        AlertConf {
+            max_ips_per_mac: 4,
+            binding_age: 300,
+            gratuitous_rate: 20,
+            known_ips: HashMap::new()
+        }
+    }
+}
+
+/// ARP traffic that looks like spoofing or a misconfiguration.
+#[derive(Clone, Debug)]
+pub enum Alert {
+    /// An IP that moved to another MAC: the IP, and the old and new MACs.
+    MacChanged(Iface, IP4Addr, MacAddr, MacAddr),
+    /// A MAC and all the IPs it's claimed, when that's more than allowed.
+    ManyIps(Iface, MacAddr, Vec<IP4Addr>),
+    /// Gratuitous ARPs per second.
+    GratuitousStorm(Iface, u64),
+    /// A MAC from known-macs at an IP it isn't listed with.
+    UnexpectedIp(Iface, MacAddr, IP4Addr)
+}
+
+impl Alert {
+    /// A short name for the kind of alert, for the websocket.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Alert::MacChanged(..) => "mac-changed",
+            Alert::ManyIps(..) => "many-ips",
+            Alert::GratuitousStorm(..) => "gratuitous-storm",
+            Alert::UnexpectedIp(..) => "unexpected-ip"
+        }
+    }
+
+    pub fn iface(&self) -> &Iface {
+        match *self {
+            Alert::MacChanged(ref iface, ..) |
+            Alert::ManyIps(ref iface, ..) |
+            Alert::GratuitousStorm(ref iface, ..) |
+            Alert::UnexpectedIp(ref iface, ..) => iface
+        }
+    }
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Alert::MacChanged(ref iface, ip, old, new) => {
+                write!(f, "{}: {} moved from {} to {}", iface, ip, old, new)
+            }
+            Alert::ManyIps(ref iface, mac, ref ips) => {
+                let ips: Vec<_> = ips.iter().map(|ip| ip.to_string()).collect();
+                write!(f, "{}: {} claims {} addresses: {}", iface, mac, ips.len(), ips.join(", "))
+            }
+            Alert::GratuitousStorm(ref iface, rate) => {
+                write!(f, "{}: gratuitous ARP storm at {} packets/s", iface, rate)
+            }
+            Alert::UnexpectedIp(ref iface, mac, ip) => {
+                write!(f, "{}: known mac {} showed up at {}", iface, mac, ip)
+            }
+        }
+    }
+}
+
+/// Watches ARP traffic for the alerts, keeping what it needs to spot storms.
+pub struct ArpWatch {
+    conf: AlertConf,
+    /// Start of the current window and the gratuitous packets seen in it, per
+    /// interface, measured in capture time like `RateWatch`.
+    storms: HashMap<Iface, (f64, u64)>
+}
+
+impl ArpWatch {
+    pub fn new(conf: AlertConf) -> ArpWatch {
+        ArpWatch { conf: conf, storms: HashMap::new() }
+    }
+
+    /// Alerts for `pkt`, which `table` has already been updated with.  `prev` is the
+    /// MAC its sender's IP was bound to before.
+    pub fn check(&mut self, pkt: &ArpPkt, prev: Option<MacAddr>, table: &ArpTable)
+                 -> Vec<Alert> {
+        let mut out = Vec::new();
+        if pkt.is_gratuitous() {
+            if let Some(alert) = self.check_storm(pkt) {
+                out.push(alert);
+            }
+        }
+        // The rest only need checking when a binding is new.
+        if pkt.is_probe() || prev == Some(pkt.sender_mac) {
+            return out;
+        }
+        let (iface, ip, mac) = (&pkt.iface, pkt.sender_ip, pkt.sender_mac);
+        if let Some(old) = prev {
+            out.push(Alert::MacChanged(iface.clone(), ip, old, mac));
+        }
+        let expected = self.conf.known_ips.get(&mac);
+        if let Some(ips) = expected {
+            if !ips.contains(&ip) {
+                out.push(Alert::UnexpectedIp(iface.clone(), mac, ip));
+            }
+        }
+        let mut ips = table.ips_for(iface, &mac, pkt.tm.sec - self.conf.binding_age);
+        // A known MAC may claim as many of its listed addresses as it likes.
+        let unlisted = ips.iter()
+            .filter(|ip| expected.map_or(true, |e| !e.contains(ip)))
+            .count();
+        if unlisted > self.conf.max_ips_per_mac {
+            ips.sort_by_key(|ip| ip.to_string());
+            out.push(Alert::ManyIps(iface.clone(), mac, ips));
+        }
+        out
+    }
+
+    fn check_storm(&mut self, pkt: &ArpPkt) -> Option<Alert> {
+        let ts = pkt.tm.sec as f64 + f64::from(pkt.tm.nsec) / 1_000_000_000.0;
+        let window = self.storms.entry(pkt.iface.clone()).or_insert((ts, 0));
+        window.1 += 1;
+
+        let elapsed = ts - window.0;
+        if elapsed < 1.0 {
+            return None;
+        }
+        let rate = (window.1 as f64 / elapsed) as u64;
+        *window = (ts, 0);
+        if rate > self.conf.gratuitous_rate {
+            Some(Alert::GratuitousStorm(pkt.iface.clone(), rate))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+fn ip(s: &str) -> IP4Addr {
+    IP4Addr::from_string(s).unwrap()
+}
+
+#[cfg(test)]
+fn feed(watch: &mut ArpWatch, table: &mut ArpTable, pkt: &ArpPkt) -> Vec<&'static str> {
+    let prev = table.get(&pkt.iface, &pkt.sender_ip).map(|b| b.mac);
+    table.update(pkt);
+    watch.check(pkt, prev, table).iter().map(|a| a.kind()).collect()
+}
+
+#[test]
+fn test_mac_changed() {
+    use arp::{arp_pkt, MAC_A, MAC_B};
+
+    let (mut w, mut t) = (ArpWatch::new(AlertConf::default()), ArpTable::new());
+    let none: Vec<&str> = vec![];
+    assert_eq!(feed(&mut w, &mut t, &arp_pkt(1, MAC_A, [10, 0, 0, 5], [10, 0, 0, 1], 0)), none);
+    assert_eq!(feed(&mut w, &mut t, &arp_pkt(2, MAC_A, [10, 0, 0, 5], [10, 0, 0, 1], 1)), none);
+    assert_eq!(feed(&mut w, &mut t, &arp_pkt(2, MAC_B, [10, 0, 0, 5], [10, 0, 0, 1], 2)),
+               vec!["mac-changed"]);
+    // Probes don't bind anything, so can't move an address.
+    assert_eq!(feed(&mut w, &mut t, &arp_pkt(1, MAC_A, [0, 0, 0, 0], [10, 0, 0, 5], 3)), none);
+}
+
+#[test]
+fn test_many_ips() {
+    use arp::{arp_pkt, MAC_A};
+
+    let conf = AlertConf { max_ips_per_mac: 2, ..AlertConf::default() };
+    let (mut w, mut t) = (ArpWatch::new(conf), ArpTable::new());
+    feed(&mut w, &mut t, &arp_pkt(2, MAC_A, [10, 0, 0, 5], [10, 0, 0, 1], 0));
+    feed(&mut w, &mut t, &arp_pkt(2, MAC_A, [10, 0, 0, 6], [10, 0, 0, 1], 0));
+    let pkt = arp_pkt(2, MAC_A, [10, 0, 0, 7], [10, 0, 0, 1], 0);
+    t.update(&pkt);
+    let alerts = w.check(&pkt, None, &t);
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0].to_string(),
+               "eth0: 02:42:ac:11:00:02 claims 3 addresses: 10.0.0.5, 10.0.0.6, 10.0.0.7");
+}
+
+#[test]
+fn test_many_ips_age_out() {
+    use arp::{arp_pkt, MAC_A};
+
+    let conf = AlertConf { max_ips_per_mac: 1, binding_age: 60, ..AlertConf::default() };
+    let (mut w, mut t) = (ArpWatch::new(conf), ArpTable::new());
+    let none: Vec<&str> = vec![];
+    // A host that's moved address is only claiming the new one.
+    assert_eq!(feed(&mut w, &mut t, &arp_pkt(2, MAC_A, [10, 0, 0, 5], [10, 0, 0, 1], 0)), none);
+    assert_eq!(feed(&mut w, &mut t, &arp_pkt(2, MAC_A, [10, 0, 0, 6], [10, 0, 0, 1], 100)),
+               none);
+    assert_eq!(feed(&mut w, &mut t, &arp_pkt(2, MAC_A, [10, 0, 0, 7], [10, 0, 0, 1], 120)),
+               vec!["many-ips"]);
+}
+
+#[test]
+fn test_known_macs() {
+    use arp::{arp_pkt, MAC_A};
+
+    let mut known_ips = HashMap::new();
+    known_ips.insert(MacAddr::from_bytes(&MAC_A), vec![ip("10.0.0.1"), ip("10.0.0.2")]);
+    let conf = AlertConf { known_ips: known_ips, ..AlertConf::default() };
+    let (mut w, mut t) = (ArpWatch::new(conf), ArpTable::new());
+    let none: Vec<&str> = vec![];
+    // Listed addresses don't count towards max_ips_per_mac.
+    assert_eq!(feed(&mut w, &mut t, &arp_pkt(2, MAC_A, [10, 0, 0, 1], [10, 0, 0, 9], 0)), none);
+    assert_eq!(feed(&mut w, &mut t, &arp_pkt(2, MAC_A, [10, 0, 0, 2], [10, 0, 0, 9], 0)), none);
+    assert_eq!(feed(&mut w, &mut t, &arp_pkt(2, MAC_A, [10, 0, 0, 3], [10, 0, 0, 9], 0)),
+               vec!["unexpected-ip"]);
+}
+
+#[test]
+fn test_gratuitous_storm() {
+    use arp::{arp_pkt, MAC_A};
+    use time;
+
+    let conf = AlertConf { gratuitous_rate: 5, ..AlertConf::default() };
+    let (mut w, mut t) = (ArpWatch::new(conf), ArpTable::new());
+    let mut alerts = vec![];
+    // Ten in the first second, then one a second.
+    for i in 0..10 {
+        let pkt = arp_pkt(1, MAC_A, [10, 0, 0, 5], [10, 0, 0, 5], 100);
+        let pkt = ArpPkt { tm: time::Timespec::new(100, i * 100_000_000), ..pkt };
+        alerts.extend(feed(&mut w, &mut t, &pkt));
+    }
+    assert!(alerts.is_empty());
+    for sec in 101..104 {
+        alerts.extend(feed(&mut w, &mut t, &arp_pkt(1, MAC_A, [10, 0, 0, 5], [10, 0, 0, 5], sec)));
+    }
+    assert_eq!(alerts, vec!["gratuitous-storm"]);
+}
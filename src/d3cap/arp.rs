@@ -0,0 +1,224 @@
+//! ARP, for the IPv4 addresses behind the MACs in the graph.  Only ARP for IPv4 over
+//! Ethernet-style addresses is decoded, which is what everyone uses it for.
+
+use std::collections::hash_map::{self, Entry, HashMap};
+
+use time;
+
+use ether::MacAddr;
+use ip::IP4Addr;
+use pkt_graph::Iface;
+use util::Be16;
+
+#[derive(Copy, Clone, Debug)]
+#[repr(packed)]
+pub struct ArpHeader {
+    pub htype: Be16,
+    pub ptype: Be16,
+    pub hlen: u8,
+    pub plen: u8,
+    pub oper: Be16,
+    pub sha: MacAddr,
+    pub spa: IP4Addr,
+    pub tha: MacAddr,
+    pub tpa: IP4Addr
+}
+
+pub const HTYPE_ETHER: u16 = 1;
+pub const HTYPE_IEEE802: u16 = 6;
+
+const OPER_REQUEST: u16 = 1;
+const OPER_REPLY: u16 = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ArpOp {
+    Request,
+    Reply
+}
+
+/// An ARP packet, as sent from the capture threads to the graphs.
+#[derive(Debug)]
+pub struct ArpPkt {
+    pub op: ArpOp,
+    pub sender_mac: MacAddr,
+    pub sender_ip: IP4Addr,
+    pub target_ip: IP4Addr,
+    pub tm: time::Timespec,
+    pub iface: Iface
+}
+
+impl ArpPkt {
+    /// None for operations other than request and reply, like RARP.
+    pub fn new(hdr: &ArpHeader, tm: time::Timespec, iface: Iface) -> Option<ArpPkt> {
+        let op = match hdr.oper.get() {
+            OPER_REQUEST => ArpOp::Request,
+            OPER_REPLY => ArpOp::Reply,
+            _ => return None
+        };
+        Some(ArpPkt {
+            op: op,
+            sender_mac: hdr.sha,
+            sender_ip: hdr.spa,
+            target_ip: hdr.tpa,
+            tm: tm,
+            iface: iface
+        })
+    }
+
+    /// Gratuitous ARP announces the sender's own binding, asking about or answering
+    /// for its own address.
+    pub fn is_gratuitous(&self) -> bool {
+        self.sender_ip == self.target_ip && !self.is_probe()
+    }
+
+    /// Probes come from hosts checking that an address is free before taking it,
+    /// and have no sender address, so they don't bind anything.
+    pub fn is_probe(&self) -> bool {
+        self.sender_ip.is_unspecified()
+    }
+}
+
+/// An IP address and the MAC it was last seen at.  Times are in seconds since the
+/// epoch, from the capture.
+#[derive(RustcEncodable, Clone, Debug)]
+pub struct Binding {
+    pub iface: Iface,
+    pub ip: IP4Addr,
+    pub mac: MacAddr,
+    pub first_seen: i64,
+    pub last_seen: i64,
+    /// How many ARP packets of each kind have claimed this binding.  Gratuitous
+    /// packets are counted here and not as requests or replies.
+    pub requests: u64,
+    pub replies: u64,
+    pub gratuitous: u64
+}
+
+/// The bindings ARP senders have claimed, by interface and IP.  A binding is
+/// replaced when its IP turns up at a different MAC.
+pub struct ArpTable {
+    bindings: HashMap<(Iface, IP4Addr), Binding>
+}
+
+impl ArpTable {
+    pub fn new() -> ArpTable {
+        ArpTable { bindings: HashMap::new() }
+    }
+
+    /// Record the binding claimed by the sender of `pkt`, returning it as updated.
+    /// None for probes.
+    pub fn update(&mut self, pkt: &ArpPkt) -> Option<&Binding> {
+        if pkt.is_probe() {
+            return None;
+        }
+        let fresh = || Binding {
+            iface: pkt.iface.clone(),
+            ip: pkt.sender_ip,
+            mac: pkt.sender_mac,
+            first_seen: pkt.tm.sec,
+            last_seen: pkt.tm.sec,
+            requests: 0,
+            replies: 0,
+            gratuitous: 0
+        };
+        let binding = match self.bindings.entry((pkt.iface.clone(), pkt.sender_ip)) {
+            Entry::Vacant(e) => e.insert(fresh()),
+            Entry::Occupied(e) => {
+                let b = e.into_mut();
+                if b.mac != pkt.sender_mac {
+                    *b = fresh();
+                }
+                b
+            }
+        };
+        binding.last_seen = binding.last_seen.max(pkt.tm.sec);
+        match pkt.op {
+            _ if pkt.is_gratuitous() => binding.gratuitous += 1,
+            ArpOp::Request => binding.requests += 1,
+            ArpOp::Reply => binding.replies += 1
+        }
+        Some(binding)
+    }
+
+    pub fn get(&self, iface: &Iface, ip: &IP4Addr) -> Option<&Binding> {
+        self.bindings.get(&(iface.clone(), *ip))
+    }
+
+    /// Every IP bound to `mac` on `iface` that it's been seen at since `since`.
+    pub fn ips_for(&self, iface: &Iface, mac: &MacAddr, since: i64) -> Vec<IP4Addr> {
+        self.bindings.values()
+            .filter(|b| b.iface == *iface && b.mac == *mac && b.last_seen >= since)
+            .map(|b| b.ip)
+            .collect()
+    }
+
+    pub fn iter<'a>(&'a self) -> hash_map::Values<'a, (Iface, IP4Addr), Binding> {
+        self.bindings.values()
+    }
+}
+
+#[cfg(test)]
+pub fn arp_pkt(oper: u8, sha: [u8; 6], spa: [u8; 4], tpa: [u8; 4], sec: i64) -> ArpPkt {
+    use std::sync::Arc;
+    use decode;
+
+    let mut data = vec![0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, oper];
+    data.extend(&sha);
+    data.extend(&spa);
+    data.extend(&[0; 6]);
+    data.extend(&tpa);
+    let (hdr, _) = decode::arp(&data).unwrap();
+    ArpPkt::new(hdr, time::Timespec::new(sec, 0), Arc::new("eth0".to_owned())).unwrap()
+}
+
+#[cfg(test)]
+pub const MAC_A: [u8; 6] = [0x02, 0x42, 0xac, 0x11, 0x00, 0x02];
+
+#[cfg(test)]
+pub const MAC_B: [u8; 6] = [0x02, 0x42, 0xac, 0x11, 0x00, 0x03];
+
+#[test]
+fn test_arp_kinds() {
+    let req = arp_pkt(1, MAC_A, [192, 168, 1, 10], [192, 168, 1, 1], 0);
+    assert_eq!(req.op, ArpOp::Request);
+    assert_eq!(req.sender_mac.to_string(), "02:42:ac:11:00:02");
+    assert_eq!(req.sender_ip.to_string(), "192.168.1.10");
+    assert_eq!(req.target_ip.to_string(), "192.168.1.1");
+    assert!(!req.is_gratuitous() && !req.is_probe());
+
+    let announce = arp_pkt(1, MAC_A, [192, 168, 1, 10], [192, 168, 1, 10], 0);
+    assert!(announce.is_gratuitous());
+    let reply = arp_pkt(2, MAC_A, [192, 168, 1, 10], [192, 168, 1, 10], 0);
+    assert!(reply.op == ArpOp::Reply && reply.is_gratuitous());
+
+    let probe = arp_pkt(1, MAC_A, [0, 0, 0, 0], [192, 168, 1, 10], 0);
+    assert!(probe.is_probe() && !probe.is_gratuitous());
+}
+
+#[test]
+fn test_arp_table() {
+    let mut table = ArpTable::new();
+    assert!(table.update(&arp_pkt(1, MAC_A, [0, 0, 0, 0], [10, 0, 0, 5], 100)).is_none());
+
+    table.update(&arp_pkt(1, MAC_A, [10, 0, 0, 5], [10, 0, 0, 1], 100));
+    table.update(&arp_pkt(2, MAC_A, [10, 0, 0, 5], [10, 0, 0, 1], 105));
+    let b = table.update(&arp_pkt(1, MAC_A, [10, 0, 0, 5], [10, 0, 0, 5], 110)).unwrap().clone();
+    assert_eq!(b.mac, MacAddr::from_bytes(&MAC_A));
+    assert_eq!((b.first_seen, b.last_seen), (100, 110));
+    assert_eq!((b.requests, b.replies, b.gratuitous), (1, 1, 1));
+
+    // A new MAC for the address starts a new binding.
+    let b = table.update(&arp_pkt(2, MAC_B, [10, 0, 0, 5], [10, 0, 0, 1], 120)).unwrap().clone();
+    assert_eq!(b.mac, MacAddr::from_bytes(&MAC_B));
+    assert_eq!((b.first_seen, b.last_seen), (120, 120));
+    assert_eq!((b.requests, b.replies, b.gratuitous), (0, 1, 0));
+    assert_eq!(table.iter().count(), 1);
+
+    table.update(&arp_pkt(2, MAC_B, [10, 0, 0, 6], [10, 0, 0, 1], 120));
+    assert_eq!(table.iter().count(), 2);
+    let iface = &arp_pkt(1, MAC_B, [10, 0, 0, 6], [10, 0, 0, 1], 120).iface;
+    let ip = IP4Addr::from_string("10.0.0.6").unwrap();
+    assert_eq!(table.get(iface, &ip).unwrap().mac, MacAddr::from_bytes(&MAC_B));
+    assert_eq!(table.ips_for(iface, &MacAddr::from_bytes(&MAC_B), 120).len(), 2);
+    assert_eq!(table.ips_for(iface, &MacAddr::from_bytes(&MAC_B), 121).len(), 0);
+}
@@ -5,11 +5,18 @@ use std::fmt::{Display};
 use std::thread::{self, JoinHandle};
 use std::io::{self};
 
-use d3cap::{D3capController, ProtocolHandler, PhysDataController};
+use time;
+
+use d3cap::{D3capController, ProtocolHandler, PhysDataController, ArpHandler, CaptureCmd,
+            CaptureInfo, CaptureSource, CaptureStats, RecentAlerts};
 use ether::{MacAddr};
 use ip::{AsStdIpAddr};
+use pkt_graph::ProtocolGraph;
 
 use readline::readline;
+use replay::Speed;
+use record::{RecordSpec, Rotation};
+use pcap::pcap as cap;
 
 trait TransAddr<T> {
     fn trans(&mut self, addr: &T) -> String;
@@ -55,6 +62,62 @@ impl From<io::Error> for CliErr {
     }
 }
 
+pub fn print_ifaces() {
+    let devs = match cap::list_devices() {
+        Ok(devs) => devs,
+        Err(e) => {
+            println!("Could not list interfaces: {}", e);
+            return;
+        }
+    };
+
+    for dev in &devs {
+        let mut flags = Vec::new();
+        if dev.flags.is_loopback() { flags.push("loopback") }
+        if dev.flags.is_up() { flags.push("up") }
+        if dev.flags.is_running() { flags.push("running") }
+        if dev.flags.is_wireless() { flags.push("wireless") }
+
+        match dev.description {
+            Some(ref desc) => println!("{} ({}) [{}]", dev.name, desc, flags.join(", ")),
+            None => println!("{} [{}]", dev.name, flags.join(", "))
+        }
+        for a in &dev.addresses {
+            let typ = if a.addr.is_ipv4() { "inet" } else { "inet6" };
+            match a.netmask {
+                Some(ref mask) => println!("    {} {} netmask {}", typ, a.addr, mask),
+                None => println!("    {} {}", typ, a.addr)
+            }
+        }
+    }
+}
+
+/// The link types a device offers, with its default marked.  Pick one with
+/// --datalink.  Opening the device takes the same privileges as capturing from it.
+pub fn print_datalinks(dev: Option<&str>) {
+    let res = match dev {
+        Some(dev) => cap::PcapSessionBuilder::new_dev(dev),
+        None => cap::PcapSessionBuilder::new()
+    }.and_then(|b| b.activate());
+    let sess = match res {
+        Ok(sess) => sess,
+        Err(e) => {
+            println!("Could not open interface: {}", e);
+            return;
+        }
+    };
+
+    let current = sess.datalink();
+    for dlt in sess.list_datalinks() {
+        let mark = if Some(dlt) == current { "*" } else { " " };
+        let name = cap::datalink_name(dlt).unwrap_or_else(|| dlt.to_string());
+        match cap::datalink_description(dlt) {
+            Some(desc) => println!("{} {} ({})", mark, name, desc),
+            None => println!("{} {}", mark, name)
+        }
+    }
+}
+
 type CliFn = (&'static str, Box<FnMut(Vec<&str>, &mut D3capController)->Result<(), CliErr>>);
 
 pub fn start_cli(ctrl: D3capController) -> io::Result<JoinHandle<()>> {
@@ -63,7 +126,23 @@ pub fn start_cli(ctrl: D3capController) -> io::Result<JoinHandle<()>> {
             where A: Eq+Hash+Copy+Clone+Display+Send+Sync,
                   T: TransAddr<A>
         {
-            let graph = ph.graph.read().unwrap();
+            print_graph(&ph.graph.read().unwrap(), t);
+        }
+
+        fn print_ls_iface_addr<A, T>(ph: &ProtocolHandler<A>, iface: &str, t: &mut T)
+            where A: Eq+Hash+Copy+Clone+Display+Send+Sync,
+                  T: TransAddr<A>
+        {
+            match ph.iface_graphs.read().unwrap().get(&iface.to_owned()) {
+                Some(graph) => print_graph(graph, t),
+                None => println!("no {} traffic seen on {}", ph.typ, iface)
+            }
+        }
+
+        fn print_graph<A, T>(graph: &ProtocolGraph<A>, t: &mut T)
+            where A: Eq+Hash+Copy+Clone+Display+Send+Sync,
+                  T: TransAddr<A>
+        {
             let mut list: Vec<_> = graph.iter()
                 .flat_map(|(src_addr, astats)| {
                     iter::repeat(src_addr).zip(astats.sent_iter())
@@ -94,6 +173,86 @@ pub fn start_cli(ctrl: D3capController) -> io::Result<JoinHandle<()>> {
             println!();
         }
 
+        fn print_ls_arp<T:TransAddr<MacAddr>>(arp: &ArpHandler, macs: &mut T) {
+            fn seen(sec: i64) -> String {
+                let tm = time::at(time::Timespec::new(sec, 0));
+                time::strftime("%Y-%m-%d %H:%M:%S", &tm).unwrap()
+            }
+
+            let table = arp.table.read().unwrap();
+            let mut list: Vec<_> = table.iter().collect();
+            list.sort_by_key(|b| (b.iface.clone(), b.ip.as_std_ip()));
+
+            for b in list {
+                println!("{}: {} is at {}: first seen {}, last seen {}, requests: {}, \
+                          replies: {}, gratuitous: {}",
+                         b.iface, b.ip, macs.trans(&b.mac), seen(b.first_seen),
+                         seen(b.last_seen), b.requests, b.replies, b.gratuitous);
+            }
+        }
+
+        fn print_ls_alerts(alerts: &RecentAlerts) {
+            for &(tm, ref alert) in alerts.read().unwrap().iter() {
+                let tm = time::strftime("%Y-%m-%d %H:%M:%S", &time::at(tm)).unwrap();
+                println!("{}: {}", tm, alert);
+            }
+        }
+
+        fn print_stats(iface: &str, stats: &CaptureStats) {
+            println!("{}:", iface);
+            match stats.pcap {
+                Some(ref st) => println!("received: {}, dropped: {}, ifdropped: {}",
+                                         st.received, st.dropped, st.ifdropped),
+                None => println!("no capture statistics from libpcap")
+            }
+            let c = &stats.counters;
+            println!("packets: {}, parsed: {}, unknown payloads: {}, bogus frames: {}, \
+                      truncated: {}, malformed: {}, unsupported link types: {}, \
+                      send failures: {}",
+                     c.packets, c.parsed, c.unknown_payload, c.bogus, c.truncated,
+                     c.malformed, c.unsupported, c.send_failures);
+        }
+
+        /// `record start <file> [size <mb>] [secs <n>] [keep <n>] [filter...]`
+        fn parse_record_args(path: &str, args: &[&str]) -> Option<RecordSpec> {
+            let mut rotation = Rotation::default();
+            let mut i = 0;
+            while i + 1 < args.len() {
+                // Limits of zero make no sense, so they're refused like bad numbers.
+                let n = args[i+1].parse::<u64>().ok().filter(|&n| n > 0);
+                match args[i] {
+                    "size" => rotation.max_bytes = Some(n? * 1024 * 1024),
+                    "secs" => rotation.max_secs = Some(n?),
+                    "keep" => rotation.keep = Some(n? as usize),
+                    _ => break
+                }
+                i += 2;
+            }
+            let filter = args[i..].join(" ");
+            Some(RecordSpec {
+                path: path.to_owned(),
+                filter: if filter.is_empty() { None } else { Some(filter) },
+                rotation: rotation
+            })
+        }
+
+        fn print_capture(c: &CaptureInfo) {
+            let typ = match c.source {
+                CaptureSource::Interface(_) => "interface",
+                CaptureSource::File(_) => "file"
+            };
+            let filter = match c.filter {
+                Some(ref f) => format!(", filter '{}'", f),
+                None => "".to_owned()
+            };
+            let recording = match c.recording {
+                Some(ref path) => format!(", recording to {}", path),
+                None => "".to_owned()
+            };
+            println!("{} ({}): {}{}{}, {} packets",
+                     c.iface, typ, c.state, filter, recording, c.stats.counters.packets);
+        }
+
         let mut ctrl = ctrl;
 
         let mut cmds: HashMap<String, CliFn> = HashMap::new();
@@ -117,13 +276,152 @@ pub fn start_cli(ctrl: D3capController) -> io::Result<JoinHandle<()>> {
                         Ok(())
                     })));
 
+        cmds.insert("filter".to_owned(),
+                    ("filter", Box::new(|cmd, ctrl| {
+                        let expr = cmd[1..].join(" ");
+                        for (iface, res) in ctrl.captures.set_filter(&expr) {
+                            match res {
+                                Ok(()) => println!("{}: filter set: '{}'", iface, expr),
+                                Err(e) => println!("{}: Could not set filter: {}", iface, e)
+                            }
+                        }
+                        Ok(())
+                    })));
+
+        cmds.insert("stats".to_owned(),
+                    ("stats", Box::new(|_, ctrl| {
+                        for c in ctrl.captures.list() {
+                            print_stats(&c.iface, &c.stats);
+                        }
+                        Ok(())
+                    })));
+
+        cmds.insert("capture".to_owned(),
+                    ("capture", Box::new(|cmd, ctrl| {
+                        let res = match cmd[1..] {
+                            ["start", src] => ctrl.captures.start(CaptureSource::guess(src)),
+                            ["stop"] => ctrl.captures.stop(None),
+                            ["stop", name] => ctrl.captures.stop(Some(name)),
+                            ["restart"] => ctrl.captures.restart(None),
+                            ["restart", name] => ctrl.captures.restart(Some(name)),
+                            ["list"] => {
+                                for c in ctrl.captures.list() {
+                                    print_capture(&c);
+                                }
+                                Ok(())
+                            }
+                            _ => { println!("Illegal argument"); Ok(()) }
+                        };
+                        if let Err(e) = res {
+                            println!("{}", e);
+                        }
+                        Ok(())
+                    })));
+
+        cmds.insert("record".to_owned(),
+                    ("record", Box::new(|cmd, ctrl| {
+                        match cmd[1..] {
+                            ["start", path, ..] => {
+                                let spec = match parse_record_args(path, &cmd[3..]) {
+                                    Some(spec) => spec,
+                                    None => {
+                                        println!("Illegal argument");
+                                        return Ok(());
+                                    }
+                                };
+                                match ctrl.captures.record(spec) {
+                                    Ok(res) => for (iface, r) in res {
+                                        match r {
+                                            Ok(p) => println!("{}: recording to {}", iface, p),
+                                            Err(e) => println!("{}: Could not record: {}", iface, e)
+                                        }
+                                    },
+                                    Err(e) => println!("{}", e)
+                                }
+                            }
+                            ["stop"] => match ctrl.captures.stop_recording() {
+                                Ok(files) => for (path, count) in files {
+                                    println!("wrote {} packets to {}", count, path);
+                                },
+                                Err(e) => println!("{}", e)
+                            },
+                            _ => println!("Illegal argument")
+                        }
+                        Ok(())
+                    })));
+
+        cmds.insert("snapshot".to_owned(),
+                    ("snapshot", Box::new(|_, ctrl| {
+                        match ctrl.captures.snapshot(None) {
+                            Ok(res) => for (iface, r) in res {
+                                match r {
+                                    Ok(Some(path)) => println!("{}: writing snapshot to {}",
+                                                               iface, path),
+                                    Ok(None) => println!("{}: snapshot extended", iface),
+                                    Err(e) => println!("{}: Could not snapshot: {}", iface, e)
+                                }
+                            },
+                            Err(e) => println!("{}", e)
+                        }
+                        Ok(())
+                    })));
+
+        cmds.insert("replay".to_owned(),
+                    ("replay", Box::new(|cmd, ctrl| {
+                        let res = match cmd[1..] {
+                            ["pause"] => ctrl.captures.replay(CaptureCmd::Pause),
+                            ["resume"] => ctrl.captures.replay(CaptureCmd::Resume),
+                            ["seek", secs] => match secs.parse::<f64>() {
+                                Ok(s) if s >= 0.0 => ctrl.captures.seek(s).map(|res| {
+                                    for (iface, r) in res {
+                                        if let Err(e) = r {
+                                            println!("{}: Could not seek: {}", iface, e);
+                                        }
+                                    }
+                                }),
+                                _ => { println!("Illegal argument"); Ok(()) }
+                            },
+                            ["speed", speed] => match Speed::parse(speed) {
+                                Some(s) => ctrl.captures.replay(CaptureCmd::Speed(s)),
+                                None => { println!("Illegal argument"); Ok(()) }
+                            },
+                            ["status"] => ctrl.captures.replay_status().map(|statuses| {
+                                for (iface, st) in statuses {
+                                    let speed = match st.speed {
+                                        Speed::Max => "max".to_owned(),
+                                        Speed::Scaled(x) => format!("{}x", x)
+                                    };
+                                    println!("{}: position: {:.3}s, speed: {}{}",
+                                             iface, st.position, speed,
+                                             if st.paused { ", paused" } else { "" });
+                                }
+                            }),
+                            _ => { println!("Illegal argument"); Ok(()) }
+                        };
+                        if let Err(e) = res {
+                            println!("{}", e);
+                        }
+                        Ok(())
+                    })));
+
         cmds.insert("ls".to_owned(),
                     ("ls", Box::new(|cmd, ctrl| {
                         match cmd[1..] {
                             ["mac"] => print_ls_addr(&ctrl.pg_ctrl.mac, &mut ctrl.mac_names),
                             ["ip4"] => print_ls_addr(&ctrl.pg_ctrl.ip4, &mut ctrl.ip4_names),
                             ["ip6"] => print_ls_addr(&ctrl.pg_ctrl.ip6, &mut ctrl.ip6_names),
+                            ["mac", iface] => print_ls_iface_addr(&ctrl.pg_ctrl.mac, iface,
+                                                                  &mut ctrl.mac_names),
+                            ["ip4", iface] => print_ls_iface_addr(&ctrl.pg_ctrl.ip4, iface,
+                                                                  &mut ctrl.ip4_names),
+                            ["ip6", iface] => print_ls_iface_addr(&ctrl.pg_ctrl.ip6, iface,
+                                                                  &mut ctrl.ip6_names),
                             ["tap"] => print_ls_tap(&ctrl.pd_ctrl, &mut ctrl.mac_names),
+                            ["arp"] => print_ls_arp(&ctrl.pg_ctrl.arp, &mut ctrl.mac_names),
+                            ["alerts"] => print_ls_alerts(&ctrl.alerts),
+                            ["ifaces"] => print_ifaces(),
+                            ["datalinks"] => print_datalinks(None),
+                            ["datalinks", dev] => print_datalinks(Some(dev)),
                             _ => println!("Illegal argument")
                         }
                         Ok(())
@@ -1,23 +1,42 @@
 use std::thread::{self, JoinHandle};
 use std::hash::{Hash};
+use std::fmt;
 use std::collections::hash_map::{Entry, HashMap};
-use std::fs::File;
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::fs::{self, File};
 use std::io::{self, Read};
-use std::sync::{Arc,RwLock};
-use std::sync::mpsc::{channel, Sender, SendError};
-
+use std::mem;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::FromRawFd;
+use std::sync::{Arc,Mutex,RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, SendError, RecvTimeoutError};
+use std::time::Duration;
+
+use libc;
+use time;
 use toml;
+use rustc_serialize::{json, Encodable};
 
 use multicast::Multicast;
 use json_serve::uiserver::UIServer;
 
-use util::{ntohs, skip_bytes_cast, skip_cast};
+use alert::{Alert, AlertConf, ArpWatch};
+use arp::{ArpPkt, ArpTable, Binding};
+use util::Be32;
+use decode::{self, DecodeErr, Dot11Addrs, Header, Layer};
 use ip::{IP4Addr, IP6Addr, IP4Header, IP6Header};
-use ether::{EthernetHeader, MacAddr,
+use ether::{MacAddr,
             ETHERTYPE_ARP, ETHERTYPE_IP4, ETHERTYPE_IP6, ETHERTYPE_802_1X};
-use dot11::{self, FrameType};
+use dot11::FrameType;
 use tap;
-use pkt_graph::{PktMeta, ProtocolGraph, RouteStats};
+use radio::{Radio, RadioHeader};
+use sll::{SllHeader, Sll2Header};
+use pkt_graph::{Iface, PktMeta, ProtocolGraph, RouteStats};
+use replay::{Pace, Replay, ReplayStatus, Speed};
+use record::{PreEventBuffer, Recorder, RecordSpec, Rotation, SnapshotConf};
+use trigger::{NewMacWatch, RateWatch, Trigger, TriggerConf};
 use fixed_ring::FixedRingBuffer;
 use pcap::pcap as cap;
 
@@ -25,6 +44,7 @@ use pcap::pcap as cap;
 #[derive(RustcEncodable, Clone)]
 struct RouteStatsMsg<T> {
     typ: &'static str,
+    iface: Iface,
     route: RouteStats<T>,
 }
 
@@ -33,12 +53,15 @@ pub enum Pkt {
     Mac(PktMeta<MacAddr>),
     IP4(PktMeta<IP4Addr>),
     IP6(PktMeta<IP6Addr>),
+    Arp(ArpPkt),
 }
 
 #[derive(Clone)]
 pub struct ProtocolHandler<T:Eq+Hash+Send+Sync+'static> {
     pub typ: &'static str,
     pub graph: Arc<RwLock<ProtocolGraph<T>>>,
+    /// The same traffic, broken down by the interface it was captured on.
+    pub iface_graphs: Arc<RwLock<HashMap<Iface, ProtocolGraph<T>>>>,
     stats_mcast: Multicast<RouteStatsMsg<T>>,
 }
 
@@ -47,51 +70,185 @@ impl <T:Send+Sync+Copy+Clone+Eq+Hash> ProtocolHandler<T> {
         Ok(ProtocolHandler {
             typ: typ,
             graph: Arc::new(RwLock::new(ProtocolGraph::new())),
+            iface_graphs: Arc::new(RwLock::new(HashMap::new())),
             stats_mcast: Multicast::spawn()?
         })
     }
 
     fn update(&mut self, pkt: &PktMeta<T>) {
+        self.graph.write().unwrap().update(pkt);
         let route_stats = {
-            self.graph.write().unwrap().update(pkt)
+            let mut graphs = self.iface_graphs.write().unwrap();
+            graphs.entry(pkt.iface.clone()).or_insert_with(ProtocolGraph::new).update(pkt)
         };
         let route_stats_msg = Arc::new(RouteStatsMsg {
             typ: self.typ,
+            iface: pkt.iface.clone(),
             route: route_stats
         });
         self.stats_mcast.send(route_stats_msg).unwrap();
     }
 }
 
+impl <T:Send+Sync+Copy+Clone+Eq+Hash+Encodable> ProtocolHandler<T> {
+    /// Current routes as json messages, in the same format as live updates.
+    fn snapshot(&self) -> Vec<String> {
+        let graphs = self.iface_graphs.read().unwrap();
+        graphs.iter()
+            .flat_map(|(iface, graph)| {
+                graph.routes().into_iter().map(move |route| {
+                    json::encode(&RouteStatsMsg {
+                        typ: self.typ,
+                        iface: iface.clone(),
+                        route: route
+                    }).unwrap()
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(RustcEncodable, Clone)]
+struct ArpMsg {
+    typ: &'static str,
+    binding: Binding
+}
+
+#[derive(RustcEncodable, Clone)]
+struct AlertMsg {
+    typ: &'static str,
+    kind: &'static str,
+    iface: Iface,
+    msg: String
+}
+
+impl AlertMsg {
+    fn new(alert: &Alert) -> AlertMsg {
+        AlertMsg {
+            typ: "alert",
+            kind: alert.kind(),
+            iface: alert.iface().clone(),
+            msg: alert.to_string()
+        }
+    }
+}
+
+/// The IP to MAC bindings seen in ARP traffic.  Every ARP packet that claims a
+/// binding is pushed out as an "arp" message.  The captures update it themselves,
+/// rather than through the graphs' thread, so that an alert can be noted on the
+/// packet that raised it.
+#[derive(Clone)]
+pub struct ArpHandler {
+    pub table: Arc<RwLock<ArpTable>>,
+    watch: Arc<Mutex<ArpWatch>>,
+    alerts: Sender<Alert>,
+    mcast: Multicast<ArpMsg>
+}
+
+impl ArpHandler {
+    fn new(alerts: &AlertConf, alert_tx: Sender<Alert>) -> io::Result<ArpHandler> {
+        Ok(ArpHandler {
+            table: Arc::new(RwLock::new(ArpTable::new())),
+            watch: Arc::new(Mutex::new(ArpWatch::new(alerts.clone()))),
+            alerts: alert_tx,
+            mcast: Multicast::spawn()?
+        })
+    }
+
+    /// Record `pkt`, returning anything suspicious in it, which also goes out on the
+    /// alert channel.
+    fn update(&self, pkt: &ArpPkt) -> Vec<Alert> {
+        let mut table = self.table.write().unwrap();
+        let prev = table.get(&pkt.iface, &pkt.sender_ip).map(|b| b.mac);
+        let binding = table.update(pkt).cloned();
+        let alerts = self.watch.lock().unwrap().check(pkt, prev, &table);
+        if let Some(binding) = binding {
+            self.mcast.send(Arc::new(ArpMsg { typ: "arp", binding: binding })).unwrap();
+        }
+        for alert in &alerts {
+            let _ = self.alerts.send(alert.clone());
+        }
+        alerts
+    }
+
+    /// Current bindings as json messages, in the same format as live updates.
+    fn snapshot(&self) -> Vec<String> {
+        self.table.read().unwrap().iter()
+            .map(|b| json::encode(&ArpMsg { typ: "arp", binding: b.clone() }).unwrap())
+            .collect()
+    }
+}
+
 #[derive(Clone)]
 pub struct ProtoGraphController {
-    pub cap_tx: Sender<Pkt>,
+    pub cap_tx: Sender<Vec<Pkt>>,
     pub mac: ProtocolHandler<MacAddr>,
     pub ip4: ProtocolHandler<IP4Addr>,
     pub ip6: ProtocolHandler<IP6Addr>,
+    pub arp: ArpHandler,
 }
 
 impl ProtoGraphController {
-    fn spawn() -> io::Result<ProtoGraphController> {
+    /// Start the thread that feeds packets into the graphs.  Traffic that matches
+    /// `triggers` is reported on `trigger_tx`, except that MACs in `known` never
+    /// count as new.  ARP traffic that looks wrong by `alerts` is reported on
+    /// `alert_tx` by the captures, as they pass it to `arp`.
+    fn spawn(triggers: &TriggerConf,
+             known: &MacMap,
+             trigger_tx: Sender<Trigger>,
+             alerts: &AlertConf,
+             alert_tx: Sender<Alert>) -> io::Result<ProtoGraphController> {
+        fn check_rate<T>(watch: &mut Option<RateWatch<T>>,
+                         pkt: &PktMeta<T>,
+                         trigger_tx: &Sender<Trigger>)
+            where T: Hash+Eq+Copy+fmt::Display
+        {
+            if let Some(t) = watch.as_mut().and_then(|w| w.check(pkt)) {
+                let _ = trigger_tx.send(t);
+            }
+        }
+
         let (cap_tx, cap_rx) = channel();
         let ctl = ProtoGraphController {
             cap_tx: cap_tx,
             mac: ProtocolHandler::new("mac")?,
             ip4: ProtocolHandler::new("ip4")?,
             ip6: ProtocolHandler::new("ip6")?,
+            arp: ArpHandler::new(alerts, alert_tx)?,
         };
 
         let mut phctl = ctl.clone();
+        let mut new_mac = if triggers.new_mac {
+            Some(NewMacWatch::new(known.keys().cloned().collect()))
+        } else {
+            None
+        };
+        let mut mac_rate = triggers.route_rate.map(|r| RateWatch::new("mac", r));
+        let mut ip4_rate = triggers.route_rate.map(|r| RateWatch::new("ip4", r));
+        let mut ip6_rate = triggers.route_rate.map(|r| RateWatch::new("ip6", r));
         thread::Builder::new().name("protocol_handler".to_owned()).spawn(move || {
-            loop {
-                let pkt = cap_rx.recv();
-                if pkt.is_err() {
-                    break
-                }
-                match pkt.unwrap() {
-                    Pkt::Mac(ref p) => phctl.mac.update(p),
-                    Pkt::IP4(ref p) => phctl.ip4.update(p),
-                    Pkt::IP6(ref p) => phctl.ip6.update(p),
+            for pkt in cap_rx.iter().flat_map(|batch: Vec<Pkt>| batch) {
+                match pkt {
+                    Pkt::Mac(ref p) => {
+                        if let Some(ref mut watch) = new_mac {
+                            let graph = phctl.mac.graph.read().unwrap();
+                            for t in watch.check(p, |addr| graph.get_addr_stats(addr).is_some()) {
+                                let _ = trigger_tx.send(t);
+                            }
+                        }
+                        check_rate(&mut mac_rate, p, &trigger_tx);
+                        phctl.mac.update(p)
+                    }
+                    Pkt::IP4(ref p) => {
+                        check_rate(&mut ip4_rate, p, &trigger_tx);
+                        phctl.ip4.update(p)
+                    }
+                    Pkt::IP6(ref p) => {
+                        check_rate(&mut ip6_rate, p, &trigger_tx);
+                        phctl.ip6.update(p)
+                    }
+                    // The captures keep ARP to themselves; see Parsers::parse.
+                    Pkt::Arp(_) => {}
                 }
             }
         })?;
@@ -99,7 +256,7 @@ impl ProtoGraphController {
         Ok(ctl)
     }
 
-    fn sender(&self) -> Sender<Pkt> {
+    fn sender(&self) -> Sender<Vec<Pkt>> {
         self.cap_tx.clone()
     }
 
@@ -114,11 +271,18 @@ impl ProtoGraphController {
     fn register_ip6_listener(&self, s: Sender<Arc<RouteStatsMsg<IP6Addr>>>) {
         self.ip6.stats_mcast.register(s).unwrap();
     }
+
+    fn register_arp_listener(&self, s: Sender<Arc<ArpMsg>>) {
+        self.arp.mcast.register(s).unwrap();
+    }
 }
 
 enum ParseErr {
     Send,
-    UnknownPacket
+    UnknownPacket,
+    UnknownPayload,
+    UnsupportedDatalink,
+    Decode(DecodeErr)
 }
 
 impl<T> From<SendError<T>> for ParseErr {
@@ -127,371 +291,1842 @@ impl<T> From<SendError<T>> for ParseErr {
     }
 }
 
+impl From<DecodeErr> for ParseErr {
+    fn from(e: DecodeErr) -> ParseErr {
+        ParseErr::Decode(e)
+    }
+}
+
 trait PktParser {
-    fn parse(&mut self, pkt: &cap::PcapData) -> Result<(), ParseErr>;
+    /// Parse `pkt`, adding anything for the graphs to `out`.
+    fn parse(&mut self, pkt: &cap::PcapData, out: &mut Vec<Pkt>) -> Result<(), ParseErr>;
 }
 
-pub struct CaptureCtx {
-    sess: cap::PcapSession,
-    parser: Box<PktParser+'static>
+/// Requests sent from the controller to a running capture thread.  Replies go back
+/// over the included channel.
+#[derive(Clone)]
+pub enum CaptureCmd {
+    Filter(String, Sender<Result<(), cap::PcapError>>),
+    Pause,
+    Resume,
+    /// Seek forward to an offset in seconds, replying whether that could be done.
+    Seek(f64, Sender<bool>),
+    Speed(Speed),
+    Status(Sender<ReplayStatus>),
+    /// Start recording to a file as laid out in the spec, appending to it if the flag
+    /// is set.
+    Record(String, RecordSpec, bool, Sender<Result<(), cap::PcapError>>),
+    Stop,
 }
 
-impl CaptureCtx {
-    fn parse_next(&mut self) {
-        let p = &mut self.parser;
-        self.sess.next(|cap| {
-            match p.parse(cap) {
-                _ => () //just ignore
-            }
-        });
-    }
+/// Counts kept by the capture thread, on top of what libpcap reports.
+#[derive(RustcEncodable, Copy, Clone, Debug, Default)]
+pub struct CaptureCounters {
+    pub packets: u64,
+    pub parsed: u64,
+    pub unknown_payload: u64,
+    pub bogus: u64,
+    pub send_failures: u64,
+    /// Packets with a link type there's no parser for, which only happens when a
+    /// pcapng file mixes link types.
+    pub unsupported: u64,
+    /// Packets cut off partway through a header.
+    pub truncated: u64,
+    /// Packets with a header whose length or version field doesn't make sense.
+    pub malformed: u64
 }
 
-struct EthernetParser {
-    pkts: Sender<Pkt>,
+impl CaptureCounters {
+    fn record(&mut self, res: &Result<(), ParseErr>) {
+        self.packets += 1;
+        match *res {
+            Ok(()) => self.parsed += 1,
+            Err(ParseErr::UnknownPayload) => self.unknown_payload += 1,
+            Err(ParseErr::UnknownPacket) => self.bogus += 1,
+            Err(ParseErr::Send) => self.send_failures += 1,
+            Err(ParseErr::UnsupportedDatalink) => self.unsupported += 1,
+            Err(ParseErr::Decode(DecodeErr::Truncated(_))) => self.truncated += 1,
+            Err(ParseErr::Decode(_)) => self.malformed += 1
+        }
+    }
+
+    fn add(&mut self, other: &CaptureCounters) {
+        self.packets += other.packets;
+        self.parsed += other.parsed;
+        self.unknown_payload += other.unknown_payload;
+        self.bogus += other.bogus;
+        self.send_failures += other.send_failures;
+        self.unsupported += other.unsupported;
+        self.truncated += other.truncated;
+        self.malformed += other.malformed;
+    }
 }
 
-impl PktParser for EthernetParser {
+/// Snapshot of a capture's statistics, refreshed by the capture thread about once
+/// every `CAPSTATS_INTERVAL_SECS`.  `pcap` is None for capture files.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CaptureStats {
+    pub pcap: Option<cap::PcapStats>,
+    pub counters: CaptureCounters
+}
 
-    fn parse(&mut self, pkt: &cap::PcapData) -> Result<(), ParseErr> {
-        let ether_hdr = unsafe { &*(pkt.pkt_ptr() as *const EthernetHeader) };
-        self.pkts.send(Pkt::Mac(PktMeta::new(ether_hdr.src, ether_hdr.dst, pkt.len())))?;
-        match ether_hdr.typ {
-            ETHERTYPE_ARP => {
-                //io::println("ARP!");
-            },
-            ETHERTYPE_IP4 => {
-                let ipp: &IP4Header = unsafe { skip_cast(ether_hdr) };
-                self.pkts.send(Pkt::IP4(PktMeta::new(ipp.src, ipp.dst, u32::from(ntohs(ipp.len)))))?;
-            },
-            ETHERTYPE_IP6 => {
-                let ipp: &IP6Header = unsafe { skip_cast(ether_hdr) };
-                self.pkts.send(Pkt::IP6(PktMeta::new(ipp.src, ipp.dst, u32::from(ntohs(ipp.len)))))?;
-            },
-            ETHERTYPE_802_1X => {
-                //io::println("802.1X!");
-            },
-            _ => {
-                //println!("Unknown type: {:x}", x);
-            }
-        }
-        Ok(())
+impl CaptureStats {
+    /// Fold in the stats of another session reading the same traffic, as for the
+    /// threads of a fanout capture.
+    fn add(&mut self, other: &CaptureStats) {
+        self.pcap = match (self.pcap, other.pcap) {
+            (Some(a), Some(b)) => Some(cap::PcapStats {
+                received: a.received.wrapping_add(b.received),
+                dropped: a.dropped.wrapping_add(b.dropped),
+                ifdropped: a.ifdropped.wrapping_add(b.ifdropped)
+            }),
+            (a, b) => a.or(b)
+        };
+        self.counters.add(&other.counters);
     }
 }
 
-#[derive(Debug)]
-pub struct PhysData { // TODO: this name sucks
-    frame_ty: FrameType,
-    addrs: [MacAddr; 3],
-    rate: Option<tap::Rate>,
-    channel: tap::Channel,
-    antenna_signal: tap::AntennaSignal,
-    antenna_noise: tap::AntennaNoise,
-    antenna: tap::Antenna,
+pub const CAPSTATS_INTERVAL_SECS: u64 = 1;
+
+#[derive(RustcEncodable, Clone)]
+struct CapStatsMsg {
+    typ: &'static str,
+    iface: Iface,
+    received: Option<u32>,
+    dropped: Option<u32>,
+    ifdropped: Option<u32>,
+    counters: CaptureCounters
 }
 
-impl PhysData {
-    fn new(frame_ty: FrameType,
-           addrs: [MacAddr; 3],
-           rate: Option<tap::Rate>,
-           channel: tap::Channel,
-           antenna_signal: tap::AntennaSignal,
-           antenna_noise: tap::AntennaNoise,
-           antenna: tap::Antenna,
-           ) -> PhysData {
-        PhysData {
-            frame_ty: frame_ty,
-            addrs: addrs,
-            rate: rate,
-            channel: channel,
-            antenna_signal: antenna_signal,
-            antenna_noise: antenna_noise,
-            antenna: antenna
+impl CapStatsMsg {
+    fn new(iface: Iface, stats: &CaptureStats) -> CapStatsMsg {
+        CapStatsMsg {
+            typ: "capstats",
+            iface: iface,
+            received: stats.pcap.map(|s| s.received),
+            dropped: stats.pcap.map(|s| s.dropped),
+            ifdropped: stats.pcap.map(|s| s.ifdropped),
+            counters: stats.counters
         }
     }
+}
 
-    fn dist(&self) -> f32 {
-        let freq = f32::from(self.channel.mhz);
-        let signal = f32::from(self.antenna_signal.dbm);
+/// Sent from a capture thread to the controller when the capture ends.
+pub enum CaptureEvent {
+    Complete(Iface, u64),
+    Stopped(Iface, u64),
+    Failed(Iface, cap::PcapError)
+}
 
-        let exp = (27.55 - (20.0 * freq.log10()) + signal.abs()) / 20.0;
-        (10.0f32).powf(exp)
+#[derive(Clone, Debug, PartialEq)]
+pub enum CaptureState {
+    Running,
+    Stopped,
+    Complete,
+    Failed(String)
+}
+
+impl fmt::Display for CaptureState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CaptureState::Running => write!(f, "running"),
+            CaptureState::Stopped => write!(f, "stopped"),
+            CaptureState::Complete => write!(f, "complete"),
+            CaptureState::Failed(ref e) => write!(f, "failed: {}", e)
+        }
     }
 }
 
-#[derive(PartialEq, Eq, Hash)]
-pub struct PhysDataKey(pub FrameType, pub [MacAddr;3]);
+/// The capture file name that means standard input.
+pub const STDIN: &str = "-";
 
-pub struct PhysDataVal {
-    pub dat: FixedRingBuffer<PhysData>,
-    pub count: u32,
+/// Marks a capture source as a file, for one that could be taken for an interface.
+pub const FILE_PREFIX: &str = "file:";
+
+/// Standard input and named pipes can only be read through once, as the data
+/// comes in.
+fn is_stream(path: &str) -> bool {
+    path == STDIN || fs::metadata(path).map(|m| m.file_type().is_fifo()).unwrap_or(false)
 }
 
-impl PhysDataVal {
-    pub fn new() -> PhysDataVal {
-        PhysDataVal {
-            dat: FixedRingBuffer::new(10),
-            count: 0
+/// Where a capture reads its packets from.
+#[derive(Clone, Debug)]
+pub enum CaptureSource {
+    Interface(String),
+    File(String)
+}
+
+impl CaptureSource {
+    /// Treat `name` as a capture file if it starts with `file:`, is `-` for
+    /// standard input, or names a regular file or a named pipe.  Anything else,
+    /// device nodes included, is taken to be an interface.
+    pub fn guess(name: &str) -> CaptureSource {
+        if name.starts_with(FILE_PREFIX) {
+            return CaptureSource::File(name[FILE_PREFIX.len()..].to_owned());
+        }
+        let is_file = fs::metadata(name)
+            .map(|m| m.file_type().is_file() || m.file_type().is_fifo())
+            .unwrap_or(false);
+        if name == STDIN || is_file {
+            CaptureSource::File(name.to_owned())
+        } else {
+            CaptureSource::Interface(name.to_owned())
         }
     }
 
-    pub fn avg_dist(&self) -> f32 {
-        let mut s = 0.0;
-        for pd in self.dat.iter() {
-            s += pd.dist();
+    pub fn name(&self) -> &str {
+        match *self {
+            CaptureSource::Interface(ref name) | CaptureSource::File(ref name) => name
         }
-        s / (self.dat.len() as f32)
     }
 
+    pub fn is_stream(&self) -> bool {
+        match *self {
+            CaptureSource::File(ref f) => is_stream(f),
+            CaptureSource::Interface(_) => false
+        }
+    }
 }
 
-#[derive(Clone)]
-pub struct PhysDataController {
-    pub map:  Arc<RwLock<HashMap<PhysDataKey, PhysDataVal>>>,
-    pd_tx: Sender<PhysData>
+#[derive(Debug)]
+pub enum CaptureErr {
+    IOError(io::Error),
+    Pcap(cap::PcapError),
+    UnsupportedDatalink(cap::DataLinkType),
+    NotRunning,
+    NotReplaying,
+    NotRecording,
+    NotBuffering,
+    SeekBackwards,
+    AlreadyRunning(String),
+    NoSuchCapture(String)
 }
 
-impl PhysDataController {
-    fn spawn() -> io::Result<PhysDataController> {
-        let (pd_tx, pd_rx) = channel();
-        let out = PhysDataController {
-            pd_tx: pd_tx,
-            map: Arc::new(RwLock::new(HashMap::new()))
-        };
-
-        let ctl = out.clone();
-        thread::Builder::new().name("physdata_handler".to_owned()).spawn(move || {
-            loop {
-                let res = pd_rx.recv();
-                if res.is_err() {
-                    break
-                }
-                let pd = res.unwrap();
-
-                match ctl.map.write().unwrap().entry(PhysDataKey(pd.frame_ty, pd.addrs)) {
-                    Entry::Occupied(mut e) => {
-                        let mut pdc = e.get_mut();
-                        pdc.dat.push(pd);
-                        pdc.count += 1;
-                    }
-                    Entry::Vacant(e) => {
-                        let mut pdc = PhysDataVal::new();
-                        pdc.dat.push(pd);
-                        pdc.count += 1;
-                        e.insert(pdc);
-                    }
-                };
-            }
-        })?;
-
-        Ok(out)
+impl From<io::Error> for CaptureErr {
+    fn from(err: io::Error) -> CaptureErr {
+        CaptureErr::IOError(err)
     }
+}
 
-    fn sender(&self) -> Sender<PhysData> {
-        self.pd_tx.clone()
+impl From<cap::PcapError> for CaptureErr {
+    fn from(err: cap::PcapError) -> CaptureErr {
+        CaptureErr::Pcap(err)
     }
 }
 
-struct RadiotapParser {
-    pkts: Sender<Pkt>,
-    phys: Sender<PhysData>
-}
-
-impl RadiotapParser {
-    fn parse_known_headers(&self,
-                           frame_ty: FrameType,
-                           addrs: [MacAddr; 3],
-                           tap_hdr: &tap::RadiotapHeader) {
-        match tap_hdr.it_present {
-            tap::ItPresent::COMMON_A => {
-                if let Some(vals) = tap::CommonA::parse(tap_hdr) {
-                    self.phys.send(PhysData::new(
-                        frame_ty,
-                        addrs,
-                        Some(vals.rate),
-                        vals.channel,
-                        vals.antenna_signal,
-                        vals.antenna_noise,
-                        vals.antenna
-                    )).unwrap();
-                }
-            },
-            tap::ItPresent::COMMON_B => {
-                if let Some(vals) = tap::CommonB::parse(tap_hdr) {
-                    self.phys.send(PhysData::new(
-                        frame_ty,
-                        addrs,
-                        None,
-                        vals.channel,
-                        vals.antenna_signal,
-                        vals.antenna_noise,
-                        vals.antenna
-                    )).unwrap();
-                }
-            },
-            _ => {} //Unknown header
+impl fmt::Display for CaptureErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CaptureErr::IOError(ref e) => write!(f, "{}", e),
+            CaptureErr::Pcap(ref e) => write!(f, "{}", e),
+            CaptureErr::UnsupportedDatalink(dlt) => write!(f, "unsupported datalink type: {}", dlt),
+            CaptureErr::NotRunning => write!(f, "capture is not running"),
+            CaptureErr::NotReplaying => write!(f, "not replaying a capture file"),
+            CaptureErr::NotRecording => write!(f, "not recording"),
+            CaptureErr::NotBuffering => write!(f, "no pre-event buffer, see --snapshot"),
+            CaptureErr::SeekBackwards => write!(f, "can only seek forwards"),
+            CaptureErr::AlreadyRunning(ref name) => write!(f, "already capturing from {}", name),
+            CaptureErr::NoSuchCapture(ref name) => write!(f, "no capture from {}", name)
         }
     }
 }
 
-
-impl PktParser for RadiotapParser {
-    fn parse(&mut self, pkt: &cap::PcapData) ->  Result<(), ParseErr> {
-        fn magic<U>(pkt: &tap::RadiotapHeader) -> &U {
-            unsafe { skip_bytes_cast(pkt, pkt.it_len as isize) }
+/// Apply a command that only changes replay pacing, handing back anything else.
+fn apply_pacing(replay: &mut Replay, cmd: CaptureCmd) -> Option<CaptureCmd> {
+    match cmd {
+        CaptureCmd::Pause => replay.pause(),
+        CaptureCmd::Resume => replay.resume(),
+        CaptureCmd::Speed(speed) => replay.set_speed(speed),
+        CaptureCmd::Seek(offset, reply) => {
+            let _ = reply.send(replay.seek(offset));
         }
-
-        let tap_hdr = unsafe { &*(pkt.pkt_ptr() as *const tap::RadiotapHeader) };
-        let base: &dot11::Dot11BaseHeader = magic(tap_hdr);
-
-        let fc = &base.fr_ctrl;
-        if fc.protocol_version() != 0 {
-            // bogus packet, bail
-            return Err(ParseErr::UnknownPacket);
+        CaptureCmd::Status(reply) => {
+            let _ = reply.send(replay.status());
         }
+        other => return Some(other)
+    }
+    None
+}
 
-        match fc.frame_type() {
-            ft @ FrameType::Management => {
-                let mgt: &dot11::ManagementFrameHeader = magic(tap_hdr);
-                self.parse_known_headers(ft, [mgt.addr1, mgt.addr2, mgt.addr3], tap_hdr);
-            }
-            ft @ FrameType::Data => {
-                let data: &dot11::DataFrameHeader = magic(tap_hdr);
-                //TODO: get length
-                self.pkts.send(Pkt::Mac(PktMeta::new(data.addr1, data.addr2, 1)))?;
-                self.parse_known_headers(ft, [data.addr1, data.addr2, data.addr3], tap_hdr);
+/// Wait until a replayed packet with timestamp `ts` is due, applying pacing
+/// commands as they come in.  Returns whether to deliver it, or None if some other
+/// command came in, which is left in `pending` for the capture loop.
+fn wait_due(replay: &mut Replay,
+            ts: f64,
+            cmds: &Receiver<CaptureCmd>,
+            pending: &mut VecDeque<CaptureCmd>) -> Option<bool> {
+    loop {
+        let cmd = match replay.pace(ts) {
+            Pace::Deliver => return Some(true),
+            Pace::Skip => return Some(false),
+            Pace::Wait(secs) => {
+                let wait = Duration::new(secs as u64, (secs.fract() * 1e9) as u32);
+                match cmds.recv_timeout(wait) {
+                    Ok(cmd) => cmd,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return Some(true)
+                }
             }
-            FrameType::Control | FrameType::Unknown => {
-                //println!("Unknown frame type");
+            Pace::Paused => match cmds.recv() {
+                Ok(cmd) => cmd,
+                Err(_) => return Some(true)
             }
+        };
+        if let Some(other) = apply_pacing(replay, cmd) {
+            pending.push_back(other);
+            return None;
         }
-        Ok(())
     }
+}
 
+/// When libpcap captured the packet, as opposed to when we got around to parsing it.
+fn pkt_time(pkt: &cap::PcapData) -> time::Timespec {
+    let ts = pkt.ts();
+    time::Timespec::new(ts.sec(), ts.nsec())
 }
 
-pub fn init_capture(conf: &D3capConf,
-                    pkt_sender: Sender<Pkt>,
-                    pd_sender: Sender<PhysData>) -> CaptureCtx {
-    let sess = match conf.file {
-        Some(ref f) => cap::PcapSession::from_file(f),
-        None => {
-            println!("No session file");
-            let sess_builder = match conf.interface {
-                Some(ref dev) => cap::PcapSessionBuilder::new_dev(dev),
-                None => cap::PcapSessionBuilder::new()
-            };
+/// A comment for a recorded packet, naming any of its addresses that are listed
+/// under known-macs in the config file, followed by a line for each of the `alerts`
+/// it raised.
+fn annotate(pkt: &cap::PcapData, names: &MacMap, alerts: &[Alert]) -> Option<String> {
+    let mut lines = Vec::new();
+    if !names.is_empty() {
+        let notes: Vec<_> = frame_addrs(pkt).into_iter()
+            .filter_map(|(which, mac)| names.get(&mac).map(|name| format!("{}: {}", which, name)))
+            .collect();
+        if !notes.is_empty() {
+            lines.push(notes.join(", "));
+        }
+    }
+    lines.extend(alerts.iter().map(|alert| format!("alert: {}", alert)));
+    if lines.is_empty() { None } else { Some(lines.join("\n")) }
+}
 
-            sess_builder.unwrap()
-                .buffer_size(0xFFFF)
-                .timeout(1000)
-                .promisc(conf.promisc)
-                .rfmon(conf.monitor)
-                .activate()
+/// The MACs in a frame, labelled "src" or "dst": the source and destination of an
+/// Ethernet frame, the transmitter and receiver of an 802.11 data or management
+/// frame, or the sender of a cooked capture packet.
+fn frame_addrs(pkt: &cap::PcapData) -> Vec<(&'static str, MacAddr)> {
+    let sender = match pkt.datalink() {
+        cap::DLT_LINUX_SLL => {
+            decode::header::<SllHeader>(pkt.data(), Layer::Sll).ok()
+                .and_then(|(hdr, _)| hdr.sender())
+        }
+        cap::DLT_LINUX_SLL2 => {
+            decode::header::<Sll2Header>(pkt.data(), Layer::Sll2).ok()
+                .and_then(|(hdr, _)| hdr.sender())
+        }
+        _ => {
+            return frame_src_dst(pkt)
+                .map_or_else(Vec::new, |(src, dst)| vec![("src", src), ("dst", dst)]);
         }
     };
+    sender.map_or_else(Vec::new, |mac| vec![("src", mac)])
+}
 
-    let parser = match sess.datalink() {
+fn frame_src_dst(pkt: &cap::PcapData) -> Option<(MacAddr, MacAddr)> {
+    match pkt.datalink() {
         cap::DLT_ETHERNET => {
-            Box::new(EthernetParser { pkts: pkt_sender }) as Box<PktParser>
+            decode::ethernet(pkt.data()).ok().map(|(hdr, _)| (hdr.src, hdr.dst))
         }
-        cap::DLT_IEEE802_11_RADIO => {
-            Box::new(RadiotapParser { pkts: pkt_sender, phys: pd_sender }) as Box<PktParser>
+        dlt => {
+            let (len, _) = radio_header(dlt)?.parse(pkt.data()).ok()?;
+            match decode::dot11(&pkt.data()[len..]).ok()?.1 {
+                Dot11Addrs::Data(hdr) => Some((hdr.addr2, hdr.addr1)),
+                Dot11Addrs::Management(hdr) => Some((hdr.addr2, hdr.addr1)),
+                Dot11Addrs::Other => None
+            }
         }
-        x => panic!("unsupported datalink type: {}", x)
-    };
+    }
+}
 
-    CaptureCtx { sess: sess, parser: parser }
+/// The radio header in front of the 802.11 frames of a wireless link type.
+fn radio_header(dlt: cap::DataLinkType) -> Option<RadioHeader> {
+    match dlt {
+        cap::DLT_IEEE802_11 => Some(RadioHeader::None),
+        cap::DLT_IEEE802_11_RADIO => Some(RadioHeader::Radiotap),
+        cap::DLT_PPI => Some(RadioHeader::Ppi),
+        cap::DLT_PRISM_HEADER => Some(RadioHeader::Prism),
+        cap::DLT_IEEE802_11_RADIO_AVS => Some(RadioHeader::Avs),
+        _ => None
+    }
 }
 
-pub fn start_capture(conf: D3capConf,
-                     pkt_sender: Sender<Pkt>,
-                     pd_sender: Sender<PhysData>) -> io::Result<JoinHandle<()>> {
-    thread::Builder::new().name("packet_capture".to_owned()).spawn(move || {
-        let mut cap = init_capture(&conf, pkt_sender, pd_sender);
-        loop {
-            cap.parse_next();
+fn open_file(file: &str, filter: &Option<String>) -> Result<cap::PcapSession, cap::PcapError> {
+    let sess = if file == STDIN {
+        // A copy of the descriptor, so that closing the session leaves stdin open.
+        let fd = unsafe { libc::dup(libc::STDIN_FILENO) };
+        if fd < 0 {
+            let msg = format!("stdin: {}", io::Error::last_os_error());
+            return Err(cap::PcapError::Open(msg));
         }
-    })
+        cap::PcapSession::from_stream_native(unsafe { File::from_raw_fd(fd) })?
+    } else if is_stream(file) {
+        let f = File::open(file).map_err(|e| cap::PcapError::Open(format!("{}: {}", file, e)))?;
+        cap::PcapSession::from_stream_native(f)?
+    } else {
+        cap::PcapSession::from_file_native(file)?
+    };
+    if let Some(ref expr) = *filter {
+        sess.set_filter(expr, true)?;
+    }
+    Ok(sess)
 }
 
-enum LoadMacError {
-    IOError(io::Error),
-    TomlError(Option<toml::de::Error>)
+/// A capture's parsers, one per link type.  Live captures and pcap files only ever
+/// have the one, but pcapng files can mix link types from packet to packet.
+struct Parsers {
+    pkts: Sender<Vec<Pkt>>,
+    // What's been parsed since the last flush.
+    batch: Vec<Pkt>,
+    phys: Sender<PhysData>,
+    arp: ArpHandler,
+    iface: Iface,
+    live: bool,
+    by_dlt: HashMap<cap::DataLinkType, Option<Box<PktParser>>>
 }
-impl From<io::Error> for LoadMacError {
-    fn from(err: io::Error) -> LoadMacError {
-        LoadMacError::IOError(err)
+
+impl Parsers {
+    fn new(pkts: Sender<Vec<Pkt>>,
+           phys: Sender<PhysData>,
+           arp: ArpHandler,
+           iface: Iface,
+           live: bool) -> Parsers {
+        Parsers {
+            pkts: pkts,
+            batch: Vec::new(),
+            phys: phys,
+            arp: arp,
+            iface: iface,
+            live: live,
+            by_dlt: HashMap::new()
+        }
     }
-}
-impl From<toml::de::Error> for LoadMacError {
-    fn from(err: toml::de::Error) -> LoadMacError { LoadMacError::TomlError(Some(err)) }
-}
 
-fn load_mac_addrs(file: &str) -> Result<HashMap<MacAddr, String>, LoadMacError> {
-    let mut s = String::new();
+    fn supports(&mut self, dlt: cap::DataLinkType) -> bool {
+        self.get(dlt).is_some()
+    }
 
-    let mut f = File::open(&file)?;
-    f.read_to_string(&mut s)?;
-
-    let t = s.parse::<toml::Value>()?;
-    if let Some(k) = t.get(&"known-macs".to_owned()) {
-        if let Some(tbl) = k.as_table() {
-            return Ok(tbl.iter()
-                .map(|(k, v)| (MacAddr::from_string(k), v.as_str()))
-                .filter_map(|x| match x {
-                    (Some(addr), Some(alias)) => Some((addr, alias.to_owned())),
-                    _ => None
-                })
-                .collect())
+    fn get(&mut self, dlt: cap::DataLinkType) -> Option<&mut Box<PktParser>> {
+        let (phys, iface, live) = (&self.phys, &self.iface, self.live);
+        self.by_dlt.entry(dlt).or_insert_with(|| new_parser(dlt, phys, iface, live)).as_mut()
+    }
+
+    /// Parse `pkt` into the batch; nothing goes to the graphs until `flush`.  ARP is
+    /// checked right away instead, adding anything suspicious to `alerts` so that
+    /// it can be noted on `pkt` when it's recorded.
+    fn parse(&mut self, pkt: &cap::PcapData, alerts: &mut Vec<Alert>) -> Result<(), ParseErr> {
+        let mut out = Vec::new();
+        let res = match self.get(pkt.datalink()) {
+            Some(p) => p.parse(pkt, &mut out),
+            None => Err(ParseErr::UnsupportedDatalink)
+        };
+        for p in out {
+            match p {
+                Pkt::Arp(ref arp) => alerts.extend(self.arp.update(arp)),
+                p => self.batch.push(p)
+            }
         }
+        res
     }
-    Err(LoadMacError::TomlError(None))
-}
 
-fn start_websocket(port: u16, mac_map: &MacMap, pg_ctl: &ProtoGraphController) -> io::Result<()> {
-    let ui = UIServer::spawn(port, mac_map)?;
-    pg_ctl.register_mac_listener(ui.create_sender()?);
-    pg_ctl.register_ip4_listener(ui.create_sender()?);
-    pg_ctl.register_ip6_listener(ui.create_sender()?);
-    Ok(())
+    /// Send what's been parsed on to the graphs in one message, rather than one per
+    /// packet.  Anything that can't be sent counts as a send failure.
+    fn flush(&mut self, counters: &mut CaptureCounters) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let batch = mem::replace(&mut self.batch, Vec::new());
+        if let Err(SendError(lost)) = self.pkts.send(batch) {
+            counters.send_failures += lost.len() as u64;
+        }
+    }
 }
 
-pub type MacMap = HashMap<MacAddr, String>;
-pub type IP4Map = HashMap<IP4Addr, String>;
-pub type IP6Map = HashMap<IP6Addr, String>;
+fn new_parser(dlt: cap::DataLinkType,
+              phys: &Sender<PhysData>,
+              iface: &Iface,
+              live: bool) -> Option<Box<PktParser>> {
+    match dlt {
+        cap::DLT_ETHERNET => {
+            Some(Box::new(EthernetParser { iface: iface.clone() }))
+        }
+        cap::DLT_IEEE802_11 | cap::DLT_IEEE802_11_RADIO | cap::DLT_PPI |
+        cap::DLT_PRISM_HEADER | cap::DLT_IEEE802_11_RADIO_AVS => {
+            Some(Box::new(Dot11Parser {
+                phys: phys.clone(),
+                iface: iface.clone(),
+                radio: radio_header(dlt)?
+            }))
+        }
+        cap::DLT_NULL | cap::DLT_LOOP | cap::DLT_RAW | cap::DLT_IPV4 | cap::DLT_IPV6 => {
+            Some(Box::new(IpParser { iface: iface.clone(), dlt: dlt }))
+        }
+        cap::DLT_LINUX_SLL | cap::DLT_LINUX_SLL2 => {
+            Some(Box::new(CookedParser {
+                iface: iface.clone(),
+                v2: dlt == cap::DLT_LINUX_SLL2,
+                live: live,
+                ifaces: HashMap::new()
+            }))
+        }
+        _ => None
+    }
+}
 
+/// What a capture writes to files: the recording and the pre-event buffer.  Shared
+/// by the capture's thread, its fanout workers, and its `Capture`, which takes the
+/// recorder back to stop recording whether or not the threads are still running.
 #[derive(Clone)]
-pub struct D3capController {
-    pub pg_ctrl: ProtoGraphController,
-    pub pd_ctrl: PhysDataController,
-    pub mac_names: MacMap,
-    pub ip4_names: IP4Map,
-    pub ip6_names: IP6Map,
-    pub server_started: bool
+struct CaptureFiles {
+    recorder: Arc<Mutex<Option<Recorder>>>,
+    prebuf: Arc<Mutex<Option<PreEventBuffer>>>,
+    names: Arc<MacMap>
 }
 
-impl D3capController {
-    pub fn spawn(conf: D3capConf) -> io::Result<D3capController> {
-        let mac_names = conf.conf.as_ref()
-            .map_or_else(HashMap::new, |x| {
-                load_mac_addrs(x).unwrap_or_else(|_| HashMap::new())
-            });
-        let ip4_names = HashMap::new();
-        let ip6_names = HashMap::new();
-
-        let pg_ctrl = ProtoGraphController::spawn()?;
-        let pd_ctrl = PhysDataController::spawn()?;
-
-        start_capture(conf, pg_ctrl.sender(), pd_ctrl.sender()).unwrap();
+impl CaptureFiles {
+    fn new(prebuf: Option<PreEventBuffer>, names: Arc<MacMap>) -> CaptureFiles {
+        CaptureFiles {
+            recorder: Arc::new(Mutex::new(None)),
+            prebuf: Arc::new(Mutex::new(prebuf)),
+            names: names
+        }
+    }
 
-        Ok(D3capController {
+    /// Record `pkt`, noting any `alerts` it raised, and add it to the pre-event
+    /// buffer.
+    fn write(&self, pkt: &cap::PcapData, alerts: &[Alert]) {
+        {
+            let mut recorder = self.recorder.lock().unwrap();
+            let names = &self.names;
+            let failed = match *recorder {
+                Some(ref mut rec) => rec.record(pkt, || annotate(pkt, names, alerts)).err(),
+                None => None
+            };
+            if let Some(e) = failed {
+                println!("Recording stopped: {}", e);
+                *recorder = None;
+            }
+        }
+        if let Some(ref mut buf) = *self.prebuf.lock().unwrap() {
+            buf.push(pkt);
+        }
+    }
+
+    fn flush(&self) {
+        {
+            let mut recorder = self.recorder.lock().unwrap();
+            let failed = match *recorder {
+                Some(ref mut rec) => rec.flush().err(),
+                None => None
+            };
+            if let Some(e) = failed {
+                println!("Recording stopped: {}", e);
+                *recorder = None;
+            }
+        }
+        if let Some(ref mut buf) = *self.prebuf.lock().unwrap() {
+            buf.flush();
+        }
+    }
+}
+
+pub struct CaptureCtx {
+    // Opened once for the life of the capture, since `Capture` stops it with the
+    // session's breaker.
+    sess: cap::PcapSession,
+    parsers: Parsers,
+    counters: CaptureCounters,
+    stats: Arc<RwLock<CaptureStats>>,
+    stats_updated: f64,
+    filter: Option<String>,
+    replay: Option<Replay>,
+    // A replayed packet that was waiting to be delivered when a command came in.
+    held: Option<cap::PcapPacket>,
+    files: CaptureFiles,
+    workers: Vec<FanoutWorker>,
+    // What workers that have been stopped saw.
+    retired: CaptureStats
+}
+
+impl CaptureCtx {
+    fn handle_cmd(&mut self, cmd: CaptureCmd) -> Result<(), cap::PcapError> {
+        let cmd = match self.replay {
+            Some(ref mut replay) => apply_pacing(replay, cmd),
+            None => Some(cmd)
+        };
+        match cmd {
+            Some(CaptureCmd::Filter(expr, reply)) => {
+                let res = self.sess.set_filter(&expr, true);
+                if res.is_ok() {
+                    for w in &self.workers {
+                        let _ = w.filters.send(expr.clone());
+                    }
+                    self.filter = Some(expr);
+                }
+                let _ = reply.send(res);
+            }
+            Some(CaptureCmd::Record(path, spec, append, reply)) => {
+                let res = Recorder::start(&self.sess, &path, &spec, append, &self.parsers.iface,
+                                          self.filter.as_ref().map(|f| &f[..]));
+                let recorder = &self.files.recorder;
+                let _ = reply.send(res.map(|rec| { *recorder.lock().unwrap() = Some(rec); }));
+            }
+            _ => {} // pacing commands for a live capture, or a stop, handled by run
+        }
+        Ok(())
+    }
+
+    /// Read and parse the next packet.  When replaying a file this also waits until
+    /// the packet is due, picking up pacing commands while it waits.  Anything else
+    /// that comes in is left in `pending` for the capture loop, and the packet is
+    /// held until the next call.
+    fn parse_next(&mut self,
+                  cmds: &Receiver<CaptureCmd>,
+                  pending: &mut VecDeque<CaptureCmd>) -> Result<bool, cap::PcapError> {
+        let resumed = self.held.take();
+        let parsers = &mut self.parsers;
+        let replay = &mut self.replay;
+        let counters = &mut self.counters;
+        let files = &self.files;
+        let held = &mut self.held;
+        let mut deliver = |cap: &cap::PcapData| {
+            if let Some(ref mut replay) = *replay {
+                match wait_due(replay, cap.ts().to_f64(), cmds, pending) {
+                    Some(true) => {}
+                    Some(false) => return false,
+                    None => {
+                        *held = Some(cap.to_packet());
+                        return false;
+                    }
+                }
+            }
+            let mut alerts = Vec::new();
+            let res = parsers.parse(cap, &mut alerts);
+            files.write(cap, &alerts);
+            counters.record(&res);
+            true
+        };
+        if let Some(pkt) = resumed {
+            return Ok(pkt.with_data(|cap| deliver(cap)));
+        }
+        let mut delivered = false;
+        self.sess.next(|cap| delivered = deliver(cap))?;
+        Ok(delivered)
+    }
+
+    fn update_stats(&mut self) {
+        let mut stats = CaptureStats {
+            pcap: self.sess.stats().ok(),
+            counters: self.counters
+        };
+        stats.add(&self.retired);
+        for w in &self.workers {
+            stats.add(&w.stats.read().unwrap());
+        }
+        *self.stats.write().unwrap() = stats;
+        self.stats_updated = time::precise_time_s();
+    }
+
+    /// Stop any fanout workers, keeping what they saw in the stats.
+    fn stop_workers(&mut self) {
+        for w in self.workers.drain(..) {
+            self.retired.add(&w.stop());
+        }
+        self.update_stats();
+    }
+
+    /// Packets read by this capture and any workers.
+    fn packets(&self) -> u64 {
+        self.stats.read().unwrap().counters.packets
+    }
+
+    /// Capture until told to stop, which returns Ok, or until the session ends.
+    fn run(&mut self, cmds: &Receiver<CaptureCmd>) -> Result<(), cap::PcapError> {
+        let mut pending = VecDeque::new();
+        loop {
+            pending.extend(cmds.try_iter());
+            while let Some(cmd) = pending.pop_front() {
+                if let CaptureCmd::Stop = cmd {
+                    self.update_stats();
+                    return Ok(());
+                }
+                self.handle_cmd(cmd)?;
+            }
+            let res = self.parse_next(cmds, &mut pending);
+            self.parsers.flush(&mut self.counters);
+            if res.is_err() ||
+                time::precise_time_s() - self.stats_updated >= CAPSTATS_INTERVAL_SECS as f64 {
+                self.update_stats();
+                self.files.flush();
+            }
+            if let Err(cap::PcapError::Break) = res {
+                // A stop sends the command before breaking the loop, so if this was
+                // us it's already waiting.
+                pending.extend(cmds.try_iter());
+                if pending.iter().any(|cmd| match *cmd { CaptureCmd::Stop => true, _ => false }) {
+                    return Ok(());
+                }
+            }
+            res?;
+        }
+    }
+}
+
+struct EthernetParser {
+    iface: Iface
+}
+
+impl PktParser for EthernetParser {
+
+    fn parse(&mut self, pkt: &cap::PcapData, out: &mut Vec<Pkt>) -> Result<(), ParseErr> {
+        let tm = pkt_time(pkt);
+        let (ether_hdr, payload) = decode::ethernet(pkt.data())?;
+        out.push(Pkt::Mac(PktMeta::new(ether_hdr.src, ether_hdr.dst, pkt.len(), tm,
+                                       self.iface.clone())));
+        parse_ethertype(out, &self.iface, ether_hdr.typ.get(), payload, tm)
+    }
+}
+
+/// Hand the `payload` following a link-layer header to the IP graphs, going by the
+/// header's ethertype `typ`.
+fn parse_ethertype(out: &mut Vec<Pkt>,
+                   iface: &Iface,
+                   typ: u16,
+                   payload: &[u8],
+                   tm: time::Timespec) -> Result<(), ParseErr> {
+    match typ {
+        ETHERTYPE_ARP => {
+            let (hdr, _) = decode::arp(payload)?;
+            match ArpPkt::new(hdr, tm, iface.clone()) {
+                Some(arp) => out.push(Pkt::Arp(arp)),
+                None => return Err(ParseErr::UnknownPayload)
+            }
+        },
+        ETHERTYPE_IP4 => out.push(ip4_pkt(iface, decode::ip4(payload)?.0, tm)),
+        ETHERTYPE_IP6 => out.push(ip6_pkt(iface, decode::ip6(payload)?.0, tm)),
+        ETHERTYPE_802_1X => {
+            //io::println("802.1X!");
+        },
+        _ => {
+            //println!("Unknown type: {:x}", x);
+            return Err(ParseErr::UnknownPayload);
+        }
+    }
+    Ok(())
+}
+
+fn ip4_pkt(iface: &Iface, ipp: &IP4Header, tm: time::Timespec) -> Pkt {
+    Pkt::IP4(PktMeta::new(ipp.src, ipp.dst, u32::from(ipp.len.get()), tm, iface.clone()))
+}
+
+fn ip6_pkt(iface: &Iface, ipp: &IP6Header, tm: time::Timespec) -> Pkt {
+    Pkt::IP6(PktMeta::new(ipp.src, ipp.dst, u32::from(ipp.len.get()), tm, iface.clone()))
+}
+
+// Address families in loopback headers.  IPv6 is different on every BSD.
+const AF_INET: u32 = 2;
+const AF_INET6_BSD: u32 = 24;
+const AF_INET6_FREEBSD: u32 = 28;
+const AF_INET6_DARWIN: u32 = 30;
+
+/// Parses link types where packets start at the IP header, possibly after a
+/// loopback header with the address family: BSD loopback, OpenBSD's DLT_LOOP, and
+/// raw IP as from VPN interfaces.  There are no MACs, so only the IP graphs are fed.
+struct IpParser {
+    iface: Iface,
+    dlt: cap::DataLinkType
+}
+
+/// The address family in front of BSD loopback packets.
+#[repr(packed)]
+struct LoopbackHeader {
+    family: Be32
+}
+
+unsafe impl Header for LoopbackHeader {}
+
+impl IpParser {
+    /// The IP version of a packet, and the bytes from its IP header on.
+    fn locate<'a>(&self, data: &'a [u8]) -> Result<(u8, &'a [u8]), DecodeErr> {
+        let family = |af| match af {
+            AF_INET => 4,
+            AF_INET6_BSD | AF_INET6_FREEBSD | AF_INET6_DARWIN => 6,
+            _ => 0
+        };
+        let lo = || decode::header::<LoopbackHeader>(data, Layer::Loopback);
+        match self.dlt {
+            // DLT_NULL's family is in the byte order of whatever machine wrote it, so
+            // take whichever order gives a small number.
+            cap::DLT_NULL => {
+                let (hdr, rest) = lo()?;
+                let af = hdr.family.get();
+                Ok((family(if af > 0xFFFF { af.swap_bytes() } else { af }), rest))
+            }
+            cap::DLT_LOOP => {
+                let (hdr, rest) = lo()?;
+                Ok((family(hdr.family.get()), rest))
+            }
+            cap::DLT_IPV4 => Ok((4, data)),
+            cap::DLT_IPV6 => Ok((6, data)),
+            _ => Ok((data.first().map_or(0, |b| b >> 4), data))
+        }
+    }
+}
+
+impl PktParser for IpParser {
+    fn parse(&mut self, pkt: &cap::PcapData, out: &mut Vec<Pkt>) -> Result<(), ParseErr> {
+        let tm = pkt_time(pkt);
+        match self.locate(pkt.data())? {
+            (4, ip) => out.push(ip4_pkt(&self.iface, decode::ip4(ip)?.0, tm)),
+            (6, ip) => out.push(ip6_pkt(&self.iface, decode::ip6(ip)?.0, tm)),
+            _ => return Err(ParseErr::UnknownPayload)
+        }
+        Ok(())
+    }
+}
+
+/// Parses Linux cooked captures, as from the "any" device.  These only carry the
+/// sender's link-layer address, so there's no MAC route to record, but the second
+/// version says which interface the packet was seen on and the packet is counted
+/// against that interface.
+struct CookedParser {
+    iface: Iface,
+    v2: bool,
+    // Interface indexes are only looked up when capturing live; in a file they're
+    // from whatever machine it was captured on.
+    live: bool,
+    ifaces: HashMap<u32, Iface>
+}
+
+impl CookedParser {
+    /// "any:eth0", or "any:2" if the name can't be found.
+    fn iface_for(&mut self, index: u32) -> Iface {
+        let (base, live) = (&self.iface, self.live);
+        self.ifaces.entry(index).or_insert_with(|| {
+            let name = if live { if_name(index) } else { None };
+            Arc::new(format!("{}:{}", base, name.unwrap_or_else(|| index.to_string())))
+        }).clone()
+    }
+}
+
+fn if_name(index: u32) -> Option<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+    unsafe {
+        let name = libc::if_indextoname(index, buf.as_mut_ptr());
+        if name.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(name).to_string_lossy().into_owned())
+        }
+    }
+}
+
+impl PktParser for CookedParser {
+    fn parse(&mut self, pkt: &cap::PcapData, out: &mut Vec<Pkt>) -> Result<(), ParseErr> {
+        let tm = pkt_time(pkt);
+        if self.v2 {
+            let (hdr, payload) = decode::header::<Sll2Header>(pkt.data(), Layer::Sll2)?;
+            let iface = self.iface_for(hdr.if_index());
+            parse_ethertype(out, &iface, hdr.protocol.get(), payload, tm)
+        } else {
+            let (hdr, payload) = decode::header::<SllHeader>(pkt.data(), Layer::Sll)?;
+            parse_ethertype(out, &self.iface, hdr.protocol.get(), payload, tm)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PhysData { // TODO: this name sucks
+    frame_ty: FrameType,
+    addrs: [MacAddr; 3],
+    rate: Option<tap::Rate>,
+    channel: tap::Channel,
+    antenna_signal: tap::AntennaSignal,
+    antenna_noise: tap::AntennaNoise,
+    antenna: tap::Antenna,
+    tm: time::Timespec,
+}
+
+impl PhysData {
+    fn new(frame_ty: FrameType,
+           addrs: [MacAddr; 3],
+           rate: Option<tap::Rate>,
+           channel: tap::Channel,
+           antenna_signal: tap::AntennaSignal,
+           antenna_noise: tap::AntennaNoise,
+           antenna: tap::Antenna,
+           tm: time::Timespec,
+           ) -> PhysData {
+        PhysData {
+            frame_ty: frame_ty,
+            addrs: addrs,
+            rate: rate,
+            channel: channel,
+            antenna_signal: antenna_signal,
+            antenna_noise: antenna_noise,
+            antenna: antenna,
+            tm: tm
+        }
+    }
+
+    fn dist(&self) -> f32 {
+        let freq = f32::from(self.channel.mhz);
+        let signal = f32::from(self.antenna_signal.dbm);
+
+        let exp = (27.55 - (20.0 * freq.log10()) + signal.abs()) / 20.0;
+        (10.0f32).powf(exp)
+    }
+}
+
+#[derive(PartialEq, Eq, Hash)]
+pub struct PhysDataKey(pub FrameType, pub [MacAddr;3]);
+
+pub struct PhysDataVal {
+    pub dat: FixedRingBuffer<PhysData>,
+    pub count: u32,
+}
+
+impl PhysDataVal {
+    pub fn new() -> PhysDataVal {
+        PhysDataVal {
+            dat: FixedRingBuffer::new(10),
+            count: 0
+        }
+    }
+
+    pub fn avg_dist(&self) -> f32 {
+        let mut s = 0.0;
+        for pd in self.dat.iter() {
+            s += pd.dist();
+        }
+        s / (self.dat.len() as f32)
+    }
+
+}
+
+#[derive(Clone)]
+pub struct PhysDataController {
+    pub map:  Arc<RwLock<HashMap<PhysDataKey, PhysDataVal>>>,
+    pd_tx: Sender<PhysData>
+}
+
+impl PhysDataController {
+    fn spawn() -> io::Result<PhysDataController> {
+        let (pd_tx, pd_rx) = channel();
+        let out = PhysDataController {
+            pd_tx: pd_tx,
+            map: Arc::new(RwLock::new(HashMap::new()))
+        };
+
+        let ctl = out.clone();
+        thread::Builder::new().name("physdata_handler".to_owned()).spawn(move || {
+            loop {
+                let res = pd_rx.recv();
+                if res.is_err() {
+                    break
+                }
+                let pd = res.unwrap();
+
+                match ctl.map.write().unwrap().entry(PhysDataKey(pd.frame_ty, pd.addrs)) {
+                    Entry::Occupied(mut e) => {
+                        let mut pdc = e.get_mut();
+                        pdc.dat.push(pd);
+                        pdc.count += 1;
+                    }
+                    Entry::Vacant(e) => {
+                        let mut pdc = PhysDataVal::new();
+                        pdc.dat.push(pd);
+                        pdc.count += 1;
+                        e.insert(pdc);
+                    }
+                };
+            }
+        })?;
+
+        Ok(out)
+    }
+
+    fn sender(&self) -> Sender<PhysData> {
+        self.pd_tx.clone()
+    }
+}
+
+/// Parses 802.11 frames, whichever radio header the card puts in front of them.
+struct Dot11Parser {
+    phys: Sender<PhysData>,
+    iface: Iface,
+    radio: RadioHeader
+}
+
+impl Dot11Parser {
+    fn send_phys(&self,
+                 frame_ty: FrameType,
+                 addrs: [MacAddr; 3],
+                 radio: &Option<Radio>,
+                 tm: time::Timespec) -> Result<(), ParseErr> {
+        if let Some(ref r) = *radio {
+            self.phys.send(PhysData::new(
+                frame_ty,
+                addrs,
+                r.rate,
+                r.channel,
+                r.antenna_signal,
+                r.antenna_noise,
+                r.antenna,
+                tm
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+
+impl PktParser for Dot11Parser {
+    fn parse(&mut self, pkt: &cap::PcapData, out: &mut Vec<Pkt>) ->  Result<(), ParseErr> {
+        let (len, radio) = self.radio.parse(pkt.data())?;
+        let frame = &pkt.data()[len..];
+
+        let tm = pkt_time(pkt);
+        let (base, addrs) = decode::dot11(frame)?;
+
+        let fc = &base.fr_ctrl;
+        if fc.protocol_version() != 0 {
+            // bogus packet, bail
+            return Err(ParseErr::UnknownPacket);
+        }
+
+        match addrs {
+            Dot11Addrs::Management(mgt) => {
+                self.send_phys(FrameType::Management,
+                               [mgt.addr1, mgt.addr2, mgt.addr3], &radio, tm)?;
+            }
+            Dot11Addrs::Data(data) => {
+                //TODO: get length
+                out.push(Pkt::Mac(PktMeta::new(data.addr1, data.addr2, 1, tm,
+                                               self.iface.clone())));
+                self.send_phys(FrameType::Data,
+                               [data.addr1, data.addr2, data.addr3], &radio, tm)?;
+            }
+            Dot11Addrs::Other => {
+                //println!("Unknown frame type");
+            }
+        }
+        Ok(())
+    }
+
+}
+
+/// Open `dev` with our own AF_PACKET ring rather than through libpcap, joining the
+/// fanout `group` if there is one.
+#[cfg(target_os = "linux")]
+fn open_ring(dev: &str,
+             conf: &D3capConf,
+             group: Option<u16>) -> Result<cap::PcapSession, CaptureErr> {
+    use pcap::ring::{Fanout, RingConf};
+
+    if conf.monitor || conf.datalinks.contains_key(dev) {
+        let msg = "monitor mode and link types need libpcap, not the ring";
+        return Err(CaptureErr::Pcap(cap::PcapError::Open(msg.to_owned())));
+    }
+    let opts = &conf.capture;
+    let mut ring_conf = RingConf::default();
+    if let Some(len) = opts.snaplen {
+        ring_conf.snaplen = len as u32;
+    }
+    if let Some(sz) = opts.buffer_size {
+        ring_conf.block_count = (sz as u32 / ring_conf.block_size).max(1);
+    }
+    ring_conf.promisc = conf.promisc;
+    ring_conf.fanout = group.map(|g| (g, Fanout::Hash));
+    let timeout = opts.timeout.unwrap_or(DEFAULT_TIMEOUT_MS);
+    let sess = cap::PcapSession::open_ring(dev, &ring_conf, timeout)?;
+    if let Some(ref expr) = conf.filter {
+        sess.set_filter(expr, true)?;
+    }
+    Ok(sess)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_ring(_: &str, _: &D3capConf, _: Option<u16>) -> Result<cap::PcapSession, CaptureErr> {
+    let msg = "the AF_PACKET ring is only on Linux";
+    Err(CaptureErr::Pcap(cap::PcapError::Open(msg.to_owned())))
+}
+
+/// A fanout group id for a new capture, different from any other capture's in this
+/// process and hopefully anyone else's.
+fn fanout_group() -> u16 {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let pid = unsafe { libc::getpid() } as usize;
+    (pid.wrapping_add(NEXT.fetch_add(1, Ordering::SeqCst)) & 0xFFFF) as u16
+}
+
+/// A thread parsing and recording its share of a fanout capture, from its own
+/// socket in the group.  The capture's own thread handles everything else.
+struct FanoutWorker {
+    breaker: cap::PcapBreaker,
+    filters: Sender<String>,
+    stats: Arc<RwLock<CaptureStats>>,
+    thread: Option<JoinHandle<()>>
+}
+
+impl FanoutWorker {
+    /// Start a thread reading from its own ring on `dev` in the fanout `group`.
+    /// Like `start_capture`, waits until the ring's been opened.
+    fn spawn(dev: &str,
+             conf: &D3capConf,
+             group: Option<u16>,
+             (pkts, phys, arp, iface): (Sender<Vec<Pkt>>, Sender<PhysData>, ArpHandler, Iface),
+             files: CaptureFiles) -> Result<FanoutWorker, CaptureErr> {
+        let (filters, filter_rx) = channel::<String>();
+        let (init_tx, init_rx) = channel();
+        let stats = Arc::new(RwLock::new(CaptureStats::default()));
+        let thread = {
+            let (dev, conf, stats) = (dev.to_owned(), conf.clone(), stats.clone());
+            thread::Builder::new().name("fanout_worker".to_owned()).spawn(move || {
+                let sess = match open_ring(&dev, &conf, group) {
+                    Ok(sess) => {
+                        init_tx.send(Ok(sess.breaker())).unwrap();
+                        sess
+                    }
+                    Err(e) => {
+                        init_tx.send(Err(e)).unwrap();
+                        return;
+                    }
+                };
+                let mut parsers = Parsers::new(pkts, phys, arp, iface, true);
+                let mut counters = CaptureCounters::default();
+                let mut updated = 0.0;
+                loop {
+                    for expr in filter_rx.try_iter() {
+                        if let Err(e) = sess.set_filter(&expr, true) {
+                            println!("Could not set filter on fanout worker: {}", e);
+                        }
+                    }
+                    let res = sess.next(|cap| {
+                        let mut alerts = Vec::new();
+                        let res = parsers.parse(cap, &mut alerts);
+                        files.write(cap, &alerts);
+                        counters.record(&res);
+                    });
+                    parsers.flush(&mut counters);
+                    let now = time::precise_time_s();
+                    if res.is_err() || now - updated >= CAPSTATS_INTERVAL_SECS as f64 {
+                        *stats.write().unwrap() = CaptureStats {
+                            pcap: sess.stats().ok(),
+                            counters: counters
+                        };
+                        updated = now;
+                    }
+                    if let Err(e) = res {
+                        if let cap::PcapError::Break = e {} else {
+                            println!("Fanout worker stopped: {}", e);
+                        }
+                        break;
+                    }
+                }
+            })?
+        };
+        let breaker = init_rx.recv().unwrap_or(Err(CaptureErr::NotRunning))?;
+        Ok(FanoutWorker { breaker: breaker, filters: filters, stats: stats, thread: Some(thread) })
+    }
+
+    /// Stop the thread and hand back the last of its stats.
+    fn stop(mut self) -> CaptureStats {
+        self.breaker.breakloop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        *self.stats.read().unwrap()
+    }
+}
+
+impl Drop for FanoutWorker {
+    fn drop(&mut self) {
+        self.breaker.breakloop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+pub fn init_capture(source: &CaptureSource,
+                    conf: &D3capConf,
+                    pkt_sender: Sender<Vec<Pkt>>,
+                    pd_sender: Sender<PhysData>,
+                    arp: ArpHandler,
+                    names: Arc<MacMap>,
+                    stats: Arc<RwLock<CaptureStats>>) -> Result<CaptureCtx, CaptureErr> {
+    let fanout = match *source {
+        CaptureSource::Interface(_) if conf.capture.ring == Some(true) => {
+            conf.capture.fanout.unwrap_or(1)
+        }
+        _ => 1
+    };
+    let group = if fanout > 1 { Some(fanout_group()) } else { None };
+    let sess = match *source {
+        CaptureSource::File(ref f) => open_file(f, &conf.filter)?,
+        CaptureSource::Interface(ref dev) if conf.capture.ring == Some(true) => {
+            open_ring(dev, conf, group)?
+        }
+        CaptureSource::Interface(ref dev) => {
+            let mut sess_builder = cap::PcapSessionBuilder::new_dev(dev)?;
+
+            let opts = &conf.capture;
+            sess_builder
+                .buffer_size(opts.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE))
+                .timeout(opts.timeout.unwrap_or(DEFAULT_TIMEOUT_MS))
+                .immediate_mode(opts.immediate.unwrap_or(false))
+                .promisc(conf.promisc)
+                .rfmon(conf.monitor)
+                .tstamp_precision(cap::TstampPrecision::Nano);
+            if let Some(len) = opts.snaplen {
+                sess_builder.snaplen(len);
+            }
+            if let Some(typ) = opts.tstamp_type {
+                sess_builder.tstamp_type(typ);
+            }
+            match conf.datalinks.get(dev) {
+                Some(&dlt) => { sess_builder.datalink(dlt); }
+                // The second version of the cooked header says which interface each
+                // packet came in on, so "any" gets it where it's offered (libpcap 1.10
+                // on).
+                None if dev == "any" => { sess_builder.prefer_datalink(cap::DLT_LINUX_SLL2); }
+                None => {}
+            }
+            if let Some(ref expr) = conf.filter {
+                sess_builder.filter(expr, true);
+            }
+            let sess = sess_builder.activate()?;
+            if let Some(w) = sess.warning() {
+                println!("Warning ({}): {}", dev, w);
+            }
+            sess
+        }
+    };
+
+    let iface = Arc::new(source.name().to_owned());
+    let live = match *source {
+        CaptureSource::Interface(_) => true,
+        CaptureSource::File(_) => false
+    };
+    let mut parsers = Parsers::new(pkt_sender.clone(), pd_sender.clone(), arp.clone(),
+                                   iface.clone(), live);
+    // A savefile that mixes link types just has the packets we can't parse skipped.
+    if let Some(dlt) = sess.datalink() {
+        if !parsers.supports(dlt) {
+            return Err(CaptureErr::UnsupportedDatalink(dlt));
+        }
+    }
+
+    let prebuf = conf.snapshot.as_ref().map(|c| PreEventBuffer::new(c, &sess, source.name()));
+    let files = CaptureFiles::new(prebuf, names);
+
+    let mut workers = Vec::new();
+    for _ in 1..fanout {
+        let parsers = (pkt_sender.clone(), pd_sender.clone(), arp.clone(), iface.clone());
+        workers.push(FanoutWorker::spawn(source.name(), conf, group, parsers, files.clone())?);
+    }
+
+    // Packets come in from streams as they're captured, so they're read like a live
+    // capture rather than replayed.
+    let replay = match *source {
+        CaptureSource::File(_) if !source.is_stream() => Some(Replay::new(conf.replay_speed)),
+        _ => None
+    };
+
+    Ok(CaptureCtx {
+        sess: sess,
+        parsers: parsers,
+        counters: CaptureCounters::default(),
+        stats: stats,
+        stats_updated: 0.0,
+        replay: replay,
+        held: None,
+        filter: conf.filter.clone(),
+        files: files,
+        workers: workers,
+        retired: CaptureStats::default()
+    })
+}
+
+/// Start capturing from `source` on a new thread.  Doesn't return until the session
+/// has been opened, so that a bad interface or file shows up as an error here.
+pub fn start_capture(source: CaptureSource,
+                     conf: D3capConf,
+                     pkt_sender: Sender<Vec<Pkt>>,
+                     pd_sender: Sender<PhysData>,
+                     arp: ArpHandler,
+                     names: Arc<MacMap>,
+                     events: Sender<CaptureEvent>) -> Result<Capture, CaptureErr> {
+    let iface = Arc::new(source.name().to_owned());
+    let filter = conf.filter.clone();
+    let stats = Arc::new(RwLock::new(CaptureStats::default()));
+    let state = Arc::new(RwLock::new(CaptureState::Running));
+    let (ctl, cmds) = channel();
+    let (init_tx, init_rx) = channel();
+
+    let thread = {
+        let (source, iface, stats, state) =
+            (source.clone(), iface.clone(), stats.clone(), state.clone());
+        thread::Builder::new().name("packet_capture".to_owned()).spawn(move || {
+            let res = init_capture(&source, &conf, pkt_sender, pd_sender, arp, names, stats);
+            let mut cap = match res {
+                Ok(cap) => {
+                    init_tx.send(Ok((cap.sess.breaker(), cap.files.clone()))).unwrap();
+                    cap
+                }
+                Err(e) => {
+                    init_tx.send(Err(e)).unwrap();
+                    return;
+                }
+            };
+            let res = cap.run(&cmds);
+            cap.stop_workers();
+            let count = cap.packets();
+            drop(cap); // close the session before anyone hears we're done
+            let ev = match res {
+                Ok(()) => {
+                    *state.write().unwrap() = CaptureState::Stopped;
+                    CaptureEvent::Stopped(iface, count)
+                }
+                Err(cap::PcapError::Break) => {
+                    *state.write().unwrap() = CaptureState::Complete;
+                    CaptureEvent::Complete(iface, count)
+                }
+                Err(e) => {
+                    *state.write().unwrap() = CaptureState::Failed(e.to_string());
+                    CaptureEvent::Failed(iface, e)
+                }
+            };
+            let _ = events.send(ev);
+        })?
+    };
+    let (breaker, files) = init_rx.recv().unwrap_or(Err(CaptureErr::NotRunning))?;
+
+    Ok(Capture {
+        source: source,
+        iface: iface,
+        filter: filter,
+        recording: None,
+        files: files,
+        stats: stats,
+        state: state,
+        ctl: ctl,
+        breaker: breaker,
+        thread: Some(thread)
+    })
+}
+
+fn watch_capture(events: Receiver<CaptureEvent>) -> io::Result<JoinHandle<()>> {
+    thread::Builder::new().name("capture_events".to_owned()).spawn(move || {
+        for ev in events.iter() {
+            match ev {
+                CaptureEvent::Complete(iface, count) => {
+                    println!("capture complete: {} packets from {}", count, iface)
+                }
+                CaptureEvent::Stopped(iface, count) => {
+                    println!("capture stopped: {} packets from {}", count, iface)
+                }
+                CaptureEvent::Failed(iface, e) => println!("capture failed on {}: {}", iface, e)
+            }
+        }
+    })
+}
+
+/// Snapshot the capture a trigger's traffic was seen on whenever one fires.
+fn watch_triggers(triggers: Receiver<Trigger>,
+                  captures: CaptureManager) -> io::Result<JoinHandle<()>> {
+    thread::Builder::new().name("snapshot_triggers".to_owned()).spawn(move || {
+        for trigger in triggers.iter() {
+            let res = match captures.snapshot(Some(trigger.iface())) {
+                Ok(res) => res,
+                Err(_) => continue
+            };
+            for (iface, r) in res {
+                match r {
+                    Ok(Some(path)) => {
+                        println!("{}: {}: writing snapshot to {}", iface, trigger, path)
+                    }
+                    Ok(None) => {}
+                    Err(e) => println!("{}: Could not snapshot: {}", iface, e)
+                }
+            }
+        }
+    })
+}
+
+/// How many of the latest alerts are kept for the CLI.
+const RECENT_ALERTS: usize = 100;
+
+/// Alerts as they came in, with the time they did, oldest first.
+pub type RecentAlerts = Arc<RwLock<FixedRingBuffer<(time::Timespec, Alert)>>>;
+
+/// Keep alerts for the CLI as they come in, and pass them on to the websocket.  They
+/// aren't printed, which would land in the middle of whatever's being typed at the
+/// prompt.
+fn watch_alerts(alerts: Receiver<Alert>,
+                recent: RecentAlerts,
+                mcast: Multicast<AlertMsg>) -> io::Result<JoinHandle<()>> {
+    thread::Builder::new().name("arp_alerts".to_owned()).spawn(move || {
+        for alert in alerts.iter() {
+            recent.write().unwrap().push((time::get_time(), alert.clone()));
+            mcast.send(Arc::new(AlertMsg::new(&alert))).unwrap();
+        }
+    })
+}
+
+fn publish_capstats(captures: Arc<RwLock<Vec<Capture>>>,
+                    mcast: Multicast<CapStatsMsg>) -> io::Result<JoinHandle<()>> {
+    thread::Builder::new().name("capstats".to_owned()).spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(CAPSTATS_INTERVAL_SECS));
+            for c in captures.read().unwrap().iter().filter(|c| c.is_running()) {
+                let msg = CapStatsMsg::new(c.iface.clone(), &c.stats.read().unwrap());
+                mcast.send(Arc::new(msg)).unwrap();
+            }
+        }
+    })
+}
+
+fn start_websocket(port: u16,
+                   mac_map: &MacMap,
+                   pg_ctl: &ProtoGraphController,
+                   capstats_mcast: &Multicast<CapStatsMsg>,
+                   alerts_mcast: &Multicast<AlertMsg>) -> io::Result<()> {
+    let snap_ctl = pg_ctl.clone();
+    let ui = UIServer::spawn(port, mac_map, move || {
+        let mut msgs = snap_ctl.mac.snapshot();
+        msgs.extend(snap_ctl.ip4.snapshot());
+        msgs.extend(snap_ctl.ip6.snapshot());
+        msgs.extend(snap_ctl.arp.snapshot());
+        msgs
+    })?;
+    pg_ctl.register_mac_listener(ui.create_sender()?);
+    pg_ctl.register_ip4_listener(ui.create_sender()?);
+    pg_ctl.register_ip6_listener(ui.create_sender()?);
+    pg_ctl.register_arp_listener(ui.create_sender()?);
+    capstats_mcast.register(ui.create_sender()?).unwrap();
+    alerts_mcast.register(ui.create_sender()?).unwrap();
+    Ok(())
+}
+
+pub type MacMap = HashMap<MacAddr, String>;
+pub type IP4Map = HashMap<IP4Addr, String>;
+pub type IP6Map = HashMap<IP6Addr, String>;
+
+/// The capture manager's handle on a capture thread.
+pub struct Capture {
+    source: CaptureSource,
+    iface: Iface,
+    filter: Option<String>,
+    recording: Option<String>,
+    files: CaptureFiles,
+    stats: Arc<RwLock<CaptureStats>>,
+    state: Arc<RwLock<CaptureState>>,
+    ctl: Sender<CaptureCmd>,
+    breaker: cap::PcapBreaker,
+    thread: Option<JoinHandle<()>>
+}
+
+impl Capture {
+    fn send(&self, cmd: CaptureCmd) -> Result<(), CaptureErr> {
+        self.ctl.send(cmd).map_err(|_| CaptureErr::NotRunning)
+    }
+
+    fn is_running(&self) -> bool {
+        *self.state.read().unwrap() == CaptureState::Running
+    }
+
+    fn is_replay(&self) -> bool {
+        match self.source {
+            CaptureSource::File(_) => true,
+            CaptureSource::Interface(_) => false
+        }
+    }
+
+    fn set_filter(&mut self, expr: &str) -> Result<(), CaptureErr> {
+        let (reply_tx, reply_rx) = channel();
+        self.send(CaptureCmd::Filter(expr.to_owned(), reply_tx))?;
+        match reply_rx.recv() {
+            Ok(res) => {
+                res?;
+                self.filter = Some(expr.to_owned());
+                Ok(())
+            }
+            Err(_) => Err(CaptureErr::NotRunning)
+        }
+    }
+
+    fn record(&mut self, path: &str, spec: &RecordSpec, append: bool) -> Result<(), CaptureErr> {
+        let (reply_tx, reply_rx) = channel();
+        self.send(CaptureCmd::Record(path.to_owned(), spec.clone(), append, reply_tx))?;
+        match reply_rx.recv() {
+            Ok(res) => {
+                res?;
+                self.recording = Some(path.to_owned());
+                Ok(())
+            }
+            Err(_) => Err(CaptureErr::NotRunning)
+        }
+    }
+
+    /// The file being written and how many packets went into it.  The recorder
+    /// outlives the capture thread, so this still works once the capture has ended.
+    fn stop_recording(&mut self) -> Option<(String, u64)> {
+        self.recording = None;
+        let rec = self.files.recorder.lock().unwrap().take()?;
+        Some((rec.path().to_owned(), rec.written()))
+    }
+
+    /// Whether traffic counted against `iface` came from this capture: it's either
+    /// the capture's own, or one a cooked capture saw its packets on ("any:eth0").
+    fn saw(&self, iface: &Iface) -> bool {
+        iface == &self.iface ||
+            iface.starts_with(&*self.iface) && iface[self.iface.len()..].starts_with(':')
+    }
+
+    /// Write out the pre-event buffer, returning the file if a new one was started.
+    fn snapshot(&self) -> Result<Option<String>, CaptureErr> {
+        match *self.files.prebuf.lock().unwrap() {
+            Some(ref mut buf) => Ok(buf.trigger()?),
+            None => Ok(None)
+        }
+    }
+
+    fn seek(&self, offset: f64) -> Result<(), CaptureErr> {
+        let (reply_tx, reply_rx) = channel();
+        self.send(CaptureCmd::Seek(offset, reply_tx))?;
+        match reply_rx.recv() {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(CaptureErr::SeekBackwards),
+            Err(_) => Err(CaptureErr::NotRunning)
+        }
+    }
+
+    fn replay_status(&self) -> Result<ReplayStatus, CaptureErr> {
+        let (reply_tx, reply_rx) = channel();
+        self.send(CaptureCmd::Status(reply_tx))?;
+        reply_rx.recv().map_err(|_| CaptureErr::NotRunning)
+    }
+
+    /// Stop the capture thread and wait for it to close its session.  The stop
+    /// command gets it out of replay pacing; breaking the loop gets it out of a
+    /// blocking read, including a stream's wait for more data.
+    fn stop(&mut self) -> Result<(), CaptureErr> {
+        if !self.is_running() {
+            return Err(CaptureErr::NotRunning);
+        }
+        let _ = self.ctl.send(CaptureCmd::Stop);
+        self.breaker.breakloop();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        Ok(())
+    }
+
+    fn info(&self) -> CaptureInfo {
+        CaptureInfo {
+            source: self.source.clone(),
+            iface: self.iface.clone(),
+            filter: self.filter.clone(),
+            recording: self.recording.clone(),
+            state: self.state.read().unwrap().clone(),
+            stats: *self.stats.read().unwrap()
+        }
+    }
+}
+
+/// A snapshot of a capture for display.
+#[derive(Clone, Debug)]
+pub struct CaptureInfo {
+    pub source: CaptureSource,
+    pub iface: Iface,
+    pub filter: Option<String>,
+    pub recording: Option<String>,
+    pub state: CaptureState,
+    pub stats: CaptureStats
+}
+
+/// Starts, stops and keeps track of the captures feeding the graphs.  Captures that
+/// have ended stay listed until they're restarted or replaced by one from the same
+/// source.
+#[derive(Clone)]
+pub struct CaptureManager {
+    conf: D3capConf,
+    pkts: Sender<Vec<Pkt>>,
+    phys: Sender<PhysData>,
+    arp: ArpHandler,
+    names: Arc<MacMap>,
+    events: Sender<CaptureEvent>,
+    captures: Arc<RwLock<Vec<Capture>>>,
+    record: Arc<RwLock<Option<RecordSpec>>>
+}
+
+impl CaptureManager {
+    fn spawn(conf: D3capConf,
+             pkts: Sender<Vec<Pkt>>,
+             phys: Sender<PhysData>,
+             arp: ArpHandler,
+             names: Arc<MacMap>) -> io::Result<CaptureManager> {
+        let (events_tx, events) = channel();
+        watch_capture(events)?;
+        Ok(CaptureManager {
+            conf: conf,
+            pkts: pkts,
+            phys: phys,
+            arp: arp,
+            names: names,
+            events: events_tx,
+            captures: Arc::new(RwLock::new(Vec::new())),
+            record: Arc::new(RwLock::new(None))
+        })
+    }
+
+    pub fn start(&self, source: CaptureSource) -> Result<(), CaptureErr> {
+        let filter = self.conf.filter.clone();
+        self.start_filtered(source, filter, None)
+    }
+
+    /// Start a capture.  If we're recording it joins in, appending to `record_path`
+    /// if given, or else to a file of its own.
+    fn start_filtered(&self,
+                      source: CaptureSource,
+                      filter: Option<String>,
+                      record_path: Option<String>) -> Result<(), CaptureErr> {
+        let mut captures = self.captures.write().unwrap();
+        let existing = captures.iter().position(|c| *c.iface == source.name());
+        if let Some(i) = existing {
+            if captures[i].is_running() {
+                return Err(CaptureErr::AlreadyRunning(source.name().to_owned()));
+            }
+        }
+
+        let mut conf = self.conf.clone();
+        conf.filter = filter;
+        let mut capture = start_capture(source, conf, self.pkts.clone(), self.phys.clone(),
+                                        self.arp.clone(), self.names.clone(),
+                                        self.events.clone())?;
+        if let Some(ref spec) = *self.record.read().unwrap() {
+            let path = record_path.unwrap_or_else(|| spec.path_for(&capture.iface, true));
+            if let Err(e) = capture.record(&path, spec, true) {
+                println!("Could not record {} to {}: {}", capture.iface, path, e);
+            }
+        }
+        match existing {
+            Some(i) => captures[i] = capture,
+            None => captures.push(capture)
+        }
+        Ok(())
+    }
+
+    /// Stop the named capture, or every running one if no name is given.
+    pub fn stop(&self, name: Option<&str>) -> Result<(), CaptureErr> {
+        let mut captures = self.captures.write().unwrap();
+        match name {
+            Some(n) => match captures.iter_mut().find(|c| *c.iface == n) {
+                Some(c) => c.stop(),
+                None => Err(CaptureErr::NoSuchCapture(n.to_owned()))
+            },
+            None => {
+                let mut running = captures.iter_mut().filter(|c| c.is_running()).peekable();
+                if running.peek().is_none() {
+                    return Err(CaptureErr::NotRunning);
+                }
+                for c in running {
+                    c.stop()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Stop the named capture, or all of them, and start again from the same source
+    /// with the same filter.  Graph state is kept.
+    pub fn restart(&self, name: Option<&str>) -> Result<(), CaptureErr> {
+        let targets: Vec<_> = self.captures.read().unwrap().iter()
+            .filter(|c| name.map_or(true, |n| *c.iface == n))
+            .map(|c| (c.source.clone(), c.filter.clone(), c.recording.clone()))
+            .collect();
+        if targets.is_empty() {
+            return Err(match name {
+                Some(n) => CaptureErr::NoSuchCapture(n.to_owned()),
+                None => CaptureErr::NotRunning
+            });
+        }
+        for (source, filter, recording) in targets {
+            match self.stop(Some(source.name())) {
+                Ok(()) | Err(CaptureErr::NotRunning) => {}
+                Err(e) => return Err(e)
+            }
+            self.start_filtered(source, filter, recording)?;
+        }
+        Ok(())
+    }
+
+    /// Record every running capture, and any started later, until told to stop.
+    /// Returns the file each capture is recording to, or why it couldn't.
+    pub fn record(&self, spec: RecordSpec)
+                  -> Result<Vec<(Iface, Result<String, CaptureErr>)>, CaptureErr> {
+        let mut captures = self.captures.write().unwrap();
+        let shared = captures.iter().filter(|c| c.is_running()).count() > 1;
+        let out: Vec<_> = captures.iter_mut()
+            .filter(|c| c.is_running())
+            .map(|c| {
+                let path = spec.path_for(&c.iface, shared);
+                let res = c.record(&path, &spec, false).map(|()| path);
+                (c.iface.clone(), res)
+            })
+            .collect();
+        if out.is_empty() {
+            return Err(CaptureErr::NotRunning);
+        }
+        *self.record.write().unwrap() = Some(spec);
+        Ok(out)
+    }
+
+    /// Stop recording, returning each file that was written and how many packets
+    /// went into it.
+    pub fn stop_recording(&self) -> Result<Vec<(String, u64)>, CaptureErr> {
+        if self.record.write().unwrap().take().is_none() {
+            return Err(CaptureErr::NotRecording);
+        }
+        let mut captures = self.captures.write().unwrap();
+        Ok(captures.iter_mut().filter_map(|c| c.stop_recording()).collect())
+    }
+
+    /// Snapshot the pre-event buffer of every running capture, or only of the one
+    /// that saw traffic on `iface`.  Returns the file each capture started writing,
+    /// or None where a snapshot was already under way and has just been extended.
+    pub fn snapshot(&self, iface: Option<&Iface>)
+                    -> Result<Vec<(Iface, Result<Option<String>, CaptureErr>)>, CaptureErr> {
+        if self.conf.snapshot.is_none() {
+            return Err(CaptureErr::NotBuffering);
+        }
+        let captures = self.captures.read().unwrap();
+        let out: Vec<_> = captures.iter()
+            .filter(|c| c.is_running() && iface.map_or(true, |i| c.saw(i)))
+            .map(|c| (c.iface.clone(), c.snapshot()))
+            .collect();
+        if out.is_empty() {
+            return Err(CaptureErr::NotRunning);
+        }
+        Ok(out)
+    }
+
+    pub fn list(&self) -> Vec<CaptureInfo> {
+        self.captures.read().unwrap().iter().map(|c| c.info()).collect()
+    }
+
+    /// Set the filter on every running capture, returning how each one went.
+    pub fn set_filter(&self, expr: &str) -> Vec<(Iface, Result<(), CaptureErr>)> {
+        let mut captures = self.captures.write().unwrap();
+        captures.iter_mut()
+            .filter(|c| c.is_running())
+            .map(|c| (c.iface.clone(), c.set_filter(expr)))
+            .collect()
+    }
+
+    /// Seek every running replay forward, returning how each one went.
+    pub fn seek(&self, offset: f64) -> Result<Vec<(Iface, Result<(), CaptureErr>)>, CaptureErr> {
+        let captures = self.captures.read().unwrap();
+        let out: Vec<_> = captures.iter()
+            .filter(|c| c.is_replay() && c.is_running())
+            .map(|c| (c.iface.clone(), c.seek(offset)))
+            .collect();
+        if out.is_empty() {
+            return Err(CaptureErr::NotReplaying);
+        }
+        Ok(out)
+    }
+
+    /// Send a pause, resume or speed command to every running replay.
+    pub fn replay(&self, cmd: CaptureCmd) -> Result<(), CaptureErr> {
+        let captures = self.captures.read().unwrap();
+        let mut replays = captures.iter().filter(|c| c.is_replay() && c.is_running()).peekable();
+        if replays.peek().is_none() {
+            return Err(CaptureErr::NotReplaying);
+        }
+        for c in replays {
+            c.send(cmd.clone())?;
+        }
+        Ok(())
+    }
+
+    pub fn replay_status(&self) -> Result<Vec<(Iface, ReplayStatus)>, CaptureErr> {
+        let captures = self.captures.read().unwrap();
+        let mut out = Vec::new();
+        for c in captures.iter().filter(|c| c.is_replay() && c.is_running()) {
+            out.push((c.iface.clone(), c.replay_status()?));
+        }
+        if out.is_empty() {
+            return Err(CaptureErr::NotReplaying);
+        }
+        Ok(out)
+    }
+}
+
+#[derive(Clone)]
+pub struct D3capController {
+    pub pg_ctrl: ProtoGraphController,
+    pub pd_ctrl: PhysDataController,
+    pub mac_names: MacMap,
+    pub ip4_names: IP4Map,
+    pub ip6_names: IP6Map,
+    pub server_started: bool,
+    pub captures: CaptureManager,
+    pub alerts: RecentAlerts,
+    capstats_mcast: Multicast<CapStatsMsg>,
+    alerts_mcast: Multicast<AlertMsg>
+}
+
+impl D3capController {
+    pub fn spawn(conf: D3capConf) -> Result<D3capController, CaptureErr> {
+        let mac_names = conf.mac_names.clone();
+        let ip4_names = HashMap::new();
+        let ip6_names = HashMap::new();
+
+        let (trigger_tx, triggers) = channel();
+        let (alert_tx, alerts) = channel();
+        let pg_ctrl = ProtoGraphController::spawn(&conf.triggers, &mac_names, trigger_tx,
+                                                  &conf.alerts, alert_tx)?;
+        let pd_ctrl = PhysDataController::spawn()?;
+
+        let captures = CaptureManager::spawn(conf.clone(), pg_ctrl.sender(), pd_ctrl.sender(),
+                                             pg_ctrl.arp.clone(),
+                                             Arc::new(mac_names.clone()))?;
+        for source in conf.sources()? {
+            captures.start(source)?;
+        }
+        if let Some(ref path) = conf.write {
+            let spec = RecordSpec {
+                path: path.clone(),
+                filter: conf.write_filter.clone(),
+                rotation: conf.rotation
+            };
+            for (_, res) in captures.record(spec)? {
+                res?;
+            }
+        }
+
+        watch_triggers(triggers, captures.clone())?;
+
+        let capstats_mcast = Multicast::spawn()?;
+        publish_capstats(captures.captures.clone(), capstats_mcast.clone())?;
+        let alerts_mcast = Multicast::spawn()?;
+        let recent = Arc::new(RwLock::new(FixedRingBuffer::new(RECENT_ALERTS)));
+        watch_alerts(alerts, recent.clone(), alerts_mcast.clone())?;
+
+        Ok(D3capController {
             pg_ctrl: pg_ctrl,
             pd_ctrl: pd_ctrl,
             mac_names: mac_names,
             ip4_names: ip4_names,
             ip6_names: ip6_names,
-            server_started: false
+            server_started: false,
+            captures: captures,
+            alerts: recent,
+            capstats_mcast: capstats_mcast,
+            alerts_mcast: alerts_mcast
         })
     }
 
@@ -499,19 +2134,340 @@ impl D3capController {
         if self.server_started {
             println!("server already started");
         } else {
-            start_websocket(port, &self.mac_names, &self.pg_ctrl)?;
+            start_websocket(port, &self.mac_names, &self.pg_ctrl, &self.capstats_mcast,
+                            &self.alerts_mcast)?;
             self.server_started = true;
         }
         Ok(())
     }
 }
 
+const DEFAULT_BUFFER_SIZE: i32 = 0xFFFF;
+const DEFAULT_TIMEOUT_MS: i32 = 1000;
+
+/// How interface captures are set up, from the command line or the [capture] table
+/// of the config file.  Anything left unset gets the default.
+#[derive(Clone, Debug, Default)]
+pub struct CaptureOpts {
+    pub snaplen: Option<i32>,
+    /// Kernel buffer size in bytes.
+    pub buffer_size: Option<i32>,
+    /// Read timeout in milliseconds.
+    pub timeout: Option<i32>,
+    pub tstamp_type: Option<cap::TstampType>,
+    pub immediate: Option<bool>,
+    /// Read from an AF_PACKET ring of our own instead of through libpcap (Linux).
+    pub ring: Option<bool>,
+    /// Split a ring capture between this many parser threads.
+    pub fanout: Option<u16>
+}
+
+impl CaptureOpts {
+    /// These options, with anything unset filled in from `other`.
+    pub fn or(self, other: CaptureOpts) -> CaptureOpts {
+        CaptureOpts {
+            snaplen: self.snaplen.or(other.snaplen),
+            buffer_size: self.buffer_size.or(other.buffer_size),
+            timeout: self.timeout.or(other.timeout),
+            tstamp_type: self.tstamp_type.or(other.tstamp_type),
+            immediate: self.immediate.or(other.immediate),
+            ring: self.ring.or(other.ring),
+            fanout: self.fanout.or(other.fanout)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfError {
+    IOError(io::Error),
+    TomlError(toml::de::Error),
+    /// The named setting has the wrong type or an unknown value.
+    BadValue(&'static str)
+}
+
+impl From<io::Error> for ConfError {
+    fn from(err: io::Error) -> ConfError {
+        ConfError::IOError(err)
+    }
+}
+
+impl From<toml::de::Error> for ConfError {
+    fn from(err: toml::de::Error) -> ConfError {
+        ConfError::TomlError(err)
+    }
+}
+
+impl fmt::Display for ConfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfError::IOError(ref e) => write!(f, "{}", e),
+            ConfError::TomlError(ref e) => write!(f, "{}", e),
+            ConfError::BadValue(key) => write!(f, "bad value for {}", key)
+        }
+    }
+}
+
+/// Read and parse a config file, for the loaders below.
+pub fn load_conf(file: &str) -> Result<toml::Value, ConfError> {
+    let mut s = String::new();
+    File::open(file)?.read_to_string(&mut s)?;
+    Ok(s.parse::<toml::Value>()?)
+}
+
+/// Names for MACs, from the known-macs table of a config file.  Entries are either
+/// just the name, or a table with the name and the IPs the MAC is expected at (see
+/// `load_alert_conf`).
+///
+/// ```toml
+/// [known-macs]
+/// "00:11:22:33:44:55" = "laptop"
+/// ```
+pub fn load_mac_addrs(conf: &toml::Value) -> Result<MacMap, ConfError> {
+    Ok(load_known_macs(conf)?.into_iter()
+        .filter_map(|(mac, name, _)| name.map(|name| (mac, name)))
+        .collect())
+}
+
+// The entries of the known-macs table: each MAC with its name and the IPs it's
+// expected at, either of which can be left out.
+fn load_known_macs(conf: &toml::Value)
+                   -> Result<Vec<(MacAddr, Option<String>, Option<Vec<IP4Addr>>)>, ConfError> {
+    let tbl = match conf.get("known-macs") {
+        Some(v) => v.as_table().ok_or(ConfError::BadValue("known-macs"))?,
+        None => return Ok(vec![])
+    };
+    tbl.iter()
+        .map(|(k, v)| {
+            let mac = MacAddr::from_string(k).ok_or(ConfError::BadValue("known-macs"))?;
+            let name = v.as_str().or_else(|| v.get("name").and_then(|n| n.as_str()));
+            let ips = match v.get("ips") {
+                Some(ips) => Some(ips.as_array().ok_or(ConfError::BadValue("ips"))?.iter()
+                    .map(|ip| ip.as_str().and_then(IP4Addr::from_string))
+                    .collect::<Option<Vec<_>>>()
+                    .ok_or(ConfError::BadValue("ips"))?),
+                None => None
+            };
+            Ok((mac, name.map(|n| n.to_owned()), ips))
+        })
+        .collect()
+}
+
+/// The [capture] table of a config file:
+///
+/// ```toml
+/// [capture]
+/// snaplen = 128
+/// buffer-size = 4194304
+/// timeout = 1000
+/// tstamp-type = "adapter"
+/// immediate = true
+/// ring = true
+/// fanout = 4
+/// ```
+pub fn load_capture_opts(conf: &toml::Value) -> Result<CaptureOpts, ConfError> {
+    let tbl = match conf.get("capture") {
+        Some(v) => v.as_table().ok_or(ConfError::BadValue("capture"))?,
+        None => return Ok(CaptureOpts::default())
+    };
+    let int = |key: &'static str| -> Result<Option<i32>, ConfError> {
+        match tbl.get(key) {
+            Some(v) => match v.as_integer() {
+                Some(n) if n > 0 && n <= i64::from(i32::max_value()) => Ok(Some(n as i32)),
+                _ => Err(ConfError::BadValue(key))
+            },
+            None => Ok(None)
+        }
+    };
+    let tstamp_type = match tbl.get("tstamp-type") {
+        Some(v) => {
+            let typ = v.as_str().and_then(cap::tstamp_type_from_name);
+            Some(typ.ok_or(ConfError::BadValue("tstamp-type"))?)
+        }
+        None => None
+    };
+    let flag = |key: &'static str| -> Result<Option<bool>, ConfError> {
+        match tbl.get(key) {
+            Some(v) => Ok(Some(v.as_bool().ok_or(ConfError::BadValue(key))?)),
+            None => Ok(None)
+        }
+    };
+    let fanout = match int("fanout")? {
+        Some(n) if n > i32::from(u16::max_value()) => return Err(ConfError::BadValue("fanout")),
+        n => n.map(|n| n as u16)
+    };
+    Ok(CaptureOpts {
+        snaplen: int("snaplen")?,
+        buffer_size: int("buffer-size")?,
+        timeout: int("timeout")?,
+        tstamp_type: tstamp_type,
+        immediate: flag("immediate")?,
+        ring: flag("ring")?,
+        fanout: fanout
+    })
+}
+
+/// The [alerts] table of a config file, along with the IPs known-macs entries are
+/// expected at:
+///
+/// ```toml
+/// [alerts]
+/// max-ips-per-mac = 4
+/// binding-age = 300
+/// gratuitous-rate = 20
+///
+/// [known-macs]
+/// "00:11:22:33:44:55" = "laptop"
+/// "66:77:88:99:aa:bb" = { name = "router", ips = ["192.168.1.1", "10.0.0.1"] }
+/// ```
+pub fn load_alert_conf(conf: &toml::Value) -> Result<AlertConf, ConfError> {
+    let mut alerts = AlertConf::default();
+    if let Some(v) = conf.get("alerts") {
+        let tbl = v.as_table().ok_or(ConfError::BadValue("alerts"))?;
+        let int = |key: &'static str| -> Result<Option<u64>, ConfError> {
+            match tbl.get(key) {
+                Some(v) => match v.as_integer() {
+                    Some(n) if n >= 0 => Ok(Some(n as u64)),
+                    _ => Err(ConfError::BadValue(key))
+                },
+                None => Ok(None)
+            }
+        };
+        if let Some(n) = int("max-ips-per-mac")? {
+            alerts.max_ips_per_mac = n as usize;
+        }
+        if let Some(n) = int("binding-age")? {
+            alerts.binding_age = n as i64;
+        }
+        if let Some(n) = int("gratuitous-rate")? {
+            alerts.gratuitous_rate = n;
+        }
+    }
+    for (mac, _, ips) in load_known_macs(conf)? {
+        if let Some(ips) = ips {
+            alerts.known_ips.insert(mac, ips);
+        }
+    }
+    Ok(alerts)
+}
+
 #[derive(Clone, Debug)]
 pub struct D3capConf {
     pub websocket: Option<u16>,
-    pub interface: Option<String>,
-    pub file: Option<String>,
-    pub conf: Option<String>,
+    pub interfaces: Vec<String>,
+    pub files: Vec<String>,
+    pub filter: Option<String>,
+    pub write: Option<String>,
+    pub write_filter: Option<String>,
+    pub rotation: Rotation,
+    pub snapshot: Option<SnapshotConf>,
+    pub triggers: TriggerConf,
+    pub alerts: AlertConf,
+    /// Names for MACs, from known-macs in the config file.
+    pub mac_names: MacMap,
+    pub replay_speed: Speed,
     pub promisc: bool,
-    pub monitor: bool
+    pub monitor: bool,
+    /// Link types to capture with, by interface, for devices that offer more than one.
+    pub datalinks: HashMap<String, cap::DataLinkType>,
+    pub capture: CaptureOpts
+}
+
+impl D3capConf {
+    /// Everything to capture from; libpcap's default device if nothing was given.
+    fn sources(&self) -> Result<Vec<CaptureSource>, cap::PcapError> {
+        let mut out: Vec<_> = self.interfaces.iter().cloned().map(CaptureSource::Interface)
+            .chain(self.files.iter().cloned().map(CaptureSource::File))
+            .collect();
+        if out.is_empty() {
+            out.push(CaptureSource::Interface(cap::default_device()?));
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+fn loopback_family(dlt: cap::DataLinkType, hdr: [u8; 4]) -> Result<u8, DecodeErr> {
+    let parser = IpParser { iface: Arc::new("lo0".to_owned()), dlt: dlt };
+    let mut pkt = hdr.to_vec();
+    pkt.extend(&[0x45, 0x00, 0x00, 0x14]);
+    let (version, ip) = parser.locate(&pkt)?;
+    assert_eq!(ip, &pkt[4..]);
+    Ok(version)
+}
+
+#[test]
+fn test_null_byte_order() {
+    // Written by a little-endian machine, then by a big-endian one.
+    assert_eq!(loopback_family(cap::DLT_NULL, [2, 0, 0, 0]), Ok(4));
+    assert_eq!(loopback_family(cap::DLT_NULL, [0, 0, 0, 2]), Ok(4));
+    for &af in &[AF_INET6_BSD, AF_INET6_FREEBSD, AF_INET6_DARWIN] {
+        let af = af as u8;
+        assert_eq!(loopback_family(cap::DLT_NULL, [af, 0, 0, 0]), Ok(6));
+        assert_eq!(loopback_family(cap::DLT_NULL, [0, 0, 0, af]), Ok(6));
+    }
+    assert_eq!(loopback_family(cap::DLT_NULL, [0, 0, 0, 99]), Ok(0));
+}
+
+#[test]
+fn test_loop_network_order() {
+    assert_eq!(loopback_family(cap::DLT_LOOP, [0, 0, 0, 2]), Ok(4));
+    assert_eq!(loopback_family(cap::DLT_LOOP, [0, 0, 0, 24]), Ok(6));
+    assert_eq!(loopback_family(cap::DLT_LOOP, [0, 0, 0, 30]), Ok(6));
+    // DLT_LOOP is always big-endian, so a host-order family isn't one.
+    assert_eq!(loopback_family(cap::DLT_LOOP, [2, 0, 0, 0]), Ok(0));
+
+    let parser = IpParser { iface: Arc::new("lo0".to_owned()), dlt: cap::DLT_LOOP };
+    assert_eq!(parser.locate(&[0, 0]).err(), Some(DecodeErr::Truncated(Layer::Loopback)));
+}
+
+#[cfg(test)]
+fn arp_frame(sha: [u8; 6], spa: [u8; 4]) -> Vec<u8> {
+    let mut frame = vec![0xff; 6];
+    frame.extend(&sha);
+    frame.extend(&[0x08, 0x06, 0x00, 0x01, 0x08, 0x00, 0x06, 0x04, 0x00, 0x02]);
+    frame.extend(&sha);
+    frame.extend(&spa);
+    frame.extend(&[0; 6]);
+    frame.extend(&[10, 0, 0, 1]);
+    frame
+}
+
+#[test]
+fn test_alert_noted_on_its_frame() {
+    use std::io::Cursor;
+    use arp::{MAC_A, MAC_B};
+    use pcap::savefile::{Interface, Packet, PcapngWriter};
+
+    // 10.0.0.5 moves from one MAC to another in the second of two ARP replies.
+    let mut out = Vec::new();
+    {
+        let mut w = PcapngWriter::new(&mut out, "test").unwrap();
+        w.add_interface(&Interface::new(1, 65535)).unwrap();
+        for (i, &mac) in [MAC_A, MAC_B].iter().enumerate() {
+            let frame = arp_frame(mac, [10, 0, 0, 5]);
+            w.write_packet(&Packet {
+                interface: 0, linktype: 0, sec: i as i64, nsec: 0, len: frame.len() as u32,
+                data: &frame, comment: None
+            }).unwrap();
+        }
+    }
+    let sess = cap::PcapSession::from_reader_native(Box::new(Cursor::new(out))).unwrap();
+
+    let (pkts, _pkts_rx) = channel();
+    let (phys, _phys_rx) = channel();
+    let (alert_tx, alert_rx) = channel();
+    let arp = ArpHandler::new(&AlertConf::default(), alert_tx).unwrap();
+    let mut parsers = Parsers::new(pkts, phys, arp, Arc::new("eth0".to_owned()), false);
+    let mut comments = vec![];
+    for _ in 0..2 {
+        sess.next(|pkt| {
+            let mut alerts = Vec::new();
+            assert!(parsers.parse(pkt, &mut alerts).is_ok());
+            comments.push(annotate(pkt, &HashMap::new(), &alerts));
+        }).unwrap();
+    }
+    let alert = format!("alert: eth0: 10.0.0.5 moved from {} to {}",
+                        MacAddr::from_bytes(&MAC_A), MacAddr::from_bytes(&MAC_B));
+    assert_eq!(comments, vec![None, Some(alert)]);
+    assert_eq!(alert_rx.try_iter().count(), 1);
 }
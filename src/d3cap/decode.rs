@@ -0,0 +1,283 @@
+//! Bounds-checked views of the headers in a captured packet.  Each step takes the
+//! bytes left over from the layer below and hands back a reference to its header
+//! and the bytes after it, without copying, or a `DecodeErr` saying which layer
+//! didn't fit.
+
+use std::mem::size_of;
+
+use arp::{self, ArpHeader};
+use dot11::{Dot11BaseHeader, DataFrameHeader, ManagementFrameHeader};
+use ether::{EthernetHeader, ETHERTYPE_IP4};
+use ip::{IP4Header, IP6Header};
+use sll::{SllHeader, Sll2Header};
+use tap::{CommonA, CommonB, RadiotapHeader};
+
+/// The header a decode error is about.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Layer {
+    Ethernet,
+    Sll,
+    Sll2,
+    Loopback,
+    Radiotap,
+    Ppi,
+    Prism,
+    Avs,
+    Dot11,
+    Arp,
+    IP4,
+    IP6
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DecodeErr {
+    /// The capture ends before the header does, whether from the snaplen or a
+    /// length field that runs past the end of the packet.
+    Truncated(Layer),
+    /// A length field that's too small for the header it's in, like an IPv4 IHL
+    /// under 5.
+    BadLength(Layer),
+    /// A version or type field for something we don't decode.
+    Unsupported(Layer)
+}
+
+/// Header structs that can be viewed in place over packet bytes.  Implementors must
+/// be `#[repr(packed)]`, so any offset is aligned, and made of plain integers, so
+/// any bytes are a valid value.
+pub unsafe trait Header: Sized {}
+
+unsafe impl Header for EthernetHeader {}
+unsafe impl Header for ArpHeader {}
+unsafe impl Header for SllHeader {}
+unsafe impl Header for Sll2Header {}
+unsafe impl Header for IP4Header {}
+unsafe impl Header for IP6Header {}
+unsafe impl Header for RadiotapHeader {}
+unsafe impl Header for CommonA {}
+unsafe impl Header for CommonB {}
+unsafe impl Header for Dot11BaseHeader {}
+unsafe impl Header for DataFrameHeader {}
+unsafe impl Header for ManagementFrameHeader {}
+
+/// The `H` at the start of `data`, and the bytes after it.
+pub fn header<H: Header>(data: &[u8], layer: Layer) -> Result<(&H, &[u8]), DecodeErr> {
+    if data.len() < size_of::<H>() {
+        return Err(DecodeErr::Truncated(layer));
+    }
+    let hdr = unsafe { &*(data.as_ptr() as *const H) };
+    Ok((hdr, &data[size_of::<H>()..]))
+}
+
+/// Split `data` after a header that says it's `len` bytes long, checking that's at
+/// least `min` and no more than was captured.
+pub fn split(data: &[u8], len: usize, min: usize, layer: Layer)
+             -> Result<(&[u8], &[u8]), DecodeErr> {
+    if len < min {
+        Err(DecodeErr::BadLength(layer))
+    } else if len > data.len() {
+        Err(DecodeErr::Truncated(layer))
+    } else {
+        Ok(data.split_at(len))
+    }
+}
+
+pub fn ethernet(data: &[u8]) -> Result<(&EthernetHeader, &[u8]), DecodeErr> {
+    header(data, Layer::Ethernet)
+}
+
+/// An ARP header, checking it's for IPv4 and MAC addresses.
+pub fn arp(data: &[u8]) -> Result<(&ArpHeader, &[u8]), DecodeErr> {
+    let (hdr, rest) = header::<ArpHeader>(data, Layer::Arp)?;
+    let htype = hdr.htype.get();
+    if (htype != arp::HTYPE_ETHER && htype != arp::HTYPE_IEEE802) ||
+        hdr.ptype.get() != ETHERTYPE_IP4 || hdr.hlen != 6 || hdr.plen != 4 {
+        return Err(DecodeErr::Unsupported(Layer::Arp));
+    }
+    Ok((hdr, rest))
+}
+
+/// An IPv4 header, checking the version and that the IHL covers at least the fixed
+/// part of the header and no more than was captured.  Options are skipped.
+pub fn ip4(data: &[u8]) -> Result<(&IP4Header, &[u8]), DecodeErr> {
+    let (hdr, _) = header::<IP4Header>(data, Layer::IP4)?;
+    if hdr.ver_ihl >> 4 != 4 {
+        return Err(DecodeErr::Unsupported(Layer::IP4));
+    }
+    let ihl = (hdr.ver_ihl & 0x0f) as usize * 4;
+    let (_, rest) = split(data, ihl, size_of::<IP4Header>(), Layer::IP4)?;
+    Ok((hdr, rest))
+}
+
+pub fn ip6(data: &[u8]) -> Result<(&IP6Header, &[u8]), DecodeErr> {
+    let (hdr, rest) = header::<IP6Header>(data, Layer::IP6)?;
+    if hdr.ver_tc_fl.get() >> 28 != 6 {
+        return Err(DecodeErr::Unsupported(Layer::IP6));
+    }
+    Ok((hdr, rest))
+}
+
+/// A radiotap header, the fields between it and `it_len`, and the frame after.
+pub fn radiotap(data: &[u8]) -> Result<(&RadiotapHeader, &[u8], &[u8]), DecodeErr> {
+    let (hdr, _) = header::<RadiotapHeader>(data, Layer::Radiotap)?;
+    if hdr.it_version != 0 {
+        return Err(DecodeErr::Unsupported(Layer::Radiotap));
+    }
+    let len = hdr.it_len.get() as usize;
+    let (all, frame) = split(data, len, size_of::<RadiotapHeader>(), Layer::Radiotap)?;
+    Ok((hdr, &all[size_of::<RadiotapHeader>()..], frame))
+}
+
+/// The addresses of an 802.11 frame, for the frame types that have them.
+pub enum Dot11Addrs<'a> {
+    Management(&'a ManagementFrameHeader),
+    Data(&'a DataFrameHeader),
+    Other
+}
+
+/// An 802.11 frame's base header, and the header for its type if it has addresses.
+pub fn dot11<'a>(frame: &'a [u8]) -> Result<(&'a Dot11BaseHeader, Dot11Addrs<'a>), DecodeErr> {
+    use dot11::FrameType;
+    let (base, _) = header::<Dot11BaseHeader>(frame, Layer::Dot11)?;
+    let addrs = match base.fr_ctrl.frame_type() {
+        FrameType::Management => Dot11Addrs::Management(header(frame, Layer::Dot11)?.0),
+        FrameType::Data => Dot11Addrs::Data(header(frame, Layer::Dot11)?.0),
+        FrameType::Control | FrameType::Unknown => Dot11Addrs::Other
+    };
+    Ok((base, addrs))
+}
+
+// An Ethernet frame carrying the start of a TCP SYN from 192.168.1.10 to 8.8.8.8.
+#[cfg(test)]
+const ETHER_IP4: [u8; 38] = [
+    0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0x08, 0x00,
+    0x45, 0x00, 0x00, 0x3c, 0x1c, 0x46, 0x40, 0x00, 0x40, 0x06, 0xb1, 0xe6,
+    0xc0, 0xa8, 0x01, 0x0a, 0x08, 0x08, 0x08, 0x08,
+    0xd4, 0x31, 0x00, 0x50
+];
+
+#[test]
+fn test_ethernet_ip4() {
+    let (eth, payload) = ethernet(&ETHER_IP4).unwrap();
+    assert_eq!(eth.dst.to_string(), "00:11:22:33:44:55");
+    assert_eq!(eth.src.to_string(), "66:77:88:99:aa:bb");
+    assert_eq!(eth.typ.get(), ::ether::ETHERTYPE_IP4);
+
+    let (ip, rest) = ip4(payload).unwrap();
+    assert_eq!(ip.len.get(), 60);
+    assert_eq!(ip.ident.get(), 0x1c46);
+    assert_eq!(ip.hchk.get(), 0xb1e6);
+    assert_eq!(ip.src.to_string(), "192.168.1.10");
+    assert_eq!(ip.dst.to_string(), "8.8.8.8");
+    assert_eq!(rest, &[0xd4, 0x31, 0x00, 0x50]);
+}
+
+#[test]
+fn test_ip4_ihl() {
+    let mut ip = ETHER_IP4[14..].to_vec();
+    ip[0] = 0x46;
+    assert_eq!(ip4(&ip).unwrap().1, &[]);
+    ip[0] = 0x44;
+    assert_eq!(ip4(&ip).err(), Some(DecodeErr::BadLength(Layer::IP4)));
+    ip[0] = 0x4f;
+    assert_eq!(ip4(&ip).err(), Some(DecodeErr::Truncated(Layer::IP4)));
+    ip[0] = 0x65;
+    assert_eq!(ip4(&ip).err(), Some(DecodeErr::Unsupported(Layer::IP4)));
+    assert_eq!(ip4(&ip[..19]).err(), Some(DecodeErr::Truncated(Layer::IP4)));
+    assert_eq!(ethernet(&ETHER_IP4[..13]).err(), Some(DecodeErr::Truncated(Layer::Ethernet)));
+}
+
+#[test]
+fn test_ethernet_ip6() {
+    let mut frame = vec![0x33, 0x33, 0x00, 0x00, 0x00, 0x01, 0x02, 0x42, 0xac, 0x11, 0x00,
+                         0x02, 0x86, 0xdd,
+                         0x60, 0x00, 0x00, 0x00, 0x00, 0x20, 0x3a, 0xff];
+    frame.extend(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x12, 0x34]);
+    frame.extend(&[0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+
+    let (eth, payload) = ethernet(&frame).unwrap();
+    assert_eq!(eth.typ.get(), ::ether::ETHERTYPE_IP6);
+    let (ip, rest) = ip6(payload).unwrap();
+    assert_eq!(ip.len.get(), 32);
+    assert_eq!(ip.nxthdr, 58);
+    assert_eq!(ip.src.to_string(), "2001:0db8:0000:0000:0000:0000:0000:1234");
+    assert_eq!(ip.dst.to_string(), "fe80:0000:0000:0000:0000:0000:0000:0001");
+    assert!(rest.is_empty());
+    assert_eq!(ip6(&payload[..39]).err(), Some(DecodeErr::Truncated(Layer::IP6)));
+}
+
+#[test]
+fn test_cooked() {
+    let pkt = [0x08, 0x06, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x01, 0x04, 0x06,
+               0x02, 0x42, 0xac, 0x11, 0x00, 0x02, 0x00, 0x00, 0xff];
+    let (hdr, rest) = header::<Sll2Header>(&pkt, Layer::Sll2).unwrap();
+    assert_eq!(hdr.protocol.get(), ::ether::ETHERTYPE_ARP);
+    assert_eq!(hdr.if_index(), 2);
+    assert_eq!(hdr.sender().unwrap().to_string(), "02:42:ac:11:00:02");
+    assert_eq!(rest, &[0xff]);
+
+    let pkt = [0x00, 0x04, 0x03, 0x04, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0x08, 0x00];
+    let (hdr, _) = header::<SllHeader>(&pkt, Layer::Sll).unwrap();
+    assert_eq!(hdr.protocol.get(), ::ether::ETHERTYPE_IP4);
+    assert!(hdr.sender().is_none());
+}
+
+// A radiotap header with the CommonA fields, in front of the start of a beacon.
+#[cfg(test)]
+const RADIOTAP: [u8; 27] = [
+    0x00, 0x00, 0x19, 0x00, 0x6f, 0x08, 0x00, 0x00,
+    0x10, 0x32, 0x54, 0x76, 0x00, 0x00, 0x00, 0x00, // tsft
+    0x10, // flags: includes FCS
+    0x0c, // 6Mbps
+    0x85, 0x09, 0xa0, 0x00, // 2437MHz, CCK | 2GHz
+    0xd8, 0xa1, 0x01, // -40dBm, -95dBm, antenna 1
+    0x80, 0x00
+];
+
+#[test]
+fn test_radiotap() {
+    let (hdr, fields, frame) = radiotap(&RADIOTAP).unwrap();
+    assert!(hdr.has_field(::tap::ItPresent::CHANNEL));
+    assert!(!hdr.has_field(::tap::ItPresent::MCS));
+    assert_eq!(fields.len(), 17);
+    assert_eq!(frame, &[0x80, 0x00]);
+
+    let common = CommonA::parse(hdr, fields).unwrap();
+    assert_eq!(common.tsft.timer_micros.get(), 0x7654_3210);
+    assert_eq!(common.rate.in_500kbps, 12);
+    let channel = common.channel.get();
+    assert_eq!(channel.mhz, 2437);
+    assert_eq!(channel.flags, ::tap::ChannelFlags::CCK | ::tap::ChannelFlags::GHZ_2);
+    assert_eq!(common.antenna_signal.dbm, -40);
+    assert_eq!(common.antenna_noise.dbm, -95);
+    assert!(CommonB::parse(hdr, fields).is_none());
+}
+
+#[test]
+fn test_radiotap_it_len() {
+    assert_eq!(radiotap(&RADIOTAP[..24]).err(), Some(DecodeErr::Truncated(Layer::Radiotap)));
+    assert_eq!(radiotap(&RADIOTAP[..7]).err(), Some(DecodeErr::Truncated(Layer::Radiotap)));
+    let mut short = RADIOTAP;
+    short[2] = 4;
+    assert_eq!(radiotap(&short).err(), Some(DecodeErr::BadLength(Layer::Radiotap)));
+    // The fields a header says it has have to fit in its it_len.
+    short[2] = 20;
+    let (hdr, fields, _) = radiotap(&short).unwrap();
+    assert!(CommonA::parse(hdr, fields).is_none());
+}
+
+#[test]
+fn test_dot11() {
+    let mut frame = vec![0x08, 0x02, 0x2c, 0x00];
+    frame.extend(&[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x0c, 0x29, 0x01, 0x02, 0x03,
+                   0x00, 0x0c, 0x29, 0x0a, 0x0b, 0x0c, 0x10, 0x00]);
+    let (base, addrs) = dot11(&frame).unwrap();
+    assert_eq!(base.fr_ctrl.protocol_version(), 0);
+    match addrs {
+        Dot11Addrs::Data(data) => {
+            assert_eq!(data.addr1.to_string(), "ff:ff:ff:ff:ff:ff");
+            assert_eq!(data.addr2.to_string(), "00:0c:29:01:02:03");
+        }
+        _ => panic!("not a data frame")
+    }
+    assert_eq!(dot11(&frame[..23]).err(), Some(DecodeErr::Truncated(Layer::Dot11)));
+}
@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use ether::{MacAddr};
+use util::Le16;
 
 // For possible reference:
 // https://github.com/simsong/tcpflow/blob/master/src/wifipcap/wifipcap.h
@@ -64,7 +65,7 @@ pub enum FrameType {
 #[derive(Copy, Clone, Debug)]
 #[repr(packed)]
 pub struct DurationID {
-    dur_id: u16
+    dur_id: Le16
 }
 
 #[derive(Copy, Clone, Debug)]
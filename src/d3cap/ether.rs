@@ -3,6 +3,8 @@ use std::fmt::{Display, Error, Formatter};
 use rustc_serialize::hex::FromHex;
 use rustc_serialize::{Encoder,Encodable};
 
+use util::Be16;
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
 pub struct MacAddr([u8; 6]);
 
@@ -21,6 +23,13 @@ impl Encodable for MacAddr {
 }
 
 impl MacAddr {
+    /// The address in the first six bytes of `b`.
+    pub fn from_bytes(b: &[u8]) -> MacAddr {
+        let mut out = [0; 6];
+        out.copy_from_slice(&b[..6]);
+        MacAddr(out)
+    }
+
     pub fn from_string(mac: &str) -> Option<MacAddr> {
         let v: Vec<_> = mac.split(':').collect();
         if v.len() == 6 {
@@ -43,11 +52,10 @@ impl MacAddr {
 pub struct EthernetHeader {
     pub dst: MacAddr,
     pub src: MacAddr,
-    pub typ: u16
+    pub typ: Be16
 }
 
-//in big-endian order to match packet
-pub const ETHERTYPE_ARP: u16 = 0x0608;
-pub const ETHERTYPE_IP4: u16 = 0x0008;
-pub const ETHERTYPE_IP6: u16 = 0xDD86;
-pub const ETHERTYPE_802_1X: u16 = 0x8E88;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_IP4: u16 = 0x0800;
+pub const ETHERTYPE_IP6: u16 = 0x86DD;
+pub const ETHERTYPE_802_1X: u16 = 0x888E;
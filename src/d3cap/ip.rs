@@ -3,6 +3,8 @@ use std::{net};
 
 use rustc_serialize::{Encodable, Encoder};
 
+use util::{Be16, Be32};
+
 pub trait AsStdIpAddr {
     fn as_std_ip(&self) -> net::IpAddr;
 }
@@ -11,6 +13,18 @@ pub trait AsStdIpAddr {
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
 pub struct IP4Addr([u8; 4]);
 
+impl IP4Addr {
+    /// Parse dotted-quad notation, e.g. "192.168.1.1".
+    pub fn from_string(ip: &str) -> Option<IP4Addr> {
+        ip.parse::<net::Ipv4Addr>().ok().map(|a| IP4Addr(a.octets()))
+    }
+
+    /// 0.0.0.0
+    pub fn is_unspecified(&self) -> bool {
+        self.0 == [0; 4]
+    }
+}
+
 impl AsStdIpAddr for IP4Addr {
     fn as_std_ip(&self) -> net::IpAddr {
         let &IP4Addr(a) = self;
@@ -36,30 +50,41 @@ impl Encodable for IP4Addr {
 pub struct IP4Header {
     pub ver_ihl: u8,
     pub dscp_ecn: u8,
-    pub len: u16,
-    pub ident: u16,
-    pub flags_frag: u16,
+    pub len: Be16,
+    pub ident: Be16,
+    pub flags_frag: Be16,
     pub ttl: u8,
     pub proto: u8,
-    pub hchk: u16,
+    pub hchk: Be16,
     pub src: IP4Addr,
     pub dst: IP4Addr,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
-pub struct IP6Addr([u16; 8]);
+pub struct IP6Addr([u8; 16]);
+
+impl IP6Addr {
+    /// The address as eight 16-bit groups.
+    pub fn segments(&self) -> [u16; 8] {
+        let &IP6Addr(b) = self;
+        let mut out = [0; 8];
+        for (i, seg) in out.iter_mut().enumerate() {
+            *seg = u16::from(b[2 * i]) << 8 | u16::from(b[2 * i + 1]);
+        }
+        out
+    }
+}
 
 impl AsStdIpAddr for IP6Addr {
     fn as_std_ip(&self) -> net::IpAddr {
-        let &IP6Addr(a) = self;
+        let a = self.segments();
         net::IpAddr::V6(net::Ipv6Addr::new(a[0], a[1], a[2], a[3], a[4], a[5], a[6], a[7]))
     }
 }
 
 impl Display for IP6Addr {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        let &IP6Addr(a) = self;
-        match a {
+        match self.segments() {
             //ip4-compatible
             [0,0,0,0,0,0,g,h] => {
                 f.write_str(&format!("::{}.{}.{}.{}",
@@ -89,8 +114,8 @@ impl Encodable for IP6Addr {
 
 #[repr(packed)]
 pub struct IP6Header {
-    pub ver_tc_fl: u32,
-    pub len: u16,
+    pub ver_tc_fl: Be32,
+    pub len: Be16,
     pub nxthdr: u8,
     pub hoplim: u8,
     pub src: IP6Addr,
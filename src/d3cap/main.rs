@@ -23,26 +23,64 @@ extern crate json_serve;
 mod util;
 mod ip;
 mod ether;
+mod arp;
 mod dot11;
 mod tap;
+mod radio;
+mod sll;
+mod decode;
 mod pkt_graph;
 mod d3cap;
 mod readline;
+mod replay;
+mod record;
+mod trigger;
+mod alert;
 mod cli;
 
 
 fn main() {
 
     use getopts as go;
-    use std::{env};
-    use d3cap::{D3capConf, D3capController};
+    use std::{env, process, thread};
+    use std::collections::HashMap;
+    use std::time::Duration;
+    use d3cap::{self, CaptureOpts, CaptureState, D3capConf, D3capController};
+    use replay::Speed;
+    use record::{Rotation, SnapshotConf};
+    use trigger::TriggerConf;
+    use alert::AlertConf;
+    use pcap::pcap as cap;
 
     let interface_opt = "i";
     let file_opt = "f";
     let conf_opt = "c";
+    let filter_opt = "filter";
+    let speed_opt = "speed";
+    let write_opt = "write";
+    let write_filter_opt = "write-filter";
+    let rotate_size_opt = "rotate-size";
+    let rotate_secs_opt = "rotate-secs";
+    let rotate_keep_opt = "rotate-keep";
+    let snapshot_opt = "snapshot";
+    let pre_secs_opt = "pre-secs";
+    let pre_mb_opt = "pre-mb";
+    let post_secs_opt = "post-secs";
+    let trigger_rate_opt = "trigger-rate";
+    let trigger_mac_flag = "trigger-new-mac";
 
     let promisc_flag = "P";
     let monitor_flag = "M";
+    let datalink_opt = "datalink";
+    let snaplen_opt = "snaplen";
+    let buffer_size_opt = "buffer-size";
+    let timeout_opt = "timeout";
+    let tstamp_type_opt = "tstamp-type";
+    let immediate_flag = "immediate";
+    let no_immediate_flag = "no-immediate";
+    let ring_flag = "ring";
+    let fanout_opt = "fanout";
+    let list_ifaces_flag = "list-interfaces";
 
     let websocket_opt = "websocket";
     let websocket_default = "7432";
@@ -50,11 +88,45 @@ fn main() {
     let mut opts = go::Options::new();
 
     opts.optflag("h", "help", "Print this help menu")
-        .optopt(interface_opt, "interface", "Network interface to listen on", "interface")
-        .optopt(file_opt, "file", "File to load from", "cap_file")
+        .optmulti(interface_opt, "interface", "Network interface to listen on, may be repeated",
+                  "interface")
+        .optmulti(file_opt, "file",
+                  "File or named pipe to load from, - for stdin; may be repeated", "cap_file")
         .optopt(conf_opt, "conf", "Configuration file", "conf_file")
+        .optopt("", filter_opt, "BPF filter expression applied to the capture", "expr")
+        .optopt("", write_opt,
+                "Record captured packets to a pcap file, or pcapng if it ends in .pcapng",
+                "out_file")
+        .optopt("", write_filter_opt, "Only record packets matching this BPF filter", "expr")
+        .optopt("", rotate_size_opt, "Start a new record file after this many megabytes", "mb")
+        .optopt("", rotate_secs_opt, "Start a new record file after this many seconds", "secs")
+        .optopt("", rotate_keep_opt, "Only keep this many record files", "count")
+        .optopt("", snapshot_opt, "Buffer recent packets and write snapshots of them here",
+                "out_file")
+        .optopt("", pre_secs_opt, "Seconds of packets to buffer for snapshots [10]", "secs")
+        .optopt("", pre_mb_opt, "Megabytes of packets to buffer for snapshots", "mb")
+        .optopt("", post_secs_opt, "Seconds to keep writing after a snapshot [10]", "secs")
+        .optopt("", trigger_rate_opt, "Snapshot when a route goes over this many bytes/s",
+                "rate")
+        .optflag("", trigger_mac_flag,
+                 "Snapshot when a new mac address shows up, after a minute's warm-up")
+        .optopt("", speed_opt, "Replay speed for capture files, a multiplier or 'max' [max]",
+                "speed")
         .optflag(promisc_flag, "promisc", "Turn on promiscuous mode")
         .optflag(monitor_flag, "monitor", "Turn on monitor mode")
+        .optmulti("", datalink_opt,
+                  "Link type to capture from an interface with, by name or number (see 'ls \
+                   datalinks'); may be repeated", "iface=type")
+        .optopt("", snaplen_opt, "Only capture this many bytes of each packet", "bytes")
+        .optopt("", buffer_size_opt, "Capture buffer size [65535]", "bytes")
+        .optopt("", timeout_opt, "Capture read timeout [1000]", "ms")
+        .optopt("", tstamp_type_opt, "Where timestamps come from, see pcap-tstamp(7)", "type")
+        .optflag("", immediate_flag, "Deliver packets as soon as they arrive")
+        .optflag("", no_immediate_flag, "Buffer packets even if the config file says not to")
+        .optflag("", ring_flag, "Capture with an AF_PACKET ring instead of libpcap (Linux)")
+        .optopt("", fanout_opt, "Split ring captures between this many parser threads",
+                "threads")
+        .optflag("", list_ifaces_flag, "List available network interfaces and exit")
         .optflagopt("", websocket_opt, "Run websocket ui server on startup",
                     &format!("port [{}]", websocket_default));
 
@@ -68,6 +140,65 @@ fn main() {
         return;
     }
 
+    if matches.opt_present(list_ifaces_flag) {
+        cli::print_ifaces();
+        return;
+    }
+
+    let num_opt = |opt: &str| matches.opt_str(opt).map(|s| {
+        match s.parse::<i32>() {
+            Ok(v) if v > 0 => v,
+            _ => panic!("{} must be a positive number", opt)
+        }
+    });
+    let capture = CaptureOpts {
+        snaplen: num_opt(snaplen_opt),
+        buffer_size: num_opt(buffer_size_opt),
+        timeout: num_opt(timeout_opt),
+        tstamp_type: matches.opt_str(tstamp_type_opt).map(|s| {
+            match cap::tstamp_type_from_name(&s) {
+                Some(typ) => typ,
+                None => panic!("unknown timestamp type: {}", s)
+            }
+        }),
+        immediate: match (matches.opt_present(immediate_flag),
+                          matches.opt_present(no_immediate_flag)) {
+            (true, true) => panic!("{} and {} contradict each other", immediate_flag,
+                                   no_immediate_flag),
+            (true, false) => Some(true),
+            (false, true) => Some(false),
+            (false, false) => None
+        },
+        ring: if matches.opt_present(ring_flag) { Some(true) } else { None },
+        fanout: num_opt(fanout_opt).map(|n| {
+            if n > i32::from(u16::max_value()) {
+                panic!("{} must be at most {}", fanout_opt, u16::max_value());
+            }
+            n as u16
+        })
+    };
+    let (capture, alerts, mac_names) = match matches.opt_str(conf_opt) {
+        Some(path) => {
+            let loaded = d3cap::load_conf(&path).and_then(|t| {
+                Ok((d3cap::load_capture_opts(&t)?, d3cap::load_alert_conf(&t)?,
+                    d3cap::load_mac_addrs(&t)?))
+            });
+            match loaded {
+                // The command line wins over the config file.
+                Ok((from_file, alerts, names)) => (capture.or(from_file), alerts, names),
+                Err(e) => {
+                    println!("Could not load {}: {}", path, e);
+                    process::exit(1);
+                }
+            }
+        }
+        None => (capture, AlertConf::default(), HashMap::new())
+    };
+    // Only our own rings can be split between threads.
+    if capture.fanout.is_some() && capture.ring != Some(true) {
+        panic!("{} needs {}", fanout_opt, ring_flag);
+    }
+
     let conf = D3capConf {
         websocket: matches.opt_default(websocket_opt, "7432").map(|p| {
             match p.parse::<u16>() {
@@ -75,19 +206,104 @@ fn main() {
                 _ => panic!("websocket port must be a number")
             }
         }),
-        interface: matches.opt_str(interface_opt),
-        file: matches.opt_str(file_opt),
-        conf: matches.opt_str(conf_opt),
+        interfaces: matches.opt_strs(interface_opt),
+        files: matches.opt_strs(file_opt),
+        filter: matches.opt_str(filter_opt),
+        write: matches.opt_str(write_opt),
+        write_filter: matches.opt_str(write_filter_opt),
+        rotation: Rotation {
+            max_bytes: matches.opt_str(rotate_size_opt).map(|s| {
+                match s.parse::<u64>() {
+                    Ok(v) if v > 0 => v * 1024 * 1024,
+                    _ => panic!("rotate size must be a positive number")
+                }
+            }),
+            max_secs: matches.opt_str(rotate_secs_opt).map(|s| {
+                match s.parse::<u64>() {
+                    Ok(v) if v > 0 => v,
+                    _ => panic!("rotate seconds must be a positive number")
+                }
+            }),
+            keep: matches.opt_str(rotate_keep_opt).map(|s| {
+                match s.parse::<usize>() {
+                    Ok(v) if v > 0 => v,
+                    _ => panic!("rotate keep must be a positive number")
+                }
+            })
+        },
+        replay_speed: matches.opt_str(speed_opt).map_or(Speed::Max, |s| {
+            match Speed::parse(&s) {
+                Some(v) => v,
+                None => panic!("speed must be a positive number or 'max'")
+            }
+        }),
+        snapshot: matches.opt_str(snapshot_opt).map(|path| {
+            let num = |opt: &str| matches.opt_str(opt).map(|s| {
+                match s.parse::<u64>() {
+                    Ok(v) => v,
+                    _ => panic!("{} must be a number", opt)
+                }
+            });
+            let pre_bytes = num(pre_mb_opt).map(|mb| mb * 1024 * 1024);
+            let pre_secs = match num(pre_secs_opt) {
+                None if pre_bytes.is_none() => Some(10),
+                secs => secs
+            };
+            SnapshotConf {
+                path: path,
+                pre_secs: pre_secs,
+                pre_bytes: pre_bytes,
+                post_secs: num(post_secs_opt).unwrap_or(10)
+            }
+        }),
+        triggers: TriggerConf {
+            new_mac: matches.opt_present(trigger_mac_flag),
+            route_rate: matches.opt_str(trigger_rate_opt).map(|s| {
+                match s.parse::<u64>() {
+                    Ok(v) => v,
+                    _ => panic!("trigger rate must be a number")
+                }
+            })
+        },
+        alerts: alerts,
+        mac_names: mac_names,
         promisc: matches.opt_present(promisc_flag),
-        monitor: matches.opt_present(monitor_flag)
+        monitor: matches.opt_present(monitor_flag),
+        datalinks: matches.opt_strs(datalink_opt).into_iter().map(|s| {
+            let (dev, typ) = match s.rfind('=') {
+                Some(i) => (&s[..i], &s[i+1..]),
+                None => panic!("datalink must be given as interface=type")
+            };
+            match cap::datalink_from_name(typ) {
+                Some(dlt) => (dev.to_owned(), dlt),
+                None => panic!("unknown link type: {}", typ)
+            }
+        }).collect(),
+        capture: capture
     };
 
-    let mut ctrl = D3capController::spawn(conf.clone()).unwrap();
+    let mut ctrl = match D3capController::spawn(conf.clone()) {
+        Ok(ctrl) => ctrl,
+        Err(e) => {
+            println!("Could not start capture: {}", e);
+            process::exit(1);
+        }
+    };
 
     // Only start the websocket server if the option is explicitly provided.
     if let Some(port) = conf.websocket {
         ctrl.start_websocket(port).unwrap();
     }
 
+    if conf.files.iter().any(|f| f == d3cap::STDIN) && !readline::use_tty() {
+        // Nowhere to read commands from, so run until the input runs out, or for as
+        // long as anyone might be looking at the websocket.
+        while conf.websocket.is_some() ||
+            ctrl.captures.list().iter().any(|c| c.state == CaptureState::Running) {
+            thread::sleep(Duration::from_secs(1));
+        }
+        return;
+    }
+
     cli::start_cli(ctrl).unwrap().join().unwrap();
 }
@@ -1,19 +1,24 @@
 use std::collections::hash_map::{self, HashMap};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::hash::{Hash};
+use std::sync::Arc;
 
 use time;
 
+/// Name of the interface or file a packet was captured from.
+pub type Iface = Arc<String>;
+
 #[derive(Debug)]
 pub struct PktMeta<T> {
     pub src: T,
     pub dst: T,
     pub size: u32,
-    pub tm: time::Timespec
+    pub tm: time::Timespec,
+    pub iface: Iface
 }
 impl<T> PktMeta<T> {
-    pub fn new(src: T, dst: T, size: u32) -> PktMeta<T> {
-        PktMeta { src: src, dst: dst, size: size, tm: time::get_time() }
+    pub fn new(src: T, dst: T, size: u32, tm: time::Timespec, iface: Iface) -> PktMeta<T> {
+        PktMeta { src: src, dst: dst, size: size, tm: tm, iface: iface }
     }
 }
 
@@ -185,6 +190,14 @@ impl<'a, T: Hash+Eq+Copy+Clone> ProtocolGraph<T> {
         self.routes.get(addr)
     }
 
+    /// Stats for every route that has seen traffic, one entry per sending direction.
+    pub fn routes(&self) -> Vec<RouteStats<T>> {
+        self.routes.iter()
+            .flat_map(|(a, astats)| astats.sent_iter().map(move |(b, _)| (a, b)))
+            .filter_map(|(a, b)| self.get_route_stats(a, b))
+            .collect()
+    }
+
     pub fn iter(&'a self) -> PGIter<'a, T> {
         PGIter { inner: self.routes.iter() }
     }
@@ -0,0 +1,335 @@
+//! The headers cards put in front of 802.11 frames to say how they were received.
+//! Radiotap is described in tap.rs; the older PPI, Prism and AVS headers are here,
+//! and all of them are boiled down to the radiotap fields d3cap uses.
+
+use std::mem::size_of;
+
+use pcap::pcap as cap;
+
+use decode::{self, DecodeErr, Header, Layer};
+use util::{Be32, Be64, Le16, Le32, Le64};
+use tap::{self, Antenna, AntennaNoise, AntennaSignal, Channel, ChannelFlags, Rate};
+
+/// What the radio header says about a frame.
+#[derive(Copy, Clone, Debug)]
+pub struct Radio {
+    pub rate: Option<Rate>,
+    pub channel: Channel,
+    pub antenna_signal: AntennaSignal,
+    pub antenna_noise: AntennaNoise,
+    pub antenna: Antenna
+}
+
+/// Which header comes before the 802.11 frame, going by link type.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RadioHeader {
+    None,
+    Radiotap,
+    Ppi,
+    Prism,
+    Avs
+}
+
+impl RadioHeader {
+    /// The length of the header at the start of `data`, and what it says about the
+    /// frame if it's in a form we understand.  An error if `data` is too short to
+    /// hold the header, or it doesn't wrap an 802.11 frame.
+    pub fn parse(self, data: &[u8]) -> Result<(usize, Option<Radio>), DecodeErr> {
+        match self {
+            RadioHeader::None => Ok((0, None)),
+            RadioHeader::Radiotap => radiotap(data),
+            RadioHeader::Ppi => ppi(data),
+            // Some drivers put AVS headers on packets they say are Prism.
+            RadioHeader::Prism if is_avs(data) => avs(data),
+            RadioHeader::Prism => prism(data),
+            RadioHeader::Avs => avs(data)
+        }
+    }
+}
+
+unsafe impl Header for PpiHeader {}
+unsafe impl Header for PpiFieldHeader {}
+unsafe impl Header for Ppi80211Common {}
+unsafe impl Header for PrismHeader {}
+unsafe impl Header for AvsHeader {}
+
+/// Channel numbers are what Prism and AVS headers give, rather than frequencies.
+fn channel(num: u32) -> Channel {
+    let (mhz, flags) = match num {
+        14 => (2484, ChannelFlags::GHZ_2),
+        n if n >= 1 && n <= 13 => (2407 + 5 * n, ChannelFlags::GHZ_2),
+        n if n >= 32 => (5000 + 5 * n, ChannelFlags::GHZ_5),
+        _ => (0, ChannelFlags::empty())
+    };
+    Channel { mhz: mhz as u16, flags: flags }
+}
+
+fn radiotap(data: &[u8]) -> Result<(usize, Option<Radio>), DecodeErr> {
+    let (hdr, fields, frame) = decode::radiotap(data)?;
+    let radio = if let Some(vals) = tap::CommonA::parse(hdr, fields) {
+        Some(Radio {
+            rate: Some(vals.rate),
+            channel: vals.channel.get(),
+            antenna_signal: vals.antenna_signal,
+            antenna_noise: vals.antenna_noise,
+            antenna: vals.antenna
+        })
+    } else if let Some(vals) = tap::CommonB::parse(hdr, fields) {
+        Some(Radio {
+            rate: None,
+            channel: vals.channel.get(),
+            antenna_signal: vals.antenna_signal,
+            antenna_noise: vals.antenna_noise,
+            antenna: vals.antenna
+        })
+    } else {
+        None // Unknown header
+    };
+    Ok((data.len() - frame.len(), radio))
+}
+
+// Per-Packet Information, from CACE/Riverbed.  Little-endian.
+#[derive(Copy, Clone, Debug)]
+#[repr(packed)]
+struct PpiHeader {
+    version: u8,
+    flags: u8,
+    len: Le16,
+    dlt: Le32
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(packed)]
+struct PpiFieldHeader {
+    typ: Le16,
+    len: Le16
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(packed)]
+struct Ppi80211Common {
+    tsf_timer: Le64,
+    flags: Le16,
+    rate: Le16,
+    channel_mhz: Le16,
+    channel_flags: Le16,
+    fhss_hopset: u8,
+    fhss_pattern: u8,
+    dbm_antsignal: i8,
+    dbm_antnoise: i8
+}
+
+const PPI_FLAG_ALIGNED: u8 = 0x01;
+const PPI_FIELD_80211_COMMON: u16 = 2;
+
+fn ppi(data: &[u8]) -> Result<(usize, Option<Radio>), DecodeErr> {
+    let (hdr, _) = decode::header::<PpiHeader>(data, Layer::Ppi)?;
+    let len = hdr.len.get() as usize;
+    let (all, _) = decode::split(data, len, size_of::<PpiHeader>(), Layer::Ppi)?;
+    if hdr.dlt.get() != cap::DLT_IEEE802_11 as u32 {
+        return Err(DecodeErr::Unsupported(Layer::Ppi));
+    }
+
+    let mut radio = None;
+    let mut fields = &all[size_of::<PpiHeader>()..];
+    while let Ok((field, rest)) = decode::header::<PpiFieldHeader>(fields, Layer::Ppi) {
+        let field_len = field.len.get() as usize;
+        let (body, rest) = decode::split(rest, field_len, 0, Layer::Ppi)?;
+        if field.typ.get() == PPI_FIELD_80211_COMMON {
+            let (common, _) = decode::header::<Ppi80211Common>(body, Layer::Ppi)?;
+            radio = Some(Radio {
+                rate: Some(Rate { in_500kbps: common.rate.get().min(255) as u8 }),
+                channel: Channel {
+                    mhz: common.channel_mhz.get(),
+                    flags: ChannelFlags::from_bits_truncate(common.channel_flags.get())
+                },
+                antenna_signal: AntennaSignal { dbm: common.dbm_antsignal },
+                antenna_noise: AntennaNoise { dbm: common.dbm_antnoise },
+                antenna: Antenna { idx: 0 }
+            });
+        }
+        fields = rest;
+        if hdr.flags & PPI_FLAG_ALIGNED != 0 {
+            let pos = len - fields.len();
+            fields = &fields[(((pos + 3) & !3) - pos).min(fields.len())..];
+        }
+    }
+    Ok((len, radio))
+}
+
+// The old linux-wlan-ng header.  Host-endian, which in practice means little.
+#[derive(Copy, Clone, Debug)]
+#[repr(packed)]
+struct PrismItem {
+    did: Le32,
+    status: Le16,
+    len: Le16,
+    data: Le32
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(packed)]
+struct PrismHeader {
+    msgcode: Le32,
+    msglen: Le32,
+    devname: [u8; 16],
+    hosttime: PrismItem,
+    mactime: PrismItem,
+    channel: PrismItem,
+    rssi: PrismItem,
+    sq: PrismItem,
+    signal: PrismItem,
+    noise: PrismItem,
+    rate: PrismItem,
+    istx: PrismItem,
+    frmlen: PrismItem
+}
+
+fn prism(data: &[u8]) -> Result<(usize, Option<Radio>), DecodeErr> {
+    let (hdr, _) = decode::header::<PrismHeader>(data, Layer::Prism)?;
+    Ok((size_of::<PrismHeader>(), Some(Radio {
+        rate: Some(Rate { in_500kbps: hdr.rate.data.get() as u8 }),
+        channel: channel(hdr.channel.data.get()),
+        antenna_signal: AntennaSignal { dbm: hdr.signal.data.get() as i8 },
+        antenna_noise: AntennaNoise { dbm: hdr.noise.data.get() as i8 },
+        antenna: Antenna { idx: 0 }
+    })))
+}
+
+// AVS capture header, version 1.  Big-endian.
+#[derive(Copy, Clone, Debug)]
+#[repr(packed)]
+struct AvsHeader {
+    version: Be32,
+    len: Be32,
+    mactime: Be64,
+    hosttime: Be64,
+    phytype: Be32,
+    channel: Be32,
+    datarate: Be32,
+    antenna: Be32,
+    priority: Be32,
+    ssi_type: Be32,
+    ssi_signal: Be32,
+    ssi_noise: Be32,
+    preamble: Be32,
+    encoding: Be32
+}
+
+const AVS_MAGIC: u32 = 0x8021_1001;
+
+// What the AVS signal and noise fields measure.  Only dBm means the same thing from
+// one card to the next; normalized RSSI runs 0-1000 and raw RSSI is up to the driver.
+const AVS_SSI_DBM: u32 = 2;
+
+fn is_avs(data: &[u8]) -> bool {
+    decode::header::<AvsHeader>(data, Layer::Avs)
+        .map_or(false, |(hdr, _)| hdr.version.get() == AVS_MAGIC)
+}
+
+fn avs(data: &[u8]) -> Result<(usize, Option<Radio>), DecodeErr> {
+    let (hdr, _) = decode::header::<AvsHeader>(data, Layer::Avs)?;
+    let len = hdr.len.get() as usize;
+    decode::split(data, len, size_of::<AvsHeader>(), Layer::Avs)?;
+    // Any other signal reading can't be turned into dBm, so there's nothing to report.
+    if hdr.ssi_type.get() != AVS_SSI_DBM {
+        return Ok((len, None));
+    }
+    Ok((len, Some(Radio {
+        // AVS rates are in 100kbps.
+        rate: Some(Rate { in_500kbps: (hdr.datarate.get() / 5).min(255) as u8 }),
+        channel: channel(hdr.channel.get()),
+        antenna_signal: AntennaSignal { dbm: hdr.ssi_signal.get() as i8 },
+        antenna_noise: AntennaNoise { dbm: hdr.ssi_noise.get() as i8 },
+        antenna: Antenna { idx: hdr.antenna.get() as u8 }
+    })))
+}
+
+#[test]
+fn test_avs_behind_prism() {
+    let mut hdr = vec![0x80, 0x21, 0x10, 0x01, 0x00, 0x00, 0x00, 0x40];
+    hdr.extend(&[0; 16]); // mactime, hosttime
+    hdr.extend(&[0, 0, 0, 2, 0, 0, 0, 36, 0, 0, 0x02, 0x1c, 0, 0, 0, 1]);
+    hdr.extend(&[0, 0, 0, 0, 0, 0, 0, 2, 0xff, 0xff, 0xff, 0xc4, 0xff, 0xff, 0xff, 0x9c]);
+    hdr.extend(&[0; 8]); // preamble, encoding
+    hdr.extend(&[0x80, 0x00]);
+
+    let (len, radio) = RadioHeader::Prism.parse(&hdr).unwrap();
+    assert_eq!(len, 64);
+    let radio = radio.unwrap();
+    assert_eq!(radio.rate.unwrap().in_500kbps, 108);
+    assert_eq!(radio.channel.mhz, 5180);
+    assert!(radio.channel.flags.contains(ChannelFlags::GHZ_5));
+    assert_eq!(radio.antenna_signal.dbm, -60);
+    assert_eq!(radio.antenna_noise.dbm, -100);
+    assert_eq!(radio.antenna.idx, 1);
+    assert!(RadioHeader::Avs.parse(&hdr[..63]).is_err());
+}
+
+#[test]
+fn test_avs_ssi_type() {
+    let mut hdr = vec![0x80, 0x21, 0x10, 0x01, 0x00, 0x00, 0x00, 0x40];
+    hdr.extend(&[0; 16]);
+    hdr.extend(&[0, 0, 0, 2, 0, 0, 0, 6, 0, 0, 0, 110, 0, 0, 0, 0]);
+    // Normalized RSSI, which isn't dBm.
+    hdr.extend(&[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0x02, 0x58, 0, 0, 0, 0x64]);
+    hdr.extend(&[0; 8]);
+
+    let (len, radio) = RadioHeader::Avs.parse(&hdr).unwrap();
+    assert_eq!(len, 64);
+    assert!(radio.is_none());
+    hdr[47] = 3; // raw RSSI
+    assert!(RadioHeader::Avs.parse(&hdr).unwrap().1.is_none());
+    hdr[47] = 2;
+    assert!(RadioHeader::Avs.parse(&hdr).unwrap().1.is_some());
+}
+
+#[test]
+fn test_prism() {
+    let item = |data: u32| {
+        let mut item = vec![0; 8];
+        item.extend(&[data as u8, (data >> 8) as u8, (data >> 16) as u8, (data >> 24) as u8]);
+        item
+    };
+    let mut hdr = vec![0x41, 0, 0, 0, 0x90, 0, 0, 0];
+    hdr.extend(b"wlan0\0\0\0\0\0\0\0\0\0\0\0");
+    for &data in &[0, 0, 6, 40, 0, -50i32 as u32, -90i32 as u32, 22, 0, 100] {
+        hdr.extend(item(data));
+    }
+    hdr.extend(&[0x80, 0x00]);
+
+    let (len, radio) = RadioHeader::Prism.parse(&hdr).unwrap();
+    assert_eq!(len, 144);
+    let radio = radio.unwrap();
+    assert_eq!(radio.rate.unwrap().in_500kbps, 22);
+    assert_eq!(radio.channel.mhz, 2437);
+    assert!(radio.channel.flags.contains(ChannelFlags::GHZ_2));
+    assert_eq!(radio.antenna_signal.dbm, -50);
+    assert_eq!(radio.antenna_noise.dbm, -90);
+    assert_eq!(RadioHeader::Prism.parse(&hdr[..143]).err(),
+               Some(DecodeErr::Truncated(Layer::Prism)));
+}
+
+#[test]
+fn test_ppi() {
+    let mut hdr = vec![0x00, 0x00, 0x20, 0x00, 0x69, 0x00, 0x00, 0x00];
+    hdr.extend(&[0x02, 0x00, 0x14, 0x00]); // 802.11-Common, 20 bytes
+    hdr.extend(&[0; 10]); // TSF timer, flags
+    hdr.extend(&[0x6c, 0x00, 0x85, 0x09, 0x80, 0x00, 0x00, 0x00, 0xc9, 0xa1]);
+    hdr.extend(&[0x80, 0x00]);
+
+    let (len, radio) = RadioHeader::Ppi.parse(&hdr).unwrap();
+    assert_eq!(len, 32);
+    let radio = radio.unwrap();
+    assert_eq!(radio.rate.unwrap().in_500kbps, 108);
+    assert_eq!(radio.channel.mhz, 2437);
+    assert!(radio.channel.flags.contains(ChannelFlags::GHZ_2));
+    assert_eq!(radio.antenna_signal.dbm, -55);
+    assert_eq!(radio.antenna_noise.dbm, -95);
+
+    // Only 802.11 frames are wrapped in PPI here.
+    hdr[4] = 0x01;
+    assert_eq!(RadioHeader::Ppi.parse(&hdr).err(), Some(DecodeErr::Unsupported(Layer::Ppi)));
+    hdr[4] = 0x69;
+    assert_eq!(RadioHeader::Ppi.parse(&hdr[..31]).err(), Some(DecodeErr::Truncated(Layer::Ppi)));
+}
@@ -9,6 +9,7 @@ mod raw {
     extern {
         pub fn readline(p: *const libc::c_char) -> *const libc::c_char;
         pub fn add_history(p: *const libc::c_char);
+        pub static mut rl_instream: *mut libc::FILE;
     }
 }
 
@@ -42,3 +43,17 @@ pub fn readline(prompt: &str) -> Result<String, ReadlineError> {
         }
     }
 }
+
+/// Read commands from the terminal instead of standard input, for when standard
+/// input is carrying something else.  Returns false if there's no terminal.
+pub fn use_tty() -> bool {
+    unsafe {
+        let tty = libc::fopen(b"/dev/tty\0".as_ptr() as *const libc::c_char,
+                              b"r\0".as_ptr() as *const libc::c_char);
+        if tty.is_null() {
+            return false;
+        }
+        raw::rl_instream = tty;
+        true
+    }
+}
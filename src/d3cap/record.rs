@@ -0,0 +1,554 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+use time;
+
+use pcap::pcap as cap;
+use pcap::savefile::{Interface, Packet, PcapngWriter};
+
+/// When a recording moves on to a new file.  With neither limit set everything goes
+/// into the one file.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Rotation {
+    pub max_bytes: Option<u64>,
+    pub max_secs: Option<u64>,
+    /// How many files to hang on to; the oldest are deleted as new ones are started.
+    pub keep: Option<usize>
+}
+
+impl Rotation {
+    pub fn enabled(&self) -> bool {
+        self.max_bytes.is_some() || self.max_secs.is_some()
+    }
+}
+
+/// Where to record to, which packets, and how to split the output up.
+#[derive(Clone, Debug)]
+pub struct RecordSpec {
+    pub path: String,
+    pub filter: Option<String>,
+    pub rotation: Rotation
+}
+
+impl RecordSpec {
+    /// The file a capture records to.  When several captures record at once each
+    /// gets its own, with the capture's source worked into the name, so recording
+    /// eth0 and wlan0 to out.pcap writes out.eth0.pcap and out.wlan0.pcap.
+    pub fn path_for(&self, iface: &str, shared: bool) -> String {
+        if shared {
+            iface_path(&self.path, iface)
+        } else {
+            self.path.clone()
+        }
+    }
+}
+
+// `path` with the name of the interface or file a capture reads from worked in.
+fn iface_path(path: &str, iface: &str) -> String {
+    let tag: String = Path::new(iface).file_name()
+        .map_or_else(|| iface.into(), |n| n.to_string_lossy())
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    tagged_path(path, &tag)
+}
+
+// The parts of a file name that go before and after a tag: "out." and ".pcap".
+fn name_parts(path: &Path) -> (String, String) {
+    let stem = path.file_stem().map_or_else(|| "".into(), |s| s.to_string_lossy());
+    let ext = path.extension()
+        .map_or_else(|| "".to_owned(), |e| format!(".{}", e.to_string_lossy()));
+    (format!("{}.", stem), ext)
+}
+
+fn tagged_path(path: &str, tag: &str) -> String {
+    let path = Path::new(path);
+    let (prefix, suffix) = name_parts(path);
+    path.with_file_name(format!("{}{}{}", prefix, tag, suffix)).to_string_lossy().into_owned()
+}
+
+/// A new timestamped file name for a rotating recording to `base`.
+fn next_file(base: &str) -> String {
+    let stamp = time::strftime("%Y%m%d-%H%M%S", &time::now_utc()).unwrap();
+    let mut path = tagged_path(base, &stamp);
+    let mut n = 1;
+    while Path::new(&path).exists() {
+        path = tagged_path(base, &format!("{}-{}", stamp, n));
+        n += 1;
+    }
+    path
+}
+
+/// Timestamped files already on disk from rotating recordings to `base`, oldest
+/// first, so that restarting a recording still keeps only the last N.
+fn rotated_files(base: &str) -> Vec<String> {
+    let path = Path::new(base);
+    let (prefix, suffix) = name_parts(path);
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new(".")
+    };
+    let mut out: Vec<_> = fs::read_dir(dir).into_iter()
+        .flat_map(|entries| entries.filter_map(Result::ok))
+        .filter_map(|e| e.file_name().into_string().ok())
+        .filter_map(|name| {
+            if name.len() <= prefix.len() + suffix.len() ||
+                !name.starts_with(&prefix) || !name.ends_with(&suffix) {
+                return None;
+            }
+            let key = rotation_key(&name[prefix.len()..name.len() - suffix.len()])?;
+            Some((key, path.with_file_name(&name).to_string_lossy().into_owned()))
+        })
+        .collect();
+    out.sort();
+    out.into_iter().map(|(_, path)| path).collect()
+}
+
+/// The timestamp and collision count that `next_file` tagged a file with, in the
+/// order the files were started.
+fn rotation_key(tag: &str) -> Option<(String, u32)> {
+    if tag.len() < 15 || !tag.is_char_boundary(15) {
+        return None;
+    }
+    let (stamp, rest) = tag.split_at(15);
+    let valid = stamp.chars().enumerate()
+        .all(|(i, c)| if i == 8 { c == '-' } else { c.is_digit(10) });
+    if !valid {
+        return None;
+    }
+    let n = if rest.is_empty() {
+        0
+    } else if rest.starts_with('-') {
+        rest[1..].parse().ok()?
+    } else {
+        return None;
+    };
+    Some((stamp.to_owned(), n))
+}
+
+/// Files named *.pcapng are written as pcapng, everything else as plain pcap.
+fn is_pcapng(path: &str) -> bool {
+    Path::new(path).extension()
+        .map_or(false, |e| e.to_string_lossy().eq_ignore_ascii_case("pcapng"))
+}
+
+/// Where a recording's packets go: a pcap file written by libpcap, or a pcapng file
+/// that also says which capture the packets came from, what filter it used, and
+/// carries a comment on each packet.  A pcap file has one link type, so it can't
+/// take packets of any other.
+enum Output {
+    Pcap(cap::PcapDumper),
+    Pcapng(PcapngOutput)
+}
+
+struct PcapngOutput {
+    writer: PcapngWriter<BufWriter<File>>,
+    iface: String,
+    filter: Option<String>,
+    snaplen: u32,
+    // pcapng interfaces have one link type, so a capture gets one per link type.
+    ids: HashMap<cap::DataLinkType, u32>
+}
+
+/// What a capture's packets look like, for starting files to write them to.
+#[derive(Copy, Clone, Debug)]
+struct Source {
+    // None when the packets have different link types.
+    dlt: Option<cap::DataLinkType>,
+    snaplen: u32,
+    prec: cap::TstampPrecision
+}
+
+impl Source {
+    fn of(sess: &cap::PcapSession) -> Source {
+        Source { dlt: sess.datalink(), snaplen: sess.snaplen(), prec: sess.tstamp_precision() }
+    }
+}
+
+fn dlt_name(dlt: cap::DataLinkType) -> String {
+    cap::datalink_name(dlt).unwrap_or_else(|| dlt.to_string())
+}
+
+impl Output {
+    fn open(src: &Source,
+            path: &str,
+            append: bool,
+            iface: &str,
+            filter: &Option<String>) -> Result<Output, cap::PcapError> {
+        if !is_pcapng(path) {
+            let dlt = src.dlt.ok_or_else(|| {
+                cap::PcapError::Dump(format!("{}: the capture has packets of more than one \
+                                              link type, record to a .pcapng file", path))
+            })?;
+            let dumper = if append {
+                cap::PcapDumper::append(path, dlt, src.snaplen, src.prec)?
+            } else {
+                cap::PcapDumper::new(path, dlt, src.snaplen, src.prec)?
+            };
+            return Ok(Output::Pcap(dumper));
+        }
+        Ok(Output::Pcapng(PcapngOutput {
+            writer: PcapngWriter::create(path, append, "d3cap")?,
+            iface: iface.to_owned(),
+            filter: filter.clone(),
+            snaplen: src.snaplen,
+            ids: HashMap::new()
+        }))
+    }
+
+    /// Whether packet comments get written, rather than dropped.
+    fn takes_comments(&self) -> bool {
+        match *self {
+            Output::Pcap(_) => false,
+            Output::Pcapng(_) => true
+        }
+    }
+
+    fn write(&mut self, pkt: &cap::PcapData, comment: Option<String>)
+             -> Result<(), cap::PcapError> {
+        let out = match *self {
+            Output::Pcap(ref mut dumper) => {
+                if pkt.datalink() != dumper.datalink() {
+                    return Err(cap::PcapError::Dump(format!(
+                        "a {} packet can't go in a {} pcap file, record to a .pcapng file",
+                        dlt_name(pkt.datalink()), dlt_name(dumper.datalink()))));
+                }
+                dumper.dump(pkt);
+                return Ok(());
+            }
+            Output::Pcapng(ref mut out) => out
+        };
+        let dlt = pkt.datalink();
+        let known = out.ids.get(&dlt).cloned();
+        let id = match known {
+            Some(id) => id,
+            None => {
+                let mut desc = Interface::new(cap::dlt_to_linktype(dlt), out.snaplen);
+                desc.name = Some(out.iface.clone());
+                desc.filter = out.filter.clone();
+                let id = out.writer.add_interface(&desc)?;
+                out.ids.insert(dlt, id);
+                id
+            }
+        };
+        out.writer.write_packet(&Packet::from_data(id, pkt, comment))
+    }
+
+    fn size(&self) -> Result<u64, cap::PcapError> {
+        match *self {
+            Output::Pcap(ref dumper) => dumper.ftell(),
+            Output::Pcapng(ref out) => Ok(out.writer.written())
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), cap::PcapError> {
+        match *self {
+            Output::Pcap(ref mut dumper) => dumper.flush(),
+            Output::Pcapng(ref mut out) => out.writer.flush()
+        }
+    }
+}
+
+/// Dumps the packets a capture reads to a savefile, skipping any that don't match
+/// the filter, and moving on to a new file whenever the rotation limits are hit.
+pub struct Recorder {
+    base: String,
+    path: String,
+    iface: String,
+    // The capture's filter and the recording's together, to note in pcapng files.
+    filter_desc: Option<String>,
+    src: Source,
+    out: Output,
+    // Compiled for each link type, since a savefile can have several.
+    filter: Option<cap::BpfFilter>,
+    rotation: Rotation,
+    files: VecDeque<String>,
+    opened: f64,
+    in_file: u64,
+    written: u64,
+    // Whether we've said that a pcap file is losing the comments.
+    comments_dropped: bool
+}
+
+impl Recorder {
+    /// Start recording to `path`.  A rotating recording always starts a new file;
+    /// otherwise `append` picks between adding to `path` and starting it over.
+    /// `iface` and `capture_filter` describe the capture, for pcapng files.
+    pub fn start(sess: &cap::PcapSession,
+                 path: &str,
+                 spec: &RecordSpec,
+                 append: bool,
+                 iface: &str,
+                 capture_filter: Option<&str>) -> Result<Recorder, cap::PcapError> {
+        let src = Source::of(sess);
+        let filter = match spec.filter {
+            Some(ref expr) => {
+                let mut filter = cap::BpfFilter::new(expr, true, src.snaplen);
+                // Catch a bad expression now when we know what it'll be used on.
+                if let Some(dlt) = src.dlt {
+                    filter.check(dlt)?;
+                }
+                Some(filter)
+            }
+            None => None
+        };
+        let filter_desc = match (capture_filter, spec.filter.as_ref()) {
+            (Some(a), Some(b)) => Some(format!("({}) and ({})", a, b)),
+            (a, b) => a.map(String::from).or_else(|| b.cloned())
+        };
+        let (file, out, files) = if spec.rotation.enabled() {
+            let mut files: VecDeque<_> = rotated_files(path).into();
+            let file = next_file(path);
+            let out = Output::open(&src, &file, false, iface, &filter_desc)?;
+            files.push_back(file.clone());
+            (file, out, files)
+        } else {
+            let out = Output::open(&src, path, append, iface, &filter_desc)?;
+            (path.to_owned(), out, VecDeque::new())
+        };
+
+        let mut rec = Recorder {
+            base: path.to_owned(),
+            path: file,
+            iface: iface.to_owned(),
+            filter_desc: filter_desc,
+            src: src,
+            out: out,
+            filter: filter,
+            rotation: spec.rotation,
+            files: files,
+            opened: time::precise_time_s(),
+            in_file: 0,
+            written: 0,
+            comments_dropped: false
+        };
+        rec.prune();
+        Ok(rec)
+    }
+
+    /// Write `pkt` if it passes the filter.  `comment` is only called for packets
+    /// that get written.  Pcap files can't hold comments, so the first one there's
+    /// nowhere to put gets a warning.
+    pub fn record<F>(&mut self, pkt: &cap::PcapData, comment: F) -> Result<(), cap::PcapError>
+        where F: FnOnce() -> Option<String> {
+        if let Some(ref mut filter) = self.filter {
+            if !filter.matches(pkt) {
+                return Ok(());
+            }
+        }
+        if self.rotation_due()? {
+            self.rotate()?;
+        }
+        let comment = if self.out.takes_comments() {
+            comment()
+        } else {
+            if !self.comments_dropped && comment().is_some() {
+                println!("{}: pcap files can't hold d3cap's packet comments, record to a \
+                          .pcapng file to keep them", self.path);
+                self.comments_dropped = true;
+            }
+            None
+        };
+        self.out.write(pkt, comment)?;
+        self.in_file += 1;
+        self.written += 1;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), cap::PcapError> {
+        self.out.flush()
+    }
+
+    fn rotation_due(&self) -> Result<bool, cap::PcapError> {
+        if self.in_file == 0 {
+            return Ok(false);
+        }
+        if let Some(secs) = self.rotation.max_secs {
+            if time::precise_time_s() - self.opened >= secs as f64 {
+                return Ok(true);
+            }
+        }
+        if let Some(bytes) = self.rotation.max_bytes {
+            if self.out.size()? >= bytes {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn rotate(&mut self) -> Result<(), cap::PcapError> {
+        let file = next_file(&self.base);
+        // Replacing the output closes the old file.
+        self.out = Output::open(&self.src, &file, false, &self.iface, &self.filter_desc)?;
+        self.files.push_back(file.clone());
+        self.path = file;
+        self.opened = time::precise_time_s();
+        self.in_file = 0;
+        self.prune();
+        Ok(())
+    }
+
+    fn prune(&mut self) {
+        if let Some(keep) = self.rotation.keep {
+            while self.files.len() > keep.max(1) {
+                if let Some(old) = self.files.pop_front() {
+                    if let Err(e) = fs::remove_file(&old) {
+                        println!("Could not remove {}: {}", old, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The file currently being written.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+}
+
+/// How much traffic to hold on to for snapshots, and where to write them.
+#[derive(Clone, Debug)]
+pub struct SnapshotConf {
+    /// Snapshot files are named after this, with the capture's source and a
+    /// timestamp worked in.
+    pub path: String,
+    pub pre_secs: Option<u64>,
+    pub pre_bytes: Option<u64>,
+    pub post_secs: u64
+}
+
+struct Snapshot {
+    path: String,
+    out: Output,
+    until: Option<f64>,
+    written: u64
+}
+
+/// Keeps the last few seconds or megabytes of a capture's packets in memory, so
+/// that when something interesting happens they can be written out along with
+/// whatever comes in for a while afterwards.  Time is measured by packet
+/// timestamps, so replays behave the same as live captures.
+pub struct PreEventBuffer {
+    conf: SnapshotConf,
+    iface: String,
+    base: String,
+    src: Source,
+    pkts: VecDeque<cap::PcapPacket>,
+    bytes: u64,
+    last_ts: Option<f64>,
+    snapshot: Option<Snapshot>
+}
+
+impl PreEventBuffer {
+    pub fn new(conf: &SnapshotConf, sess: &cap::PcapSession, iface: &str) -> PreEventBuffer {
+        PreEventBuffer {
+            conf: conf.clone(),
+            iface: iface.to_owned(),
+            base: iface_path(&conf.path, iface),
+            src: Source::of(sess),
+            pkts: VecDeque::new(),
+            bytes: 0,
+            last_ts: None,
+            snapshot: None
+        }
+    }
+
+    pub fn push(&mut self, pkt: &cap::PcapData) {
+        let ts = pkt.ts().to_f64();
+        self.last_ts = Some(ts);
+
+        let post = self.conf.post_secs as f64;
+        let done = match self.snapshot {
+            Some(ref mut snap) => {
+                let until = *snap.until.get_or_insert(ts + post);
+                if ts >= until {
+                    Ok(true)
+                } else {
+                    let res = snap.out.write(pkt, pkt.comment().map(String::from));
+                    res.map(|()| {
+                        snap.written += 1;
+                        false
+                    })
+                }
+            }
+            None => Ok(false)
+        };
+        match done {
+            Ok(false) => {}
+            Ok(true) => {
+                if let Some(snap) = self.snapshot.take() {
+                    println!("snapshot complete: {} packets to {}", snap.written, snap.path);
+                }
+            }
+            Err(e) => {
+                println!("Snapshot stopped: {}", e);
+                self.snapshot = None;
+            }
+        }
+
+        self.bytes += u64::from(pkt.caplen());
+        self.pkts.push_back(pkt.to_packet());
+        while let Some(oldest) = self.pkts.front().map(|p| p.ts().to_f64()) {
+            let too_old = self.conf.pre_secs.map_or(false, |secs| ts - oldest > secs as f64);
+            let too_big = self.conf.pre_bytes.map_or(false, |bytes| self.bytes > bytes);
+            if !too_old && !too_big {
+                break;
+            }
+            if let Some(p) = self.pkts.pop_front() {
+                self.bytes -= u64::from(p.caplen());
+            }
+        }
+    }
+
+    /// Start a snapshot: everything buffered now, then packets as they come in for
+    /// the next `post_secs`.  If a snapshot is already being written it's kept open
+    /// that much longer instead.  Returns the new file, if one was started.
+    pub fn trigger(&mut self) -> Result<Option<String>, cap::PcapError> {
+        let until = self.last_ts.map(|ts| ts + self.conf.post_secs as f64);
+        if let Some(ref mut snap) = self.snapshot {
+            snap.until = until;
+            return Ok(None);
+        }
+
+        let path = next_file(&self.base);
+        let mut out = Output::open(&self.src, &path, false, &self.iface, &None)?;
+        for p in &self.pkts {
+            p.with_data(|d| out.write(d, d.comment().map(String::from)))?;
+        }
+        self.snapshot = Some(Snapshot {
+            path: path.clone(),
+            out: out,
+            until: until,
+            written: self.pkts.len() as u64
+        });
+        Ok(Some(path))
+    }
+
+    pub fn flush(&mut self) {
+        let failed = match self.snapshot {
+            Some(ref mut snap) => snap.out.flush().err(),
+            None => None
+        };
+        if let Some(e) = failed {
+            println!("Snapshot stopped: {}", e);
+            self.snapshot = None;
+        }
+    }
+}
+
+#[test]
+fn test_rotation_key() {
+    let mut tags = vec!["20240102-030405-10", "20240102-030405-2", "20240102-030406",
+                        "20240102-030405", "20240102-030405-1"];
+    tags.sort_by_key(|t| rotation_key(t));
+    assert_eq!(tags, vec!["20240102-030405", "20240102-030405-1", "20240102-030405-2",
+                          "20240102-030405-10", "20240102-030406"]);
+    assert_eq!(rotation_key("2024-0102-030405"), None);
+    assert_eq!(rotation_key("20240102-030405-"), None);
+}
@@ -0,0 +1,127 @@
+use time;
+
+/// How fast to play back a capture file.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Speed {
+    /// As fast as packets can be read.
+    Max,
+    /// A multiple of the original capture rate.
+    Scaled(f64)
+}
+
+impl Speed {
+    /// Parse a multiplier like "1" or "2.5", or "max".
+    pub fn parse(s: &str) -> Option<Speed> {
+        match s {
+            "max" => Some(Speed::Max),
+            _ => match s.parse::<f64>() {
+                Ok(x) if x > 0.0 => Some(Speed::Scaled(x)),
+                _ => None
+            }
+        }
+    }
+}
+
+/// What to do with the packet that was just read from a capture file.
+pub enum Pace {
+    /// Hand it to the parser now.
+    Deliver,
+    /// It's not due yet, ask again in this many seconds.
+    Wait(f64),
+    /// Playback is paused, ask again once it's resumed.
+    Paused,
+    /// We're seeking past it.
+    Skip
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ReplayStatus {
+    /// Seconds from the start of the capture to the last packet delivered.
+    pub position: f64,
+    pub speed: Speed,
+    pub paused: bool
+}
+
+/// Keeps track of where playback of a capture file is and when each packet
+/// is due, based on the timestamps in the file.
+pub struct Replay {
+    speed: Speed,
+    paused: bool,
+    first_ts: Option<f64>,
+    seek_to: Option<f64>,
+    // Capture timestamp and wall clock time that pacing is measured from.
+    base: Option<(f64, f64)>,
+    pos: f64
+}
+
+impl Replay {
+    pub fn new(speed: Speed) -> Replay {
+        Replay {
+            speed: speed,
+            paused: false,
+            first_ts: None,
+            seek_to: None,
+            base: None,
+            pos: 0.0
+        }
+    }
+
+    pub fn pace(&mut self, ts: f64) -> Pace {
+        let first = *self.first_ts.get_or_insert(ts);
+        let offset = ts - first;
+
+        if let Some(target) = self.seek_to {
+            if offset < target {
+                return Pace::Skip;
+            }
+            self.seek_to = None;
+            self.base = None;
+        }
+
+        if self.paused {
+            return Pace::Paused;
+        }
+
+        if let Speed::Scaled(speed) = self.speed {
+            let now = time::precise_time_s();
+            let (base_ts, base_wall) = *self.base.get_or_insert((ts, now));
+            let due = base_wall + (ts - base_ts) / speed;
+            if due > now {
+                return Pace::Wait(due - now);
+            }
+        }
+
+        self.pos = offset;
+        Pace::Deliver
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.base = None;
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.base = None;
+    }
+
+    /// Seek forward to `offset` seconds from the start of the capture.  Packets
+    /// before the target are skipped as they're read.  Going backwards would count
+    /// packets in the graphs twice, so that's refused, returning false.
+    pub fn seek(&mut self, offset: f64) -> bool {
+        if offset < self.pos {
+            return false;
+        }
+        self.seek_to = Some(offset);
+        self.base = None;
+        true
+    }
+
+    pub fn status(&self) -> ReplayStatus {
+        ReplayStatus { position: self.pos, speed: self.speed, paused: self.paused }
+    }
+}
@@ -0,0 +1,60 @@
+//! Linux "cooked" capture headers, which libpcap uses for the "any" device and for
+//! devices like tun and ppp that don't have a link-layer header of their own.
+//! See http://www.tcpdump.org/linktypes/LINKTYPE_LINUX_SLL.html and
+//! http://www.tcpdump.org/linktypes/LINKTYPE_LINUX_SLL2.html.
+
+use ether::MacAddr;
+use util::{Be16, Be32};
+
+#[derive(Copy, Clone, Debug)]
+#[repr(packed)]
+pub struct SllHeader {
+    pub pkttype: Be16,
+    pub hatype: Be16,
+    pub halen: Be16,
+    pub addr: [u8; 8],
+    pub protocol: Be16
+}
+
+impl SllHeader {
+    pub fn sender(&self) -> Option<MacAddr> {
+        sender(self.hatype.get(), self.halen.get(), &self.addr)
+    }
+}
+
+/// The second version puts the protocol first and adds the index of the interface
+/// the packet was seen on.
+#[derive(Copy, Clone, Debug)]
+#[repr(packed)]
+pub struct Sll2Header {
+    pub protocol: Be16,
+    pub reserved: Be16,
+    pub if_index: Be32,
+    pub hatype: Be16,
+    pub pkttype: u8,
+    pub halen: u8,
+    pub addr: [u8; 8]
+}
+
+impl Sll2Header {
+    pub fn if_index(&self) -> u32 {
+        self.if_index.get()
+    }
+
+    pub fn sender(&self) -> Option<MacAddr> {
+        sender(self.hatype.get(), u16::from(self.halen), &self.addr)
+    }
+}
+
+const ARPHRD_ETHER: u16 = 1;
+const ARPHRD_IEEE80211: u16 = 801;
+
+/// The sender's link-layer address, when it's a MAC address.
+fn sender(hatype: u16, halen: u16, addr: &[u8; 8]) -> Option<MacAddr> {
+    match hatype {
+        ARPHRD_ETHER | ARPHRD_IEEE80211 if halen == 6 => {
+            Some(MacAddr::from_bytes(&addr[..6]))
+        }
+        _ => None
+    }
+}
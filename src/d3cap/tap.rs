@@ -1,8 +1,7 @@
 #![allow(dead_code)]
 
-use std::mem::size_of;
-
-use util::skip_cast;
+use decode::{self, Layer};
+use util::{Le16, Le32, Le64};
 
 //For possible reference:
 //https://github.com/simsong/tcpflow/blob/master/src/wifipcap/ieee802_11_radio.h
@@ -12,8 +11,8 @@ use util::skip_cast;
 pub struct RadiotapHeader {
     pub it_version: u8, // 8 -> 1
     pub it_pad: u8, // 8 -> 1
-    pub it_len: u16, // 16 -> 2
-    pub it_present: ItPresent // 32 -> 4
+    pub it_len: Le16, // 16 -> 2
+    pub it_present: Le32 // 32 -> 4
 }
 //size_of should == 8
 
@@ -63,7 +62,7 @@ bitflags! {
 #[derive(Copy,Clone,Debug)]
 #[repr(packed)]
 pub struct Tsft {
-    pub timer_micros: u64
+    pub timer_micros: Le64
 }
 
 bitflags! {
@@ -100,12 +99,28 @@ bitflags! {
 
 
 #[derive(Copy,Clone,Debug)]
-#[repr(packed)]
 pub struct Channel {
     pub mhz: u16,
     pub flags: ChannelFlags
 }
 
+/// The channel as radiotap lays it out.
+#[derive(Copy,Clone,Debug)]
+#[repr(packed)]
+pub struct ChannelField {
+    pub mhz: Le16,
+    pub flags: Le16
+}
+
+impl ChannelField {
+    pub fn get(&self) -> Channel {
+        Channel {
+            mhz: self.mhz.get(),
+            flags: ChannelFlags::from_bits_truncate(self.flags.get())
+        }
+    }
+}
+
 #[derive(Copy,Clone,Debug)]
 #[repr(packed)]
 pub struct AntennaSignal {
@@ -140,7 +155,7 @@ pub struct CommonA {
     pub tsft: Tsft,  // 8
     pub flags: Flags, // 1
     pub rate: Rate, // 1
-    pub channel: Channel, // 2 + 2 = 4
+    pub channel: ChannelField, // 2 + 2 = 4
     pub antenna_signal: AntennaSignal, // 1
     pub antenna_noise: AntennaNoise, // 1
     pub antenna: Antenna // 1
@@ -148,12 +163,10 @@ pub struct CommonA {
 // sizeof should be 17.
 
 impl CommonA {
-    pub fn parse(hdr: &RadiotapHeader) -> Option<&CommonA> {
-        let sz = size_of::<RadiotapHeader>() + size_of::<CommonA>();
-        if hdr.it_present == ItPresent::COMMON_A
-        && hdr.it_len as usize >= sz {
-            let out: &CommonA = unsafe { skip_cast(hdr) };
-            Some(out)
+    /// `fields` are the bytes between the header and its `it_len`.
+    pub fn parse<'a>(hdr: &RadiotapHeader, fields: &'a [u8]) -> Option<&'a CommonA> {
+        if hdr.it_present.get() == ItPresent::COMMON_A.bits() {
+            decode::header(fields, Layer::Radiotap).ok().map(|(out, _)| out)
         } else {
             None
         }
@@ -164,7 +177,7 @@ impl CommonA {
 pub struct CommonB {
     pub tsft: Tsft,
     pub flags: Flags,
-    pub channel: Channel,
+    pub channel: ChannelField,
     pub antenna_signal: AntennaSignal,
     pub antenna_noise: AntennaNoise,
     pub antenna: Antenna,
@@ -172,12 +185,10 @@ pub struct CommonB {
 }
 
 impl CommonB {
-    pub fn parse(hdr: &RadiotapHeader) -> Option<&CommonB> {
-        let sz = size_of::<RadiotapHeader>() + size_of::<CommonB>();
-        if hdr.it_present == ItPresent::COMMON_B
-        && hdr.it_len as usize >= sz {
-            let out: &CommonB = unsafe { skip_cast(hdr) };
-            Some(out)
+    /// `fields` are the bytes between the header and its `it_len`.
+    pub fn parse<'a>(hdr: &RadiotapHeader, fields: &'a [u8]) -> Option<&'a CommonB> {
+        if hdr.it_present.get() == ItPresent::COMMON_B.bits() {
+            decode::header(fields, Layer::Radiotap).ok().map(|(out, _)| out)
         } else {
             None
         }
@@ -189,6 +200,6 @@ impl CommonB {
 
 impl RadiotapHeader {
     pub fn has_field(&self, fld: ItPresent) -> bool {
-        self.it_present.contains(fld)
+        ItPresent::from_bits_truncate(self.it_present.get()).contains(fld)
     }
 }
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::hash::Hash;
+
+use ether::MacAddr;
+use pkt_graph::{Iface, PktMeta};
+
+/// Seconds of capture time spent learning the MACs already on a network before a new
+/// one sets off a snapshot.
+pub const NEW_MAC_WARMUP_SECS: f64 = 60.0;
+
+/// Which kinds of traffic set off a snapshot, on top of the `snapshot` command.
+#[derive(Clone, Debug, Default)]
+pub struct TriggerConf {
+    pub new_mac: bool,
+    /// Bytes per second on any one route.
+    pub route_rate: Option<u64>
+}
+
+/// Traffic that set off a snapshot, and the interface it was seen on.
+#[derive(Clone, Debug)]
+pub enum Trigger {
+    NewMac(Iface, MacAddr),
+    /// Protocol, source, destination and bytes per second.
+    RouteRate(Iface, &'static str, String, String, u64)
+}
+
+impl Trigger {
+    pub fn iface(&self) -> &Iface {
+        match *self {
+            Trigger::NewMac(ref iface, _) | Trigger::RouteRate(ref iface, ..) => iface
+        }
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Trigger::NewMac(_, ref mac) => write!(f, "new mac {}", mac),
+            Trigger::RouteRate(_, typ, ref src, ref dst, rate) => {
+                write!(f, "{} route {} -> {} at {} bytes/s", typ, src, dst, rate)
+            }
+        }
+    }
+}
+
+/// Measures each route's traffic over windows of about a second of capture time,
+/// and reports the routes that go over the limit.  Routes are kept apart by
+/// interface, since each capture has its own clock.
+pub struct RateWatch<T> {
+    typ: &'static str,
+    limit: u64,
+    windows: HashMap<(Iface, T, T), (f64, u64)>
+}
+
+impl<T: Hash+Eq+Copy+Display> RateWatch<T> {
+    pub fn new(typ: &'static str, limit: u64) -> RateWatch<T> {
+        RateWatch { typ: typ, limit: limit, windows: HashMap::new() }
+    }
+
+    pub fn check(&mut self, pkt: &PktMeta<T>) -> Option<Trigger> {
+        let ts = pkt.tm.sec as f64 + f64::from(pkt.tm.nsec) / 1_000_000_000.0;
+        let key = (pkt.iface.clone(), pkt.src, pkt.dst);
+        let window = self.windows.entry(key).or_insert((ts, 0));
+        window.1 += u64::from(pkt.size);
+
+        let elapsed = ts - window.0;
+        if elapsed < 1.0 {
+            return None;
+        }
+        let rate = (window.1 as f64 / elapsed) as u64;
+        *window = (ts, 0);
+        if rate > self.limit {
+            Some(Trigger::RouteRate(pkt.iface.clone(), self.typ, pkt.src.to_string(),
+                                    pkt.dst.to_string(), rate))
+        } else {
+            None
+        }
+    }
+}
+
+/// Reports MACs that haven't been seen before.  Each capture first gets a warm-up so
+/// that the hosts already on its network don't all go off at once, and MACs listed
+/// under known-macs never count as new.
+pub struct NewMacWatch {
+    known: HashSet<MacAddr>,
+    // When each capture's first packet was seen, in capture time.
+    started: HashMap<Iface, f64>
+}
+
+impl NewMacWatch {
+    pub fn new(known: HashSet<MacAddr>) -> NewMacWatch {
+        NewMacWatch { known: known, started: HashMap::new() }
+    }
+
+    /// Check the addresses in `pkt`, with `seen` saying whether one's already in the
+    /// graph.
+    pub fn check<F>(&mut self, pkt: &PktMeta<MacAddr>, seen: F) -> Vec<Trigger>
+        where F: Fn(&MacAddr) -> bool
+    {
+        let ts = pkt.tm.sec as f64 + f64::from(pkt.tm.nsec) / 1_000_000_000.0;
+        let start = *self.started.entry(pkt.iface.clone()).or_insert(ts);
+        if ts - start < NEW_MAC_WARMUP_SECS {
+            return vec![];
+        }
+        let mut addrs = vec![pkt.src];
+        if pkt.dst != pkt.src {
+            addrs.push(pkt.dst);
+        }
+        addrs.into_iter()
+            .filter(|addr| !self.known.contains(addr) && !seen(addr))
+            .map(|addr| Trigger::NewMac(pkt.iface.clone(), addr))
+            .collect()
+    }
+}
+
+#[test]
+fn test_new_mac_warmup() {
+    use std::sync::Arc;
+    use time;
+
+    let a = MacAddr::from_bytes(&[2, 0, 0, 0, 0, 1]);
+    let b = MacAddr::from_bytes(&[2, 0, 0, 0, 0, 2]);
+    let pkt = |sec| PktMeta::new(a, b, 60, time::Timespec::new(sec, 0), Arc::new("eth0".into()));
+    let mut known = HashSet::new();
+    known.insert(b);
+    let mut watch = NewMacWatch::new(known);
+    assert!(watch.check(&pkt(100), |_| false).is_empty());
+    assert!(watch.check(&pkt(159), |_| false).is_empty());
+    let fired: Vec<_> = watch.check(&pkt(160), |_| false).iter().map(|t| t.to_string()).collect();
+    assert_eq!(fired, vec![format!("new mac {}", a)]);
+    assert!(watch.check(&pkt(161), |_| true).is_empty());
+}
+
+#[test]
+fn test_rate_per_iface() {
+    use std::sync::Arc;
+    use time;
+
+    let a = MacAddr::from_bytes(&[2, 0, 0, 0, 0, 1]);
+    let b = MacAddr::from_bytes(&[2, 0, 0, 0, 0, 2]);
+    let pkt = |iface: &str, sec, size| {
+        PktMeta::new(a, b, size, time::Timespec::new(sec, 0), Arc::new(iface.into()))
+    };
+    let mut watch = RateWatch::new("mac", 1000);
+    // A file replayed from long ago doesn't share a window with a live capture.
+    assert!(watch.check(&pkt("eth0", 1_500_000_000, 500)).is_none());
+    assert!(watch.check(&pkt("old.pcap", 100, 1500)).is_none());
+    assert!(watch.check(&pkt("eth0", 1_500_000_001, 60)).is_none());
+    let t = watch.check(&pkt("old.pcap", 101, 1500)).unwrap();
+    assert_eq!(**t.iface(), "old.pcap");
+    assert_eq!(t.to_string(), format!("mac route {} -> {} at 3000 bytes/s", a, b));
+}
@@ -1,13 +1,77 @@
+//! Integer fields as they're laid out on the wire, for the packed header structs.
+//! The bytes are kept in packet order and only put together when read, so headers
+//! come out the same whatever the host's byte order.
 
-//TODO: this is dumb and just assumes we're on a little-endian system.
-pub fn ntohs(n: u16) -> u16 {
-    (n>>8) | (n<<8)
+#![allow(dead_code)]
+
+fn from_be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |n, &b| n << 8 | u64::from(b))
+}
+
+fn from_le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |n, &b| n << 8 | u64::from(b))
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Be16([u8; 2]);
+
+impl Be16 {
+    pub fn get(self) -> u16 {
+        from_be(&self.0) as u16
+    }
 }
 
-pub unsafe fn skip_cast<T, U>(t: &T) -> &U {
-    &*((t as *const T).offset(1) as *const U)
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Be32([u8; 4]);
+
+impl Be32 {
+    pub fn get(self) -> u32 {
+        from_be(&self.0) as u32
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Be64([u8; 8]);
+
+impl Be64 {
+    pub fn get(self) -> u64 {
+        from_be(&self.0)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Le16([u8; 2]);
+
+impl Le16 {
+    pub fn get(self) -> u16 {
+        from_le(&self.0) as u16
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Le32([u8; 4]);
+
+impl Le32 {
+    pub fn get(self) -> u32 {
+        from_le(&self.0) as u32
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Le64([u8; 8]);
+
+impl Le64 {
+    pub fn get(self) -> u64 {
+        from_le(&self.0)
+    }
 }
 
-pub unsafe fn skip_bytes_cast<T,U>(t: &T, bytes: isize) -> &U {
-    &*((t as *const T as *const u8).offset(bytes) as *const U)
+#[test]
+fn test_wire_order() {
+    assert_eq!(Be16([0x08, 0x06]).get(), 0x0806);
+    assert_eq!(Le16([0x08, 0x06]).get(), 0x0608);
+    assert_eq!(Be32([0x80, 0x21, 0x10, 0x01]).get(), 0x8021_1001);
+    assert_eq!(Le32([0x2f, 0x48, 0x00, 0x80]).get(), 0x8000_482f);
+    assert_eq!(Be64([0, 0, 0, 0, 0, 0, 0x01, 0x02]).get(), 0x0102);
+    assert_eq!(Le64([0x01, 0x02, 0, 0, 0, 0, 0, 0x80]).get(), 0x8000_0000_0000_0201);
 }
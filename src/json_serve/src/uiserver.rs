@@ -75,7 +75,14 @@ pub struct UIServer {
 }
 
 impl UIServer {
-    pub fn spawn<T: Encodable>(port: u16, welcome: &T) -> io::Result<UIServer> {
+    /// `snapshot` is called for each new connection, and its messages are sent right
+    /// after `welcome` so late joiners see state that was built before they connected.
+    /// The connection gets live updates from before the snapshot is taken, so none
+    /// are lost in between; some may arrive ahead of the snapshot.
+    pub fn spawn<T, F>(port: u16, welcome: &T, snapshot: F) -> io::Result<UIServer>
+        where T: Encodable,
+              F: Fn() -> Vec<String> + Send + 'static
+    {
         let welcome_msg = Arc::new(json::encode(welcome).unwrap());
 
         let mc = Multicast::spawn()?;
@@ -89,7 +96,10 @@ impl UIServer {
             for tcp_stream in listener.incoming() {
                 let (conn_tx, conn_rx) = channel();
                 conn_tx.send(welcome_msg.clone()).unwrap();
-                json_dest_sender.register(conn_tx).unwrap();
+                json_dest_sender.register(conn_tx.clone()).unwrap();
+                for msg in snapshot() {
+                    conn_tx.send(Arc::new(msg)).unwrap();
+                }
                 thread::Builder::new().name(format!("websocket_{}", wrkr_cnt)).spawn(move || {
                     let tcps = tcp_stream.unwrap();
                     let mut tcpr = BufReader::new(tcps.try_clone().unwrap());
@@ -1,28 +1,30 @@
-#![feature(mpsc_select)]
-
 use std::sync::mpsc::{channel, Sender, SendError, Receiver};
 use std::thread;
 use std::sync::Arc;
 use std::io;
 
+// Messages and new destinations share a channel, so a destination gets every
+// message sent after it was registered.
+enum Cmd<T> {
+    Msg(Arc<T>),
+    Dest(Sender<Arc<T>>)
+}
+
 #[derive(Clone)]
 pub struct Multicast<T:Send+Sync+'static> {
-    msg_tx: Sender<Arc<T>>,
-    dest_tx: Sender<Sender<Arc<T>>>
+    cmd_tx: Sender<Cmd<T>>
 }
 
 impl<T:Send+Sync+'static> Multicast<T> {
     pub fn spawn() -> io::Result<Multicast<T>> {
-        let (msg_tx, msg_rx): (Sender<Arc<T>>, Receiver<Arc<T>>) = channel();
-        let (dest_tx, dest_rx): (Sender<Sender<Arc<T>>>, Receiver<Sender<Arc<T>>>) = channel();
+        let (cmd_tx, cmd_rx): (Sender<Cmd<T>>, Receiver<Cmd<T>>) = channel();
         thread::Builder::new().name("multicast".to_string()).spawn(move || {
             let mut mc_txs = Vec::new();
             let mut to_remove = Vec::new();
-            loop {
-                select!(
-                    dest = dest_rx.recv() => mc_txs.push(dest.unwrap()),
-                    msg = msg_rx.recv() => {
-                        let m = msg.unwrap();
+            for cmd in cmd_rx.iter() {
+                match cmd {
+                    Cmd::Dest(dest) => mc_txs.push(dest),
+                    Cmd::Msg(m) => {
                         to_remove.truncate(0);
                         for (i, mc_tx) in mc_txs.iter().enumerate() {
                             if mc_tx.send(m.clone()).is_err() {
@@ -37,18 +39,26 @@ impl<T:Send+Sync+'static> Multicast<T> {
                             }
                         }
                     }
-                )
+                }
             }
         })?;
 
-        Ok(Multicast { msg_tx: msg_tx, dest_tx: dest_tx })
+        Ok(Multicast { cmd_tx: cmd_tx })
     }
 
     pub fn send(&self, msg: Arc<T>) -> Result<(), SendError<Arc<T>>> {
-        self.msg_tx.send(msg)
+        self.cmd_tx.send(Cmd::Msg(msg)).map_err(|SendError(cmd)| match cmd {
+            Cmd::Msg(msg) => SendError(msg),
+            Cmd::Dest(_) => unreachable!()
+        })
     }
 
+    /// Start sending to `dest`.  It gets everything sent from here on, including by
+    /// other threads once this returns.
     pub fn register(&self, dest: Sender<Arc<T>>) -> Result<(), SendError<Sender<Arc<T>>>> {
-        self.dest_tx.send(dest)
+        self.cmd_tx.send(Cmd::Dest(dest)).map_err(|SendError(cmd)| match cmd {
+            Cmd::Dest(dest) => SendError(dest),
+            Cmd::Msg(_) => unreachable!()
+        })
     }
 }
@@ -6,3 +6,6 @@ extern crate libc;
 
 pub mod pcap;
 pub mod pcapll;
+#[cfg(target_os = "linux")]
+pub mod ring;
+pub mod savefile;
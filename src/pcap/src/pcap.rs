@@ -1,152 +1,1008 @@
 use libc::{self,c_char,c_int};
-use std::{ptr, slice};
-use std::ffi::CString;
+use std::{fmt, io, ptr, slice};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::{self, CStr, CString};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use pcapll;
+#[cfg(target_os = "linux")]
+use ring::{PacketRing, RingConf};
+use savefile::Savefile;
 
 //TODO: http://www.tcpdump.org/linktypes.html
 pub type DataLinkType = c_int;
 pub const DLT_NULL: DataLinkType = 0;
 pub const DLT_ETHERNET: DataLinkType = 1;
+/// 14 on OpenBSD; savefiles use LINKTYPE_RAW (101) instead.
+pub const DLT_RAW: DataLinkType = 12;
+pub const DLT_IEEE802_11: DataLinkType = 105;
+pub const DLT_LOOP: DataLinkType = 108;
+pub const DLT_LINUX_SLL: DataLinkType = 113;
+pub const DLT_PRISM_HEADER: DataLinkType = 119;
 pub const DLT_IEEE802_11_RADIO: DataLinkType = 127;
+pub const DLT_IEEE802_11_RADIO_AVS: DataLinkType = 163;
+pub const DLT_PPI: DataLinkType = 192;
+pub const DLT_IPV4: DataLinkType = 228;
+pub const DLT_IPV6: DataLinkType = 229;
+pub const DLT_LINUX_SLL2: DataLinkType = 276;
+
+/// Where a live capture's timestamps come from, as named in pcap-tstamp(7).  What's
+/// available depends on the device.
+pub type TstampType = c_int;
+
+/// Netmask to hand pcap_compile when we don't know the capture network's netmask;
+/// only matters for filters that test for IPv4 broadcast addresses.
+pub const PCAP_NETMASK_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+pub const PCAP_ERRBUF_SIZE: usize = 256;
+
+/// What savefiles use when no snaplen is given; matches libpcap's MAXIMUM_SNAPLEN.
+pub const MAX_SNAPLEN: u32 = 262_144;
+
+const LINKTYPE_RAW: u32 = 101;
+
+/// The DLT_ value for a LINKTYPE_ value read from a savefile.  They're the same
+/// except for a few link types whose DLT_ values differ between platforms.
+pub fn linktype_to_dlt(linktype: u32) -> DataLinkType {
+    match linktype {
+        LINKTYPE_RAW => DLT_RAW,
+        lt => lt as DataLinkType
+    }
+}
+
+/// The LINKTYPE_ value to write to a savefile for a DLT_ value.
+pub fn dlt_to_linktype(dlt: DataLinkType) -> u32 {
+    match dlt {
+        DLT_RAW => LINKTYPE_RAW,
+        dlt => dlt as u32
+    }
+}
+
+/// Resolution of packet timestamps.  Nanosecond timestamps have to be asked for,
+/// and not every platform or file can provide them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TstampPrecision {
+    Micro,
+    Nano
+}
+
+impl TstampPrecision {
+    fn to_raw(self) -> c_int {
+        match self {
+            TstampPrecision::Micro => pcapll::PCAP_TSTAMP_PRECISION_MICRO,
+            TstampPrecision::Nano => pcapll::PCAP_TSTAMP_PRECISION_NANO
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PcapError {
+    /// pcap_lookupdev or pcap_findalldevs failed; carries the errbuf text.
+    Lookup(String),
+    /// A handle or dump file couldn't be opened.
+    Open(String),
+    /// pcap_activate failed; carries the status code and its description.
+    Activate(c_int, String),
+    /// A filter failed to compile or couldn't be installed.
+    Filter(String),
+    /// The session couldn't be switched to the link type asked for.
+    Datalink(String),
+    /// pcap_next_ex reported an error while reading.
+    Read(String),
+    /// Statistics aren't available, e.g. because this is a savefile.
+    Stats(String),
+    /// Writing to a dump file failed.
+    Dump(String),
+    /// The savefile has no more packets, or pcap_breakloop was called.
+    Break,
+    /// A string handed to libpcap had an interior nul byte.
+    NulInString(ffi::NulError)
+}
+
+impl From<ffi::NulError> for PcapError {
+    fn from(e: ffi::NulError) -> PcapError {
+        PcapError::NulInString(e)
+    }
+}
+
+impl fmt::Display for PcapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PcapError::Lookup(ref msg) => write!(f, "device lookup failed: {}", msg),
+            PcapError::Open(ref msg) => write!(f, "could not open: {}", msg),
+            PcapError::Activate(status, ref msg) => {
+                write!(f, "could not activate ({}): {}", status, msg)
+            }
+            PcapError::Filter(ref msg) => write!(f, "bad filter: {}", msg),
+            PcapError::Datalink(ref msg) => write!(f, "could not set link type: {}", msg),
+            PcapError::Read(ref msg) => write!(f, "read failed: {}", msg),
+            PcapError::Stats(ref msg) => write!(f, "no statistics: {}", msg),
+            PcapError::Dump(ref msg) => write!(f, "could not write dump file: {}", msg),
+            PcapError::Break => write!(f, "no more packets"),
+            PcapError::NulInString(ref e) => write!(f, "{}", e)
+        }
+    }
+}
+
+/// Non-fatal conditions reported by pcap_activate.  Each carries the status
+/// description, plus the pcap_geterr text when libpcap had more to say.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PcapWarning {
+    Generic(String),
+    PromiscNotSup(String),
+    TstampTypeNotSup(String)
+}
+
+impl PcapWarning {
+    fn from_status(p: *mut pcapll::pcap_t, status: c_int) -> PcapWarning {
+        let msg = status_msg(p, status);
+        match status {
+            pcapll::PCAP_WARNING_PROMISC_NOTSUP => PcapWarning::PromiscNotSup(msg),
+            pcapll::PCAP_WARNING_TSTAMP_TYPE_NOTSUP => PcapWarning::TstampTypeNotSup(msg),
+            _ => PcapWarning::Generic(msg)
+        }
+    }
+}
+
+impl fmt::Display for PcapWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PcapWarning::Generic(ref msg) |
+            PcapWarning::PromiscNotSup(ref msg) |
+            PcapWarning::TstampTypeNotSup(ref msg) => f.write_str(msg)
+        }
+    }
+}
+
+fn errbuf_to_string(errbuf: &[c_char]) -> String {
+    unsafe { CStr::from_ptr(errbuf.as_ptr()).to_string_lossy().into_owned() }
+}
+
+/// Describe a pcap_activate status.  libpcap only fills in pcap_geterr for some
+/// statuses, so use it to add detail when it's there.
+fn status_msg(p: *mut pcapll::pcap_t, status: c_int) -> String {
+    let (status_str, err) = unsafe {
+        (CStr::from_ptr(pcapll::pcap_statustostr(status)).to_string_lossy().into_owned(),
+         CStr::from_ptr(pcapll::pcap_geterr(p)).to_string_lossy().into_owned())
+    };
+    if err.is_empty() {
+        status_str
+    } else {
+        format!("{}: {}", status_str, err)
+    }
+}
 
-#[derive(Copy,Clone)]
 pub struct PcapSessionBuilder {
     p: *mut pcapll::pcap_t,
-    activated: bool
+    // The link type to switch to, and whether to fail if the device doesn't offer it.
+    datalink: Option<(DataLinkType, bool)>,
+    filter: Option<(String, bool)>
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct DeviceFlags(u32);
+
+impl DeviceFlags {
+    pub fn is_loopback(&self) -> bool {
+        self.0 & pcapll::PCAP_IF_LOOPBACK != 0
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.0 & pcapll::PCAP_IF_UP != 0
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.0 & pcapll::PCAP_IF_RUNNING != 0
+    }
+
+    pub fn is_wireless(&self) -> bool {
+        self.0 & pcapll::PCAP_IF_WIRELESS != 0
+    }
 }
 
-pub fn list_devices() {
-    println!("list_devices")
+/// One of a device's addresses.  Only IPv4 and IPv6 entries are kept; link-layer
+/// addresses show up in the same list on some platforms and are skipped.
+#[derive(Clone, Debug)]
+pub struct DeviceAddr {
+    pub addr: IpAddr,
+    pub netmask: Option<IpAddr>,
+    pub broadaddr: Option<IpAddr>,
+    pub dstaddr: Option<IpAddr>
 }
 
-impl PcapSessionBuilder {
+#[derive(Clone, Debug)]
+pub struct Device {
+    pub name: String,
+    pub description: Option<String>,
+    pub flags: DeviceFlags,
+    pub addresses: Vec<DeviceAddr>
+}
+
+impl Device {
+    unsafe fn from_raw(dev: &pcapll::Struct_pcap_if) -> Device {
+        let mut addresses = Vec::new();
+        let mut a = dev.addresses;
+        while !a.is_null() {
+            if let Some(addr) = sockaddr_to_ip((*a).addr) {
+                addresses.push(DeviceAddr {
+                    addr: addr,
+                    netmask: sockaddr_to_ip((*a).netmask),
+                    broadaddr: sockaddr_to_ip((*a).broadaddr),
+                    dstaddr: sockaddr_to_ip((*a).dstaddr)
+                });
+            }
+            a = (*a).next;
+        }
 
-    pub fn new_dev(dev: &str) -> Result<PcapSessionBuilder, &'static str> {
-        let mut errbuf = Vec::with_capacity(256);
-        let c_dev = CString::new(dev.as_bytes()).unwrap().as_ptr();
-        PcapSessionBuilder::do_new(c_dev, errbuf.as_mut_slice())
+        Device {
+            name: CStr::from_ptr(dev.name).to_string_lossy().into_owned(),
+            description: if dev.description.is_null() {
+                None
+            } else {
+                Some(CStr::from_ptr(dev.description).to_string_lossy().into_owned())
+            },
+            flags: DeviceFlags(dev.flags),
+            addresses: addresses
+        }
     }
+}
 
-    pub fn new() -> Result<PcapSessionBuilder, &'static str> {
-        let mut errbuf = Vec::with_capacity(256);
-        let dev = unsafe { pcapll::pcap_lookupdev(errbuf.as_mut_slice().as_mut_ptr()) };
-        if dev.is_null() {
-            Err("No device available")
-        } else {
-            PcapSessionBuilder::do_new(dev as *const c_char, errbuf.as_mut_slice())
+unsafe fn sockaddr_to_ip(sa: *const pcapll::Struct_sockaddr) -> Option<IpAddr> {
+    if sa.is_null() {
+        return None;
+    }
+    let sa = sa as *const libc::sockaddr;
+    match i32::from((*sa).sa_family) {
+        libc::AF_INET => {
+            let sin = &*(sa as *const libc::sockaddr_in);
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = &*(sa as *const libc::sockaddr_in6);
+            Some(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)))
         }
+        _ => None
+    }
+}
+
+/// libpcap's name for a link type, like "EN10MB" for DLT_ETHERNET.
+pub fn datalink_name(dlt: DataLinkType) -> Option<String> {
+    let name = unsafe { pcapll::pcap_datalink_val_to_name(dlt) };
+    if name.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(name).to_string_lossy().into_owned() })
+    }
+}
+
+/// libpcap's description of a link type, like "Ethernet" for DLT_ETHERNET.
+pub fn datalink_description(dlt: DataLinkType) -> Option<String> {
+    let desc = unsafe { pcapll::pcap_datalink_val_to_description(dlt) };
+    if desc.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(desc).to_string_lossy().into_owned() })
+    }
+}
+
+/// The link type with the given libpcap name, in any case and with or without the
+/// DLT_ prefix, or given as a number.
+pub fn datalink_from_name(name: &str) -> Option<DataLinkType> {
+    if let Ok(dlt) = name.parse::<DataLinkType>() {
+        return Some(dlt);
+    }
+    let name = match name.get(..4) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("DLT_") => &name[4..],
+        _ => name
+    };
+    let c_name = CString::new(name.as_bytes()).ok()?;
+    match unsafe { pcapll::pcap_datalink_name_to_val(c_name.as_ptr()) } {
+        -1 => None,
+        dlt => Some(dlt)
+    }
+}
+
+/// The timestamp type with the given libpcap name, like "host" or "adapter_unsynced".
+pub fn tstamp_type_from_name(name: &str) -> Option<TstampType> {
+    let c_name = CString::new(name.as_bytes()).ok()?;
+    match unsafe { pcapll::pcap_tstamp_type_name_to_val(c_name.as_ptr()) } {
+        pcapll::PCAP_ERROR => None,
+        typ => Some(typ)
     }
+}
 
-    fn do_new(dev: *const c_char, errbuf: &mut [c_char]) -> Result<PcapSessionBuilder, &'static str> {
+/// The device libpcap would pick when none is given.
+pub fn default_device() -> Result<String, PcapError> {
+    let mut errbuf = vec![0 as c_char; PCAP_ERRBUF_SIZE];
+    let dev = unsafe { pcapll::pcap_lookupdev(errbuf.as_mut_ptr()) };
+    if dev.is_null() {
+        Err(PcapError::Lookup(errbuf_to_string(&errbuf)))
+    } else {
+        Ok(unsafe { CStr::from_ptr(dev).to_string_lossy().into_owned() })
+    }
+}
+
+pub fn list_devices() -> Result<Vec<Device>, PcapError> {
+    let mut errbuf = vec![0 as c_char; PCAP_ERRBUF_SIZE];
+    let mut alldevs = ptr::null_mut();
+    unsafe {
+        if pcapll::pcap_findalldevs(&mut alldevs, errbuf.as_mut_ptr()) != 0 {
+            return Err(PcapError::Lookup(errbuf_to_string(&errbuf)));
+        }
+        let mut out = Vec::new();
+        let mut d = alldevs;
+        while !d.is_null() {
+            out.push(Device::from_raw(&*d));
+            d = (*d).next;
+        }
+        pcapll::pcap_freealldevs(alldevs);
+        Ok(out)
+    }
+}
+
+impl PcapSessionBuilder {
+
+    pub fn new_dev(dev: &str) -> Result<PcapSessionBuilder, PcapError> {
+        let c_dev = CString::new(dev.as_bytes())?;
+        PcapSessionBuilder::do_new(c_dev.as_ptr())
+    }
+
+    pub fn new() -> Result<PcapSessionBuilder, PcapError> {
+        PcapSessionBuilder::new_dev(&default_device()?)
+    }
+
+    fn do_new(dev: *const c_char) -> Result<PcapSessionBuilder, PcapError> {
+        let mut errbuf = vec![0 as c_char; PCAP_ERRBUF_SIZE];
         let p = unsafe { pcapll::pcap_create(dev, errbuf.as_mut_ptr()) };
         if p.is_null() {
-            Err("Could not initialize device")
+            Err(PcapError::Open(errbuf_to_string(&errbuf)))
         } else {
-            Ok(PcapSessionBuilder { p: p, activated: false })
+            Ok(PcapSessionBuilder { p: p, datalink: None, filter: None })
         }
     }
 
+    /// Only capture the first `len` bytes of each packet.
+    pub fn snaplen(&mut self, len: i32) -> &mut PcapSessionBuilder {
+        unsafe { pcapll::pcap_set_snaplen(self.p, len); }
+        self
+    }
+
     pub fn buffer_size(&mut self, sz: i32) -> &mut PcapSessionBuilder {
-        if self.activated { panic!("Session already activated") }
         unsafe { pcapll::pcap_set_buffer_size(self.p, sz); }
         self
     }
 
     pub fn timeout(&mut self, to: i32) -> &mut PcapSessionBuilder {
-        if self.activated { panic!("Session already activated") }
         unsafe { pcapll::pcap_set_timeout(self.p, to); }
         self
     }
 
     pub fn promisc(&mut self, promisc: bool) -> &mut PcapSessionBuilder {
-        if self.activated { panic!("Session already activated") }
         unsafe { pcapll::pcap_set_promisc(self.p, promisc as c_int); }
         self
     }
 
     pub fn rfmon(&mut self, rfmon: bool) -> &mut PcapSessionBuilder {
-        if self.activated { panic!("Session already activated") }
         unsafe { pcapll::pcap_set_rfmon(self.p, rfmon as c_int); }
         self
     }
 
-    pub fn activate(&mut self) -> PcapSession {
-        if self.activated { panic!("Session already activated") }
-        unsafe {
-            let res = pcapll::pcap_activate(self.p);
-            if res != 0 {
-                panic!("Could not activate pcap session: {}", res);
+    /// Hand packets over as soon as they arrive instead of waiting for the buffer
+    /// to fill or the timeout to run out.
+    pub fn immediate_mode(&mut self, immediate: bool) -> &mut PcapSessionBuilder {
+        unsafe { pcapll::pcap_set_immediate_mode(self.p, immediate as c_int); }
+        self
+    }
+
+    /// Take timestamps from somewhere other than the host's clock, like the
+    /// adapter.  If the device can't do it pcap_activate falls back to the default
+    /// and warns with `PcapWarning::TstampTypeNotSup`.
+    pub fn tstamp_type(&mut self, typ: TstampType) -> &mut PcapSessionBuilder {
+        unsafe { pcapll::pcap_set_tstamp_type(self.p, typ); }
+        self
+    }
+
+    /// Ask for timestamps at the given precision.  If the device can't do it the
+    /// session falls back to microseconds; check `PcapSession::tstamp_precision`.
+    pub fn tstamp_precision(&mut self, prec: TstampPrecision) -> &mut PcapSessionBuilder {
+        unsafe { pcapll::pcap_set_tstamp_precision(self.p, prec.to_raw()); }
+        self
+    }
+
+    /// Capture with one of the other link types the device offers, like radiotap
+    /// headers instead of fake Ethernet ones on a wifi card.  Activation fails if
+    /// the device doesn't offer it.
+    pub fn datalink(&mut self, dlt: DataLinkType) -> &mut PcapSessionBuilder {
+        self.datalink = Some((dlt, true));
+        self
+    }
+
+    /// Like `datalink`, except the device's default is kept if it doesn't offer the
+    /// link type.
+    pub fn prefer_datalink(&mut self, dlt: DataLinkType) -> &mut PcapSessionBuilder {
+        self.datalink = Some((dlt, false));
+        self
+    }
+
+    /// Filters can only be installed on an activated handle, so this just remembers
+    /// the expression and `activate` applies it.
+    pub fn filter(&mut self, expr: &str, optimize: bool) -> &mut PcapSessionBuilder {
+        self.filter = Some((expr.to_owned(), optimize));
+        self
+    }
+
+    /// Activate the handle.  Warnings from pcap_activate don't stop the session from
+    /// working, so they come back through `PcapSession::warning` rather than as errors.
+    pub fn activate(mut self) -> Result<PcapSession, PcapError> {
+        let res = unsafe { pcapll::pcap_activate(self.p) };
+        let warning = match res {
+            0 => None,
+            w if w > 0 => Some(PcapWarning::from_status(self.p, w)),
+            e => return Err(PcapError::Activate(e, status_msg(self.p, e)))
+        };
+        // The session owns the handle now, don't let drop close it.
+        let sess = PcapSession::from_raw(self.p, warning);
+        self.p = ptr::null_mut();
+        // The filter has to be compiled for the link type we end up with.
+        if let Some((dlt, required)) = self.datalink {
+            if required || sess.list_datalinks().contains(&dlt) {
+                sess.set_datalink(dlt)?;
             }
         }
-        self.activated = true;
-        PcapSession { p: self.p }
+        if let Some((ref expr, optimize)) = self.filter {
+            sess.set_filter(expr, optimize)?;
+        }
+        Ok(sess)
+    }
+}
+
+impl Drop for PcapSessionBuilder {
+    fn drop(&mut self) {
+        if !self.p.is_null() {
+            unsafe { pcapll::pcap_close(self.p); }
+        }
     }
 }
 
-#[derive(Copy,Clone)]
+// The handle as seen by a PcapBreaker; nulled out when the session is closed.
+struct BreakHandle(*mut pcapll::pcap_t);
+
+// pcap_breakloop only sets a flag on the handle, which is safe from any thread, and
+// the pointer is only used while holding the lock that PcapSession::drop takes.
+unsafe impl Send for BreakHandle {}
+
 pub struct PcapSession {
-    p: *mut pcapll::pcap_t
+    // Null for sessions that don't go through libpcap at all.
+    p: *mut pcapll::pcap_t,
+    warning: Option<PcapWarning>,
+    nano: bool,
+    brk: Arc<Mutex<BreakHandle>>,
+    // Set by a PcapBreaker, for sessions that don't read through libpcap.
+    stop: Arc<AtomicBool>,
+    native: Option<RefCell<NativeInput>>,
+    #[cfg(target_os = "linux")]
+    ring: Option<RefCell<RingInput>>
+}
+
+// A savefile read with our own reader rather than libpcap.  There's no libpcap
+// handle; the filter is applied to packets as they're read.
+struct NativeInput {
+    file: Savefile<Box<Read>>,
+    filter: Option<BpfFilter>
+}
+
+impl NativeInput {
+    fn next<F>(&mut self, f: &mut F) -> Result<bool, PcapError> where F: FnMut(&PcapData) {
+        loop {
+            let pkt = match self.file.next_packet()? {
+                Some(pkt) => pkt,
+                None => return Err(PcapError::Break)
+            };
+            let mut hdr = pcapll::Struct_pcap_pkthdr::default();
+            hdr.ts.tv_sec = pkt.sec as libc::time_t;
+            hdr.ts.tv_usec = pkt.nsec as libc::suseconds_t;
+            hdr.caplen = pkt.data.len() as u32;
+            hdr.len = pkt.len;
+            let data = PcapData {
+                hdr: &mut hdr,
+                dat: pkt.data.as_ptr(),
+                nano: true,
+                dlt: linktype_to_dlt(pkt.linktype),
+                comment: pkt.comment
+            };
+
+            if let Some(ref mut filter) = self.filter {
+                if !filter.matches(&data) {
+                    continue;
+                }
+            }
+            f(&data);
+            return Ok(true);
+        }
+    }
+
+    // The link type of every packet, if the interfaces so far all have the same
+    // one.  A pcapng file can add interfaces as it goes, so this can change.
+    fn datalink(&self) -> Option<DataLinkType> {
+        let mut dlts = self.file.interfaces().iter().map(|i| linktype_to_dlt(i.linktype));
+        let first = dlts.next()?;
+        if dlts.all(|dlt| dlt == first) { Some(first) } else { None }
+    }
+
+    fn snaplen(&self) -> u32 {
+        self.file.interfaces().iter()
+            .map(|i| if i.snaplen == 0 { MAX_SNAPLEN } else { i.snaplen.min(MAX_SNAPLEN) })
+            .max()
+            .unwrap_or(MAX_SNAPLEN)
+    }
+}
+
+// How often a read from a stream that's waiting for data checks for a stop.
+const STREAM_POLL_MS: c_int = 100;
+
+// A pipe or standard input, which can sit waiting for data indefinitely.  Reads
+// wait for data a little at a time, and fail once the session's been stopped.
+struct StreamReader {
+    file: File,
+    stop: Arc<AtomicBool>
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.stop.load(Ordering::SeqCst) {
+                return Err(io::Error::new(io::ErrorKind::Other, "stopped"));
+            }
+            let mut pfd = libc::pollfd { fd: self.file.as_raw_fd(), events: libc::POLLIN,
+                                         revents: 0 };
+            match unsafe { libc::poll(&mut pfd, 1, STREAM_POLL_MS) } {
+                0 => {}
+                n if n < 0 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+                _ => return self.file.read(buf)
+            }
+        }
+    }
+}
+
+// A live capture read from an AF_PACKET ring rather than through libpcap.  Like
+// NativeInput there's no libpcap handle.  Filters are run by the kernel, and only
+// need libpcap to compile them.
+#[cfg(target_os = "linux")]
+struct RingInput {
+    ring: PacketRing,
+    timeout_ms: c_int
+}
+
+// A handle from pcap_open_dead, which libpcap needs for compiling filters and
+// writing savefiles even when it isn't reading any packets.  Closed on drop.
+struct DeadHandle(*mut pcapll::pcap_t);
+
+impl DeadHandle {
+    fn open(dlt: DataLinkType, snaplen: u32, prec: TstampPrecision)
+            -> Result<DeadHandle, PcapError> {
+        let p = unsafe {
+            pcapll::pcap_open_dead_with_tstamp_precision(dlt, snaplen.min(MAX_SNAPLEN) as c_int,
+                                                         prec.to_raw() as pcapll::u_int)
+        };
+        if p.is_null() {
+            Err(PcapError::Open("pcap_open_dead failed".to_owned()))
+        } else {
+            Ok(DeadHandle(p))
+        }
+    }
+}
+
+impl Drop for DeadHandle {
+    fn drop(&mut self) {
+        unsafe { pcapll::pcap_close(self.0); }
+    }
+}
+
+fn geterr(p: *mut pcapll::pcap_t) -> String {
+    unsafe { CStr::from_ptr(pcapll::pcap_geterr(p)).to_string_lossy().into_owned() }
+}
+
+fn compile_on(p: *mut pcapll::pcap_t, expr: &str, optimize: bool)
+              -> Result<BpfProgram, PcapError> {
+    let c_expr = CString::new(expr.as_bytes())?;
+    let mut prog = pcapll::Struct_bpf_program::default();
+    let res = unsafe {
+        pcapll::pcap_compile(p, &mut prog, c_expr.as_ptr(), optimize as c_int,
+                             PCAP_NETMASK_UNKNOWN)
+    };
+    if res != 0 {
+        return Err(PcapError::Filter(geterr(p)));
+    }
+    Ok(BpfProgram { prog: prog })
+}
+
+/// Compile a filter for packets of link type `dlt` cut to `snaplen`, without
+/// needing a session.
+pub fn compile_for(dlt: DataLinkType, snaplen: u32, expr: &str, optimize: bool)
+                   -> Result<BpfProgram, PcapError> {
+    let dead = DeadHandle::open(dlt, snaplen, TstampPrecision::Nano)?;
+    compile_on(dead.0, expr, optimize)
+}
+
+impl Drop for PcapSession {
+    fn drop(&mut self) {
+        let mut brk = self.brk.lock().unwrap();
+        brk.0 = ptr::null_mut();
+        if !self.p.is_null() {
+            unsafe { pcapll::pcap_close(self.p); }
+        }
+    }
+}
+
+/// Stops a session from another thread: the session's next (or current) read
+/// returns `PcapError::Break`.  Does nothing once the session has been closed.
+#[derive(Clone)]
+pub struct PcapBreaker {
+    brk: Arc<Mutex<BreakHandle>>,
+    stop: Arc<AtomicBool>
+}
+
+impl PcapBreaker {
+    pub fn breakloop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let brk = self.brk.lock().unwrap();
+        if !brk.0.is_null() {
+            unsafe { pcapll::pcap_breakloop(brk.0); }
+        }
+    }
 }
 
 impl PcapSession {
-    pub fn from_file(f: &str) -> PcapSession {
-        let mut errbuf = Vec::with_capacity(256);
-        unsafe {
-            let p = pcapll::pcap_open_offline(CString::new(f.as_bytes()).unwrap().as_ptr(),
-                                            errbuf.as_mut_slice().as_mut_ptr());
-            PcapSession { p: p }
+    fn from_raw(p: *mut pcapll::pcap_t, warning: Option<PcapWarning>) -> PcapSession {
+        let prec = unsafe { pcapll::pcap_get_tstamp_precision(p) };
+        PcapSession {
+            p: p,
+            warning: warning,
+            nano: prec == pcapll::PCAP_TSTAMP_PRECISION_NANO,
+            brk: Arc::new(Mutex::new(BreakHandle(p))),
+            stop: Arc::new(AtomicBool::new(false)),
+            native: None,
+            #[cfg(target_os = "linux")]
+            ring: None
         }
     }
 
-    pub fn datalink(&self) -> DataLinkType {
-        unsafe { pcapll::pcap_datalink(self.p) }
+    // A session for packets that come from somewhere other than libpcap.
+    fn without_handle() -> PcapSession {
+        PcapSession {
+            p: ptr::null_mut(),
+            warning: None,
+            nano: true,
+            brk: Arc::new(Mutex::new(BreakHandle(ptr::null_mut()))),
+            stop: Arc::new(AtomicBool::new(false)),
+            native: None,
+            #[cfg(target_os = "linux")]
+            ring: None
+        }
+    }
+
+    pub fn breaker(&self) -> PcapBreaker {
+        PcapBreaker { brk: self.brk.clone(), stop: self.stop.clone() }
+    }
+
+    pub fn from_file(f: &str) -> Result<PcapSession, PcapError> {
+        PcapSession::from_file_with_precision(f, TstampPrecision::Micro)
+    }
+
+    /// Open a capture file, scaling its timestamps to `prec` regardless of the
+    /// precision they were written with.
+    pub fn from_file_with_precision(f: &str, prec: TstampPrecision)
+                                    -> Result<PcapSession, PcapError> {
+        let c_f = CString::new(f.as_bytes())?;
+        let mut errbuf = vec![0 as c_char; PCAP_ERRBUF_SIZE];
+        let p = unsafe {
+            pcapll::pcap_open_offline_with_tstamp_precision(c_f.as_ptr(),
+                                                            prec.to_raw() as pcapll::u_int,
+                                                            errbuf.as_mut_ptr())
+        };
+        if p.is_null() {
+            Err(PcapError::Open(errbuf_to_string(&errbuf)))
+        } else {
+            Ok(PcapSession::from_raw(p, None))
+        }
+    }
+
+    /// Open a pcap or pcapng file with our own reader instead of libpcap's, which
+    /// handles pcapng files whose interfaces have different link types.  No libpcap
+    /// handle is opened.  Timestamps always have nanosecond precision, and
+    /// `datalink` is None when the interfaces differ; check `PcapData::datalink` for
+    /// each packet's.
+    pub fn from_file_native(f: &str) -> Result<PcapSession, PcapError> {
+        let file = File::open(f).map_err(|e| PcapError::Open(format!("{}: {}", f, e)))?;
+        PcapSession::from_reader_native(Box::new(BufReader::new(file)))
+    }
+
+    /// Like `from_file_native`, for a savefile coming from any reader.
+    pub fn from_reader_native(rdr: Box<Read>) -> Result<PcapSession, PcapError> {
+        PcapSession::from_savefile(Savefile::new(rdr)?)
+    }
+
+    /// Like `from_reader_native`, for a pipe or standard input.  Unlike a plain
+    /// reader, a read waiting for more data returns `PcapError::Break` once the
+    /// session's breaker is used.
+    pub fn from_stream_native(file: File) -> Result<PcapSession, PcapError> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let rdr = StreamReader { file: file, stop: stop.clone() };
+        let mut sess = PcapSession::from_reader_native(Box::new(BufReader::new(rdr)))?;
+        sess.stop = stop;
+        Ok(sess)
+    }
+
+    fn from_savefile(file: Savefile<Box<Read>>) -> Result<PcapSession, PcapError> {
+        let mut sess = PcapSession::without_handle();
+        sess.native = Some(RefCell::new(NativeInput { file: file, filter: None }));
+        Ok(sess)
+    }
+
+    /// Capture live from `dev` through an AF_PACKET ring instead of libpcap; see
+    /// the `ring` module.  Filters run in the kernel, and `next` waits at most
+    /// `timeout_ms` for a block of packets.
+    #[cfg(target_os = "linux")]
+    pub fn open_ring(dev: &str, conf: &RingConf, timeout_ms: i32)
+                     -> Result<PcapSession, PcapError> {
+        let ring = PacketRing::open(dev, conf)?;
+        let mut sess = PcapSession::without_handle();
+        sess.ring = Some(RefCell::new(RingInput { ring: ring, timeout_ms: timeout_ms }));
+        Ok(sess)
+    }
+
+    pub fn warning(&self) -> Option<&PcapWarning> {
+        self.warning.as_ref()
+    }
+
+    pub fn tstamp_precision(&self) -> TstampPrecision {
+        if self.nano { TstampPrecision::Nano } else { TstampPrecision::Micro }
     }
 
-    pub fn list_datalinks(&self) -> Vec<i32> {
+    /// The link type of the session's packets, or None for a savefile read with
+    /// `from_file_native` whose interfaces have different ones.
+    pub fn datalink(&self) -> Option<DataLinkType> {
+        if let Some(ref native) = self.native {
+            return native.borrow().datalink();
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ref ring) = self.ring {
+                return Some(ring.borrow().ring.datalink());
+            }
+        }
+        Some(unsafe { pcapll::pcap_datalink(self.p) })
+    }
+
+    /// How many bytes of each packet are captured.
+    pub fn snaplen(&self) -> u32 {
+        if let Some(ref native) = self.native {
+            return native.borrow().snaplen();
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ref ring) = self.ring {
+                return ring.borrow().ring.snaplen();
+            }
+        }
+        unsafe { pcapll::pcap_snapshot(self.p) as u32 }
+    }
+
+    /// The link types the device can capture with; the first is usually its default.
+    pub fn list_datalinks(&self) -> Vec<DataLinkType> {
+        if self.p.is_null() {
+            return Vec::new();
+        }
         unsafe {
             let mut dlt_buf = ptr::null_mut();
             let sz = pcapll::pcap_list_datalinks(self.p, &mut dlt_buf);
+            if sz < 0 {
+                return Vec::new();
+            }
             let out = slice::from_raw_parts(dlt_buf as *const c_int, sz as usize).to_vec();
             pcapll::pcap_free_datalinks(dlt_buf);
             out
         }
     }
 
-    //TODO: add a return value for success/failure
-    pub fn next<F>(&self, mut f: F) where F: FnMut(&PcapData) {
+    /// Switch to another of the link types from `list_datalinks`.  A filter that's
+    /// already installed was compiled for the old link type, so set it again after.
+    pub fn set_datalink(&self, dlt: DataLinkType) -> Result<(), PcapError> {
+        if self.native.is_some() {
+            return Err(PcapError::Datalink("savefiles keep the link types they were written with"
+                                           .to_owned()));
+        }
+        if self.p.is_null() {
+            return Err(PcapError::Datalink("not a libpcap capture".to_owned()));
+        }
+        if !self.list_datalinks().contains(&dlt) {
+            let name = datalink_name(dlt).unwrap_or_else(|| dlt.to_string());
+            return Err(PcapError::Datalink(format!("{} not offered by device", name)));
+        }
+        if unsafe { pcapll::pcap_set_datalink(self.p, dlt) } != 0 {
+            return Err(PcapError::Datalink(self.geterr()));
+        }
+        Ok(())
+    }
+
+    /// Compile `expr` as a BPF program and install it on the session, replacing
+    /// any filter already in place.  An empty expression matches everything.
+    pub fn set_filter(&self, expr: &str, optimize: bool) -> Result<(), PcapError> {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ref ring) = self.ring {
+                let mut ring = ring.borrow_mut();
+                if expr.is_empty() {
+                    return ring.ring.clear_filter();
+                }
+                let prog = compile_for(ring.ring.datalink(), ring.ring.snaplen(), expr, optimize)?;
+                let insns = unsafe {
+                    slice::from_raw_parts(prog.prog.bf_insns, prog.prog.bf_len as usize)
+                };
+                return ring.ring.set_filter(insns);
+            }
+        }
+        if let Some(ref native) = self.native {
+            let mut native = native.borrow_mut();
+            // Check the filter against the link types seen so far; any that turn up
+            // later get compiled as their first packet is read.
+            let mut filter = BpfFilter::new(expr, optimize, MAX_SNAPLEN);
+            for iface in native.file.interfaces() {
+                filter.check(linktype_to_dlt(iface.linktype))?;
+            }
+            native.filter = Some(filter);
+            return Ok(());
+        }
+        let mut prog = self.compile(expr, optimize)?;
+        let res = unsafe { pcapll::pcap_setfilter(self.p, &mut prog.prog) };
+        if res != 0 {
+            return Err(PcapError::Filter(self.geterr()));
+        }
+        Ok(())
+    }
+
+    /// Compile a filter for this session's link type without installing it.  Fails
+    /// for a savefile that mixes link types; use a `BpfFilter` for those.
+    pub fn compile(&self, expr: &str, optimize: bool) -> Result<BpfProgram, PcapError> {
+        if !self.p.is_null() {
+            return compile_on(self.p, expr, optimize);
+        }
+        match self.datalink() {
+            Some(dlt) => compile_for(dlt, self.snaplen(), expr, optimize),
+            None => Err(PcapError::Filter("the savefile mixes link types".to_owned()))
+        }
+    }
+
+    fn geterr(&self) -> String {
+        geterr(self.p)
+    }
+
+    /// Packet counts from the kernel/driver since the session was activated.  What
+    /// exactly gets counted varies by platform; see pcap_stats(3pcap).
+    pub fn stats(&self) -> Result<PcapStats, PcapError> {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ref ring) = self.ring {
+                let (received, dropped) = ring.borrow_mut().ring.stats()?;
+                return Ok(PcapStats { received: received, dropped: dropped, ifdropped: 0 });
+            }
+        }
+        if self.p.is_null() {
+            return Err(PcapError::Stats("savefiles don't have any".to_owned()));
+        }
+        let mut st = pcapll::Struct_pcap_stat::default();
+        if unsafe { pcapll::pcap_stats(self.p, &mut st) } != 0 {
+            return Err(PcapError::Stats(self.geterr()));
+        }
+        Ok(PcapStats { received: st.ps_recv, dropped: st.ps_drop, ifdropped: st.ps_ifdrop })
+    }
+
+    /// Read the next packet and hand it to `f`.  Returns `Ok(false)` if the read
+    /// timed out before a packet showed up.  Sessions opened with `open_ring` read a
+    /// block at a time, so `f` may be called many times.
+    pub fn next<F>(&self, mut f: F) -> Result<bool, PcapError> where F: FnMut(&PcapData) {
+        if let Some(ref native) = self.native {
+            let res = native.borrow_mut().next(&mut f);
+            if res.is_err() && self.stop.swap(false, Ordering::SeqCst) {
+                return Err(PcapError::Break);
+            }
+            return res;
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ref ring) = self.ring {
+                if self.stop.swap(false, Ordering::SeqCst) {
+                    return Err(PcapError::Break);
+                }
+                let mut ring = ring.borrow_mut();
+                let (dlt, timeout_ms) = (ring.ring.datalink(), ring.timeout_ms);
+                return ring.ring.next(timeout_ms, |frame| {
+                    let mut hdr = pcapll::Struct_pcap_pkthdr::default();
+                    hdr.ts.tv_sec = frame.sec as libc::time_t;
+                    hdr.ts.tv_usec = frame.nsec as libc::suseconds_t;
+                    hdr.caplen = frame.data.len() as u32;
+                    hdr.len = frame.len;
+                    f(&PcapData {
+                        hdr: &mut hdr,
+                        dat: frame.data.as_ptr(),
+                        nano: true,
+                        dlt: dlt,
+                        comment: None
+                    });
+                });
+            }
+        }
         let mut head_ptr = ptr::null_mut();
         let mut data_ptr = ptr::null();
         let res = unsafe { pcapll::pcap_next_ex(self.p, &mut head_ptr, &mut data_ptr) };
         match res {
-            0 => return, //timed out
+            0 => Ok(false),
             1 => {
-                let p = PcapData { hdr: head_ptr, dat: data_ptr };
+                let p = PcapData {
+                    hdr: head_ptr,
+                    dat: data_ptr,
+                    nano: self.nano,
+                    dlt: unsafe { pcapll::pcap_datalink(self.p) },
+                    comment: None
+                };
                 f(&p);
+                Ok(true)
             }
-            _ => {
-                panic!("pcap_next_ex panicked with {}, find something better to do than blow up",
-                       res);
-            }
+            pcapll::PCAP_ERROR_BREAK => Err(PcapError::Break),
+            _ => Err(PcapError::Read(self.geterr()))
         }
     }
 }
 
+#[derive(Copy, Clone, Debug)]
+pub struct PcapStats {
+    pub received: u32,
+    pub dropped: u32,
+    pub ifdropped: u32
+}
 
-pub struct PcapTimeval(libc::timeval);
+/// A packet timestamp.  For sessions with nanosecond precision libpcap stores
+/// nanoseconds in `tv_usec`, so use the accessors rather than the raw timeval.
+pub struct PcapTimeval {
+    tv: libc::timeval,
+    nano: bool
+}
 
 impl PcapTimeval {
     pub fn sec(&self) -> i64 {
-        self.0.tv_sec
+        self.tv.tv_sec
     }
 
     pub fn usec(&self) -> i32 {
-        self.0.tv_usec
+        if self.nano { self.tv.tv_usec / 1000 } else { self.tv.tv_usec }
+    }
+
+    pub fn nsec(&self) -> i32 {
+        if self.nano { self.tv.tv_usec } else { self.tv.tv_usec * 1000 }
+    }
+
+    /// Seconds since the epoch.
+    pub fn to_f64(&self) -> f64 {
+        self.sec() as f64 + f64::from(self.nsec()) / 1_000_000_000.0
     }
 }
 
 pub struct PcapData {
-    hdr: *mut pcapll::Struct_pcap_pkthdr,
-    dat: *const u8
+    hdr: *const pcapll::Struct_pcap_pkthdr,
+    dat: *const u8,
+    nano: bool,
+    dlt: DataLinkType,
+    comment: Option<String>
 }
 
 impl PcapData {
@@ -159,29 +1015,216 @@ impl PcapData {
     }
 
     pub fn ts(&self) -> PcapTimeval {
-        unsafe { PcapTimeval((*self.hdr).ts) }
+        unsafe { PcapTimeval { tv: (*self.hdr).ts, nano: self.nano } }
     }
 
     pub fn pkt_ptr(&self) -> *const u8 {
         self.dat
     }
+
+    /// The captured bytes, which may be fewer than `len` if the snaplen cut them off.
+    pub fn data(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.dat, self.caplen() as usize) }
+    }
+
+    /// The packet's link type.  Only savefiles read with `from_file_native` can mix
+    /// link types; otherwise it's always the session's.
+    pub fn datalink(&self) -> DataLinkType {
+        self.dlt
+    }
+
+    /// The packet's comment, from a pcapng file read with `from_file_native`.
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_ref().map(|c| &c[..])
+    }
+
+    /// Copy the packet out of libpcap's buffer, which gets reused on the next read.
+    pub fn to_packet(&self) -> PcapPacket {
+        PcapPacket {
+            hdr: unsafe { *self.hdr },
+            dat: self.data().to_vec(),
+            nano: self.nano,
+            dlt: self.dlt,
+            comment: self.comment.clone()
+        }
+    }
+}
+
+/// A packet copied out of a session, to hang on to after the next read.
+#[derive(Clone)]
+pub struct PcapPacket {
+    hdr: pcapll::Struct_pcap_pkthdr,
+    dat: Vec<u8>,
+    nano: bool,
+    dlt: DataLinkType,
+    comment: Option<String>
+}
+
+impl PcapPacket {
+    pub fn caplen(&self) -> u32 {
+        self.hdr.caplen
+    }
+
+    pub fn ts(&self) -> PcapTimeval {
+        PcapTimeval { tv: self.hdr.ts, nano: self.nano }
+    }
+
+    /// Look at the packet as if it had just been read.
+    pub fn with_data<F, T>(&self, f: F) -> T where F: FnOnce(&PcapData) -> T {
+        f(&PcapData {
+            hdr: &self.hdr,
+            dat: self.dat.as_ptr(),
+            nano: self.nano,
+            dlt: self.dlt,
+            comment: self.comment.clone()
+        })
+    }
 }
 
+/// A compiled filter, for matching packets in userspace rather than in the kernel.
+pub struct BpfProgram {
+    prog: pcapll::Struct_bpf_program
+}
+
+impl BpfProgram {
+    pub fn matches(&mut self, data: &PcapData) -> bool {
+        unsafe { pcapll::pcap_offline_filter(&mut self.prog, data.hdr, data.dat) != 0 }
+    }
+}
+
+impl Drop for BpfProgram {
+    fn drop(&mut self) {
+        unsafe { pcapll::pcap_freecode(&mut self.prog); }
+    }
+}
+
+// The program is plain memory owned by us; nothing in libpcap refers back to it.
+unsafe impl Send for BpfProgram {}
+
+/// A filter for packets that can come with any link type, compiled separately for
+/// each one the first time it turns up.
+pub struct BpfFilter {
+    expr: String,
+    optimize: bool,
+    snaplen: u32,
+    // None for link types the filter can't be compiled for.
+    progs: HashMap<DataLinkType, Option<BpfProgram>>
+}
+
+impl BpfFilter {
+    pub fn new(expr: &str, optimize: bool, snaplen: u32) -> BpfFilter {
+        BpfFilter {
+            expr: expr.to_owned(),
+            optimize: optimize,
+            snaplen: snaplen,
+            progs: HashMap::new()
+        }
+    }
+
+    /// Compile the filter for `dlt` now, to find out whether it can be.
+    pub fn check(&mut self, dlt: DataLinkType) -> Result<(), PcapError> {
+        if let Some(&Some(_)) = self.progs.get(&dlt) {
+            return Ok(());
+        }
+        let prog = compile_for(dlt, self.snaplen, &self.expr, self.optimize)?;
+        self.progs.insert(dlt, Some(prog));
+        Ok(())
+    }
+
+    /// Whether `data` passes.  Packets with a link type the filter can't be compiled
+    /// for don't.
+    pub fn matches(&mut self, data: &PcapData) -> bool {
+        let (expr, optimize, snaplen) = (&self.expr, self.optimize, self.snaplen);
+        let prog = self.progs.entry(data.dlt)
+            .or_insert_with(|| compile_for(data.dlt, snaplen, expr, optimize).ok());
+        match *prog {
+            Some(ref mut prog) => prog.matches(data),
+            None => false
+        }
+    }
+}
+
+/// Writes a classic savefile, which has one link type for all its packets.
 pub struct PcapDumper {
-    p: *mut pcapll::Struct_pcap_dumper
+    p: *mut pcapll::Struct_pcap_dumper,
+    dlt: DataLinkType,
+    nano: bool
 }
 
+// The dumper only holds the FILE it writes to.
+unsafe impl Send for PcapDumper {}
+
 impl PcapDumper {
-    pub fn new(sess: &PcapSession, path: &str) -> PcapDumper {
-        unsafe {
-            let p = pcapll::pcap_dump_open(sess.p, CString::new(path.as_bytes()).unwrap().as_ptr());
-            PcapDumper { p: p }
+    /// Start a savefile for packets of link type `dlt` cut to `snaplen`, with
+    /// timestamps written at `prec`.
+    pub fn new(path: &str, dlt: DataLinkType, snaplen: u32, prec: TstampPrecision)
+               -> Result<PcapDumper, PcapError> {
+        PcapDumper::open(path, dlt, snaplen, prec, false)
+    }
+
+    /// Add to the end of an existing savefile, creating it if it isn't there.  The
+    /// file has to have the same link type and timestamp precision.
+    pub fn append(path: &str, dlt: DataLinkType, snaplen: u32, prec: TstampPrecision)
+                  -> Result<PcapDumper, PcapError> {
+        PcapDumper::open(path, dlt, snaplen, prec, true)
+    }
+
+    fn open(path: &str, dlt: DataLinkType, snaplen: u32, prec: TstampPrecision, append: bool)
+            -> Result<PcapDumper, PcapError> {
+        let c_path = CString::new(path.as_bytes())?;
+        // The handle is only needed to write the file header.
+        let dead = DeadHandle::open(dlt, snaplen, prec)?;
+        let p = unsafe {
+            if append {
+                pcapll::pcap_dump_open_append(dead.0, c_path.as_ptr())
+            } else {
+                pcapll::pcap_dump_open(dead.0, c_path.as_ptr())
+            }
+        };
+        if p.is_null() {
+            Err(PcapError::Open(geterr(dead.0)))
+        } else {
+            Ok(PcapDumper { p: p, dlt: dlt, nano: prec == TstampPrecision::Nano })
         }
     }
 
+    /// The link type the file was started with.  Packets with any other don't
+    /// belong in it.
+    pub fn datalink(&self) -> DataLinkType {
+        self.dlt
+    }
+
     pub fn dump(&mut self, data: &PcapData) {
+        let mut hdr = unsafe { *data.hdr };
+        if data.nano != self.nano {
+            let ts = data.ts();
+            hdr.ts.tv_usec = (if self.nano { ts.nsec() } else { ts.usec() }) as libc::suseconds_t;
+        }
         unsafe {
-            pcapll::pcap_dump(self.p as *mut u8, data.hdr, data.dat);
+            pcapll::pcap_dump(self.p as *mut u8, &hdr, data.dat);
+        }
+    }
+
+    pub fn dump_packet(&mut self, pkt: &PcapPacket) {
+        pkt.with_data(|data| self.dump(data))
+    }
+
+    /// How many bytes have been written, including anything still buffered.
+    pub fn ftell(&self) -> Result<u64, PcapError> {
+        let pos = unsafe { pcapll::pcap_dump_ftell(self.p) };
+        if pos < 0 {
+            Err(PcapError::Dump(io::Error::last_os_error().to_string()))
+        } else {
+            Ok(pos as u64)
+        }
+    }
+
+    /// Push buffered packets out to the file.
+    pub fn flush(&mut self) -> Result<(), PcapError> {
+        if unsafe { pcapll::pcap_dump_flush(self.p) } != 0 {
+            Err(PcapError::Dump(io::Error::last_os_error().to_string()))
+        } else {
+            Ok(())
         }
     }
 }
@@ -193,3 +1236,25 @@ impl Drop for PcapDumper {
         }
     }
 }
+
+#[test]
+fn test_stream_stop() {
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let (rd, mut wr) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    // A classic little-endian header, for Ethernet.
+    wr.write_all(&[0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                   0xff, 0xff, 0, 0, 1, 0, 0, 0]).unwrap();
+    let sess = PcapSession::from_stream_native(rd).unwrap();
+    // Nothing more is coming, but the writer's still open: the read has to be
+    // broken out of.
+    sess.breaker().breakloop();
+    match sess.next(|_| {}) {
+        Err(PcapError::Break) => {}
+        r => panic!("expected a break, got {:?}", r.map_err(|e| e.to_string()))
+    }
+    drop(wr);
+}
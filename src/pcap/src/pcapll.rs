@@ -57,6 +57,23 @@ pub const PCAP_D_INOUT: ::libc::c_uint = 0;
 pub const PCAP_D_IN: ::libc::c_uint = 1;
 pub const PCAP_D_OUT: ::libc::c_uint = 2;
 pub type pcap_direction_t = Enum_Unnamed1;
+pub const PCAP_ERROR: ::libc::c_int = -1;
+pub const PCAP_ERROR_BREAK: ::libc::c_int = -2;
+pub const PCAP_ERROR_NOT_ACTIVATED: ::libc::c_int = -3;
+pub const PCAP_ERROR_ACTIVATED: ::libc::c_int = -4;
+pub const PCAP_ERROR_NO_SUCH_DEVICE: ::libc::c_int = -5;
+pub const PCAP_ERROR_RFMON_NOTSUP: ::libc::c_int = -6;
+pub const PCAP_ERROR_NOT_RFMON: ::libc::c_int = -7;
+pub const PCAP_ERROR_PERM_DENIED: ::libc::c_int = -8;
+pub const PCAP_ERROR_IFACE_NOT_UP: ::libc::c_int = -9;
+pub const PCAP_ERROR_CANTSET_TSTAMP_TYPE: ::libc::c_int = -10;
+pub const PCAP_ERROR_PROMISC_PERM_DENIED: ::libc::c_int = -11;
+pub const PCAP_ERROR_TSTAMP_PRECISION_NOTSUP: ::libc::c_int = -12;
+pub const PCAP_WARNING: ::libc::c_int = 1;
+pub const PCAP_WARNING_PROMISC_NOTSUP: ::libc::c_int = 2;
+pub const PCAP_WARNING_TSTAMP_TYPE_NOTSUP: ::libc::c_int = 3;
+pub const PCAP_TSTAMP_PRECISION_MICRO: ::libc::c_int = 0;
+pub const PCAP_TSTAMP_PRECISION_NANO: ::libc::c_int = 1;
 #[repr(C)]
 #[derive(Copy)]
 pub struct Struct_pcap_pkthdr {
@@ -95,6 +112,10 @@ pub struct Struct_pcap_if {
     pub addresses: *mut Struct_pcap_addr,
     pub flags: bpf_u_int32,
 }
+pub const PCAP_IF_LOOPBACK: bpf_u_int32 = 0x00000001;
+pub const PCAP_IF_UP: bpf_u_int32 = 0x00000002;
+pub const PCAP_IF_RUNNING: bpf_u_int32 = 0x00000004;
+pub const PCAP_IF_WIRELESS: bpf_u_int32 = 0x00000008;
 impl ::std::default::Default for Struct_pcap_if {
     fn default() -> Struct_pcap_if { unsafe { ::std::mem::zeroed() } }
 }
@@ -140,6 +161,8 @@ extern "C" {
      -> ::libc::c_int;
     pub fn pcap_set_buffer_size(arg1: *mut pcap_t, arg2: ::libc::c_int)
      -> ::libc::c_int;
+    pub fn pcap_set_immediate_mode(arg1: *mut pcap_t, arg2: ::libc::c_int)
+     -> ::libc::c_int;
     pub fn pcap_activate(arg1: *mut pcap_t) -> ::libc::c_int;
     pub fn pcap_apple_set_exthdr(p: *mut pcap_t, arg1: ::libc::c_int)
      -> ::libc::c_int;
@@ -153,16 +176,27 @@ extern "C" {
      -> *const ::libc::c_char;
     pub fn pcap_tstamp_type_val_to_description(arg1: ::libc::c_int)
      -> *const ::libc::c_char;
+    pub fn pcap_set_tstamp_precision(arg1: *mut pcap_t, arg2: ::libc::c_int)
+     -> ::libc::c_int;
+    pub fn pcap_get_tstamp_precision(arg1: *mut pcap_t) -> ::libc::c_int;
     pub fn pcap_open_live(arg1: *const ::libc::c_char, arg2: ::libc::c_int,
                           arg3: ::libc::c_int, arg4: ::libc::c_int,
                           arg5: *mut ::libc::c_char) -> *mut pcap_t;
     pub fn pcap_open_dead(arg1: ::libc::c_int, arg2: ::libc::c_int)
      -> *mut pcap_t;
+    pub fn pcap_open_dead_with_tstamp_precision(arg1: ::libc::c_int,
+                                                arg2: ::libc::c_int,
+                                                arg3: u_int)
+     -> *mut pcap_t;
 
     pub fn pcap_open_offline(arg1: *const ::libc::c_char,
                              arg2: *mut ::libc::c_char) -> *mut pcap_t;
     pub fn pcap_fopen_offline(arg1: *mut FILE, arg2: *mut ::libc::c_char)
      -> *mut pcap_t;
+    pub fn pcap_open_offline_with_tstamp_precision(arg1: *const ::libc::c_char,
+                                                   arg2: u_int,
+                                                   arg3: *mut ::libc::c_char)
+     -> *mut pcap_t;
 
     pub fn pcap_close(arg1: *mut pcap_t) -> ();
     pub fn pcap_loop(arg1: *mut pcap_t, arg2: ::libc::c_int,
@@ -230,6 +264,8 @@ extern "C" {
     pub fn pcap_dump_open(arg1: *mut pcap_t, arg2: *const ::libc::c_char)
      -> *mut pcap_dumper_t;
 
+    pub fn pcap_dump_open_append(arg1: *mut pcap_t, arg2: *const ::libc::c_char)
+     -> *mut pcap_dumper_t;
     pub fn pcap_dump_fopen(arg1: *mut pcap_t, fp: *mut FILE)
      -> *mut pcap_dumper_t;
     pub fn pcap_dump_file(arg1: *mut pcap_dumper_t) -> *mut FILE;
@@ -0,0 +1,475 @@
+//! Live capture straight from a Linux AF_PACKET socket with a TPACKET_V3 ring
+//! mapped into our memory, bypassing libpcap's read path.  The kernel fills whole
+//! blocks of packets and hands them over at once, so reading costs a poll per block
+//! rather than a call per packet, and several sockets can share an interface's
+//! traffic through a fanout group.  See packet(7) and
+//! https://www.kernel.org/doc/Documentation/networking/packet_mmap.txt.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::{mem, ptr, slice};
+use std::sync::atomic::{fence, Ordering};
+
+use libc::{self, c_int, c_void};
+
+use pcap::{self, DataLinkType, PcapError};
+use pcapll;
+
+const SOL_PACKET: c_int = 263;
+const PACKET_ADD_MEMBERSHIP: c_int = 1;
+const PACKET_RX_RING: c_int = 5;
+const PACKET_STATISTICS: c_int = 6;
+const PACKET_VERSION: c_int = 10;
+const PACKET_FANOUT: c_int = 18;
+const PACKET_MR_PROMISC: u16 = 1;
+const TPACKET_V3: c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+const ETH_P_ALL: u16 = 0x0003;
+const BPF_RET: u16 = 0x06;
+const BPF_K: u16 = 0x00;
+
+const ARPHRD_ETHER: u16 = 1;
+const ARPHRD_LOOPBACK: u16 = 772;
+const ARPHRD_IEEE80211_RADIOTAP: u16 = 803;
+const ARPHRD_NONE: u16 = 0xFFFE;
+
+// The kernel wants a frame size even for V3 rings, where packets are packed into
+// blocks regardless; it only has to divide the block size.
+const FRAME_SIZE: u32 = 2048;
+
+#[repr(C)]
+struct TpacketReq3 {
+    tp_block_size: u32,
+    tp_block_nr: u32,
+    tp_frame_size: u32,
+    tp_frame_nr: u32,
+    tp_retire_blk_tov: u32,
+    tp_sizeof_priv: u32,
+    tp_feature_req_word: u32
+}
+
+// Offsets of the fields we use in a block's tpacket_block_desc, and in the
+// tpacket3_hdr in front of each packet.  Both are in host byte order.
+const BLOCK_STATUS: usize = 8;
+const BLOCK_NUM_PKTS: usize = 12;
+const BLOCK_FIRST_PKT: usize = 16;
+const PKT_NEXT: usize = 0;
+const PKT_SEC: usize = 4;
+const PKT_NSEC: usize = 8;
+const PKT_SNAPLEN: usize = 12;
+const PKT_LEN: usize = 16;
+const PKT_MAC: usize = 24;
+
+#[repr(C)]
+#[derive(Default)]
+struct TpacketStatsV3 {
+    tp_packets: u32,
+    tp_drops: u32,
+    tp_freeze_q_cnt: u32
+}
+
+#[repr(C)]
+struct PacketMreq {
+    mr_ifindex: c_int,
+    mr_type: u16,
+    mr_alen: u16,
+    mr_address: [u8; 8]
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const pcapll::Struct_bpf_insn
+}
+
+/// How the kernel splits an interface's packets between the sockets in a fanout
+/// group.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fanout {
+    /// By flow, so both directions of a connection go to the same socket.
+    Hash,
+    /// Round robin.
+    LoadBalance,
+    /// By the CPU the packet arrived on.
+    Cpu
+}
+
+impl Fanout {
+    fn mode(self) -> u32 {
+        match self {
+            Fanout::Hash => 0,
+            Fanout::LoadBalance => 1,
+            Fanout::Cpu => 2
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RingConf {
+    /// Bytes per block; a multiple of the page size.
+    pub block_size: u32,
+    pub block_count: u32,
+    /// How long the kernel waits before handing over a block that isn't full.
+    pub block_timeout_ms: u32,
+    pub snaplen: u32,
+    pub promisc: bool,
+    /// Join the fanout group with this id, shared by every socket on the interface
+    /// that uses it.
+    pub fanout: Option<(u16, Fanout)>
+}
+
+impl Default for RingConf {
+    fn default() -> RingConf {
+        RingConf {
+            block_size: 1 << 20,
+            block_count: 64,
+            block_timeout_ms: 100,
+            snaplen: pcap::MAX_SNAPLEN,
+            promisc: false,
+            fanout: None
+        }
+    }
+}
+
+/// A packet in the ring, there until its block is handed back to the kernel.
+#[derive(Debug)]
+pub struct Frame<'a> {
+    pub sec: u32,
+    pub nsec: u32,
+    /// Length of the packet on the wire, which may be more than was captured.
+    pub len: u32,
+    pub data: &'a [u8]
+}
+
+pub struct PacketRing {
+    fd: c_int,
+    map: *mut u8,
+    block_size: usize,
+    block_count: usize,
+    block: usize,
+    dlt: DataLinkType,
+    snaplen: u32,
+    received: u32,
+    dropped: u32
+}
+
+// The mapping is only ever touched through &mut self.
+unsafe impl Send for PacketRing {}
+
+fn os_err(what: &str) -> PcapError {
+    PcapError::Open(format!("{}: {}", what, io::Error::last_os_error()))
+}
+
+fn setsockopt<T>(fd: c_int, opt: c_int, val: &T) -> c_int {
+    unsafe {
+        libc::setsockopt(fd, SOL_PACKET, opt, val as *const T as *const c_void,
+                         mem::size_of::<T>() as libc::socklen_t)
+    }
+}
+
+fn field_u16(b: &[u8], off: usize) -> Option<u16> {
+    let b = b.get(off..off + 2)?;
+    let v = u16::from(b[0]) | u16::from(b[1]) << 8;
+    Some(if cfg!(target_endian = "big") { v.swap_bytes() } else { v })
+}
+
+fn field_u32(b: &[u8], off: usize) -> Option<u32> {
+    let b = b.get(off..off + 4)?;
+    let v = u32::from(b[0]) | u32::from(b[1]) << 8 | u32::from(b[2]) << 16 |
+        u32::from(b[3]) << 24;
+    Some(if cfg!(target_endian = "big") { v.swap_bytes() } else { v })
+}
+
+/// Hand each packet in a block the kernel has filled to `f`, making sure every
+/// header and packet lies within the block.
+fn walk_block<F>(block: &[u8], mut f: F) -> Result<(), PcapError> where F: FnMut(&Frame) {
+    let bad = || PcapError::Read("malformed ring block".to_owned());
+    let num_pkts = field_u32(block, BLOCK_NUM_PKTS).ok_or_else(bad)?;
+    let mut off = field_u32(block, BLOCK_FIRST_PKT).ok_or_else(bad)? as usize;
+    for _ in 0..num_pkts {
+        let hdr = block.get(off..).ok_or_else(bad)?;
+        let mac = field_u16(hdr, PKT_MAC).ok_or_else(bad)? as usize;
+        let snaplen = field_u32(hdr, PKT_SNAPLEN).ok_or_else(bad)? as usize;
+        f(&Frame {
+            sec: field_u32(hdr, PKT_SEC).ok_or_else(bad)?,
+            nsec: field_u32(hdr, PKT_NSEC).ok_or_else(bad)?,
+            len: field_u32(hdr, PKT_LEN).ok_or_else(bad)?,
+            data: hdr.get(mac..mac + snaplen).ok_or_else(bad)?
+        });
+        off += field_u32(hdr, PKT_NEXT).ok_or_else(bad)? as usize;
+    }
+    Ok(())
+}
+
+/// A filter program that passes every packet, cut down to `snaplen` bytes.
+pub fn snaplen_filter(snaplen: u32) -> [pcapll::Struct_bpf_insn; 1] {
+    [pcapll::Struct_bpf_insn { code: BPF_RET | BPF_K, jt: 0, jf: 0, k: snaplen }]
+}
+
+/// The link type of packets read raw from `dev`, going by its hardware type.
+fn datalink(dev: &str) -> Result<DataLinkType, PcapError> {
+    let path = format!("/sys/class/net/{}/type", dev);
+    let mut s = String::new();
+    File::open(&path).and_then(|mut f| f.read_to_string(&mut s))
+        .map_err(|e| PcapError::Open(format!("{}: {}", dev, e)))?;
+    match s.trim().parse::<u16>() {
+        Ok(ARPHRD_ETHER) | Ok(ARPHRD_LOOPBACK) => Ok(pcap::DLT_ETHERNET),
+        Ok(ARPHRD_IEEE80211_RADIOTAP) => Ok(pcap::DLT_IEEE802_11_RADIO),
+        Ok(ARPHRD_NONE) => Ok(pcap::DLT_RAW),
+        _ => Err(PcapError::Open(format!("{}: unsupported hardware type {}", dev, s.trim())))
+    }
+}
+
+impl PacketRing {
+    pub fn open(dev: &str, conf: &RingConf) -> Result<PacketRing, PcapError> {
+        let dlt = datalink(dev)?;
+        let c_dev = ::std::ffi::CString::new(dev.as_bytes())?;
+        let ifindex = unsafe { libc::if_nametoindex(c_dev.as_ptr()) };
+        if ifindex == 0 {
+            return Err(os_err(dev));
+        }
+
+        // Nothing comes in until the socket's bound, so the ring's set up first.
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(os_err("socket"));
+        }
+        let mut ring = PacketRing {
+            fd: fd,
+            map: ptr::null_mut(),
+            block_size: conf.block_size as usize,
+            block_count: conf.block_count as usize,
+            block: 0,
+            dlt: dlt,
+            snaplen: conf.snaplen,
+            received: 0,
+            dropped: 0
+        };
+
+        if setsockopt(fd, PACKET_VERSION, &TPACKET_V3) != 0 {
+            return Err(os_err("PACKET_VERSION"));
+        }
+        let req = TpacketReq3 {
+            tp_block_size: conf.block_size,
+            tp_block_nr: conf.block_count,
+            tp_frame_size: FRAME_SIZE,
+            tp_frame_nr: conf.block_size / FRAME_SIZE * conf.block_count,
+            tp_retire_blk_tov: conf.block_timeout_ms,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0
+        };
+        if setsockopt(fd, PACKET_RX_RING, &req) != 0 {
+            return Err(os_err("PACKET_RX_RING"));
+        }
+        let map = unsafe {
+            libc::mmap(ptr::null_mut(), ring.block_size * ring.block_count,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED | libc::MAP_POPULATE, fd, 0)
+        };
+        if map == libc::MAP_FAILED {
+            return Err(os_err("mmap"));
+        }
+        ring.map = map as *mut u8;
+        // The kernel only cuts packets down to the snaplen when a filter says to.
+        ring.clear_filter()?;
+
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = ETH_P_ALL.to_be();
+        addr.sll_ifindex = ifindex as c_int;
+        let res = unsafe {
+            libc::bind(fd, &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                       mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t)
+        };
+        if res != 0 {
+            return Err(os_err("bind"));
+        }
+
+        if conf.promisc {
+            let mreq = PacketMreq {
+                mr_ifindex: ifindex as c_int,
+                mr_type: PACKET_MR_PROMISC,
+                mr_alen: 0,
+                mr_address: [0; 8]
+            };
+            if setsockopt(fd, PACKET_ADD_MEMBERSHIP, &mreq) != 0 {
+                return Err(os_err("PACKET_ADD_MEMBERSHIP"));
+            }
+        }
+        if let Some((group, mode)) = conf.fanout {
+            let arg = u32::from(group) | (mode.mode() << 16);
+            if setsockopt(fd, PACKET_FANOUT, &arg) != 0 {
+                return Err(os_err("PACKET_FANOUT"));
+            }
+        }
+        Ok(ring)
+    }
+
+    /// The link type of the packets in the ring.
+    pub fn datalink(&self) -> DataLinkType {
+        self.dlt
+    }
+
+    /// How many bytes of each packet are captured.
+    pub fn snaplen(&self) -> u32 {
+        self.snaplen
+    }
+
+    /// Go back to passing every packet, cut down to the snaplen.
+    pub fn clear_filter(&mut self) -> Result<(), PcapError> {
+        let snaplen = self.snaplen;
+        self.set_filter(&snaplen_filter(snaplen))
+    }
+
+    /// Have the kernel run `insns` on each packet before it goes in the ring.  As
+    /// with libpcap's own filters, the program's return value is also the snaplen.
+    pub fn set_filter(&mut self, insns: &[pcapll::Struct_bpf_insn]) -> Result<(), PcapError> {
+        let prog = SockFprog { len: insns.len() as u16, filter: insns.as_ptr() };
+        let res = unsafe {
+            libc::setsockopt(self.fd, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER,
+                             &prog as *const SockFprog as *const c_void,
+                             mem::size_of::<SockFprog>() as libc::socklen_t)
+        };
+        if res != 0 {
+            return Err(PcapError::Filter(io::Error::last_os_error().to_string()));
+        }
+        Ok(())
+    }
+
+    /// Packets seen and dropped by the kernel since the ring was opened.
+    pub fn stats(&mut self) -> Result<(u32, u32), PcapError> {
+        let mut st = TpacketStatsV3::default();
+        let mut len = mem::size_of::<TpacketStatsV3>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(self.fd, SOL_PACKET, PACKET_STATISTICS,
+                             &mut st as *mut TpacketStatsV3 as *mut c_void, &mut len)
+        };
+        if res != 0 {
+            return Err(PcapError::Stats(io::Error::last_os_error().to_string()));
+        }
+        // Reading the counters resets them.
+        self.received = self.received.wrapping_add(st.tp_packets);
+        self.dropped = self.dropped.wrapping_add(st.tp_drops);
+        Ok((self.received, self.dropped))
+    }
+
+    /// Wait up to `timeout_ms` for the next block and hand each of its packets to
+    /// `f`.  Returns `Ok(false)` if no block was ready in time.
+    pub fn next<F>(&mut self, timeout_ms: c_int, f: F) -> Result<bool, PcapError>
+        where F: FnMut(&Frame)
+    {
+        let block = unsafe { self.map.offset((self.block * self.block_size) as isize) };
+        let status = unsafe { block.offset(BLOCK_STATUS as isize) as *mut u32 };
+        if unsafe { ptr::read_volatile(status) } & TP_STATUS_USER == 0 {
+            let mut pfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+            match unsafe { libc::poll(&mut pfd, 1, timeout_ms) } {
+                n if n < 0 => {
+                    let e = io::Error::last_os_error();
+                    return match e.kind() {
+                        io::ErrorKind::Interrupted => Ok(false),
+                        _ => Err(PcapError::Read(e.to_string()))
+                    };
+                }
+                _ if pfd.revents & libc::POLLERR != 0 => {
+                    return Err(PcapError::Read("socket error".to_owned()));
+                }
+                _ => {}
+            }
+            if unsafe { ptr::read_volatile(status) } & TP_STATUS_USER == 0 {
+                return Ok(false);
+            }
+        }
+        // Don't read the packets until we've seen the kernel's done with them.
+        fence(Ordering::Acquire);
+
+        let res = walk_block(unsafe { slice::from_raw_parts(block, self.block_size) }, f);
+        // Hand the block back even if it was garbled, so the ring keeps moving.
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(status, TP_STATUS_KERNEL); }
+        self.block = (self.block + 1) % self.block_count;
+        res.map(|()| true)
+    }
+}
+
+impl Drop for PacketRing {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.map.is_null() {
+                libc::munmap(self.map as *mut c_void, self.block_size * self.block_count);
+            }
+            libc::close(self.fd);
+        }
+    }
+}
+
+#[cfg(test)]
+fn put_u32(b: &mut [u8], off: usize, v: u32) {
+    let v = if cfg!(target_endian = "big") { v.swap_bytes() } else { v };
+    for i in 0..4 {
+        b[off + i] = (v >> (8 * i)) as u8;
+    }
+}
+
+// A block holding packets of the given lengths, each with its data `mac` bytes
+// past its header and numbered by the first byte.
+#[cfg(test)]
+fn test_block(lens: &[u32], mac: u16) -> Vec<u8> {
+    let mut block = vec![0; 1024];
+    put_u32(&mut block, BLOCK_NUM_PKTS, lens.len() as u32);
+    put_u32(&mut block, BLOCK_FIRST_PKT, 48);
+    let mut off = 48;
+    for (i, &len) in lens.iter().enumerate() {
+        let next = if i + 1 == lens.len() { 0 } else { 128 };
+        put_u32(&mut block, off + PKT_NEXT, next);
+        put_u32(&mut block, off + PKT_SEC, 100 + i as u32);
+        put_u32(&mut block, off + PKT_NSEC, 5);
+        put_u32(&mut block, off + PKT_SNAPLEN, len);
+        put_u32(&mut block, off + PKT_LEN, len + 10);
+        block[off + PKT_MAC] = mac as u8;
+        block[off + PKT_MAC + 1] = (mac >> 8) as u8;
+        if cfg!(target_endian = "big") {
+            block.swap(off + PKT_MAC, off + PKT_MAC + 1);
+        }
+        block[off + mac as usize] = i as u8;
+        off += next as usize;
+    }
+    block
+}
+
+#[test]
+fn test_walk_block() {
+    let mut frames = vec![];
+    walk_block(&test_block(&[60, 1, 42], 32), |f| {
+        frames.push((f.sec, f.nsec, f.len, f.data.len(), f.data[0]));
+    }).unwrap();
+    assert_eq!(frames, vec![(100, 5, 70, 60, 0), (101, 5, 11, 1, 1), (102, 5, 52, 42, 2)]);
+
+    let mut count = 0;
+    walk_block(&test_block(&[], 32), |_| count += 1).unwrap();
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn test_walk_block_bounds() {
+    // A packet running off the end of the block.
+    let mut block = test_block(&[60, 60], 32);
+    put_u32(&mut block, 48 + 128 + PKT_SNAPLEN, 2000);
+    let mut count = 0;
+    assert!(walk_block(&block, |_| count += 1).is_err());
+    assert_eq!(count, 1);
+
+    // More packets than there are headers in the block.
+    let mut block = test_block(&[60], 32);
+    put_u32(&mut block, BLOCK_NUM_PKTS, 3);
+    put_u32(&mut block, 48 + PKT_NEXT, 1000);
+    assert!(walk_block(&block, |_| {}).is_err());
+
+    assert!(walk_block(&[0; 8], |_| {}).is_err());
+}
+
+#[test]
+fn test_snaplen_filter() {
+    let prog = snaplen_filter(96);
+    assert_eq!((prog[0].code, prog[0].jt, prog[0].jf, prog[0].k), (0x06, 0, 0, 96));
+}
@@ -0,0 +1,997 @@
+//! A reader for pcap and pcapng savefiles that doesn't go through libpcap, so
+//! that pcapng files with several interfaces and link types can be read.  See
+//! https://www.ietf.org/archive/id/draft-ietf-opsawg-pcapng-01.html for pcapng.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use pcap::{self, PcapData, PcapError};
+
+const PCAP_MAGIC_MICRO: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANO: u32 = 0xa1b2_3c4d;
+
+const BT_SHB: u32 = 0x0a0d_0d0a;
+const BT_IDB: u32 = 0x0000_0001;
+const BT_PB: u32 = 0x0000_0002;
+const BT_SPB: u32 = 0x0000_0003;
+const BT_NRB: u32 = 0x0000_0004;
+const BT_EPB: u32 = 0x0000_0006;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_DESCRIPTION: u16 = 3;
+const IF_TSRESOL: u16 = 9;
+const IF_FILTER: u16 = 11;
+const IF_TSOFFSET: u16 = 14;
+
+const NRES_ENDOFRECORD: u16 = 0;
+const NRES_IP4RECORD: u16 = 1;
+const NRES_IP6RECORD: u16 = 2;
+
+// The upper bits of a classic pcap link type are used for FCS length and flags.
+const LINKTYPE_MASK: u32 = 0x03ff_ffff;
+
+// Anything bigger is taken to be a corrupt file rather than something to allocate.
+const MAX_BLOCK_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Pcap,
+    Pcapng
+}
+
+/// Units of an interface's raw timestamps: 10^-n or 2^-n seconds.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Resolution {
+    Dec(u32),
+    Bin(u32)
+}
+
+impl Resolution {
+    fn from_raw(v: u8) -> Result<Resolution, PcapError> {
+        let res = if v & 0x80 != 0 {
+            Resolution::Bin(u32::from(v & 0x7f))
+        } else {
+            Resolution::Dec(u32::from(v))
+        };
+        match res {
+            Resolution::Dec(n) if n > 19 => Err(bad("timestamp resolution out of range")),
+            Resolution::Bin(n) if n > 63 => Err(bad("timestamp resolution out of range")),
+            _ => Ok(res)
+        }
+    }
+
+    /// Split a raw timestamp into seconds and nanoseconds.
+    fn split(self, ts: u64) -> (u64, u32) {
+        match self {
+            Resolution::Dec(n) => {
+                let units = 10u64.pow(n);
+                let frac = ts % units;
+                let nsec = if n <= 9 {
+                    frac * 10u64.pow(9 - n)
+                } else {
+                    frac / 10u64.pow(n - 9)
+                };
+                (ts / units, nsec as u32)
+            }
+            Resolution::Bin(0) => (ts, 0),
+            Resolution::Bin(n) => {
+                let frac = ts & ((1u64 << n) - 1);
+                (ts >> n, ((u128::from(frac) * 1_000_000_000) >> n) as u32)
+            }
+        }
+    }
+}
+
+/// An interface packets were captured on.  Classic pcap files have exactly one;
+/// pcapng files have one per Interface Description Block in the current section.
+#[derive(Clone, Debug)]
+pub struct Interface {
+    /// The LINKTYPE_ value from the file, which mostly but not always matches the
+    /// platform's DLT_ value.
+    pub linktype: u32,
+    pub snaplen: u32,
+    pub name: Option<String>,
+    pub description: Option<String>,
+    /// The capture filter, when it was a libpcap filter expression.
+    pub filter: Option<String>,
+    tsresol: Resolution,
+    tsoffset: i64
+}
+
+impl Interface {
+    /// An interface to describe to a `PcapngWriter`.
+    pub fn new(linktype: u32, snaplen: u32) -> Interface {
+        Interface::with_resolution(linktype, snaplen, Resolution::Dec(6))
+    }
+
+    fn with_resolution(linktype: u32, snaplen: u32, tsresol: Resolution) -> Interface {
+        Interface {
+            linktype: linktype,
+            snaplen: snaplen,
+            name: None,
+            description: None,
+            filter: None,
+            tsresol: tsresol,
+            tsoffset: 0
+        }
+    }
+}
+
+/// A name resolution record from a pcapng file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NameRecord {
+    pub addr: IpAddr,
+    pub names: Vec<String>
+}
+
+/// A packet as read from the file.  `data` points into the reader's buffer, so
+/// it's only around until the next read.
+#[derive(Debug)]
+pub struct Packet<'a> {
+    /// Index into `Savefile::interfaces`.
+    pub interface: u32,
+    pub linktype: u32,
+    pub sec: i64,
+    pub nsec: u32,
+    /// Length of the packet on the wire, which may be more than was captured.
+    pub len: u32,
+    pub data: &'a [u8],
+    pub comment: Option<String>
+}
+
+impl<'a> Packet<'a> {
+    /// A packet read from a session, to write to `interface` of a `PcapngWriter`.
+    pub fn from_data(interface: u32, pkt: &'a PcapData, comment: Option<String>) -> Packet<'a> {
+        let ts = pkt.ts();
+        Packet {
+            interface: interface,
+            linktype: pcap::dlt_to_linktype(pkt.datalink()),
+            sec: ts.sec(),
+            nsec: ts.nsec() as u32,
+            len: pkt.len(),
+            data: pkt.data(),
+            comment: comment
+        }
+    }
+}
+
+pub struct Savefile<R> {
+    rdr: R,
+    format: Format,
+    big_endian: bool,
+    interfaces: Vec<Interface>,
+    names: Vec<NameRecord>,
+    comment: Option<String>,
+    // The body of the block (or the classic pcap record) being read.
+    buf: Vec<u8>,
+    // A packet block that was read while looking for the section's interfaces,
+    // and hasn't been handed out yet.
+    pending: Option<u32>
+}
+
+impl Savefile<BufReader<File>> {
+    pub fn open(path: &str) -> Result<Savefile<BufReader<File>>, PcapError> {
+        let f = File::open(path).map_err(|e| PcapError::Open(format!("{}: {}", path, e)))?;
+        Savefile::new(BufReader::new(f))
+    }
+}
+
+impl<R: Read> Savefile<R> {
+    /// Start reading a savefile, working out from its first few bytes whether it's
+    /// pcap or pcapng and which byte order it was written in.
+    pub fn new(mut rdr: R) -> Result<Savefile<R>, PcapError> {
+        let mut magic = [0u8; 4];
+        match fill(&mut rdr, &mut magic) {
+            Ok(4) => {}
+            Ok(_) => return Err(PcapError::Open("file is too short".to_owned())),
+            Err(e) => return Err(PcapError::Open(e.to_string()))
+        }
+
+        let mut sf = Savefile {
+            rdr: rdr,
+            format: Format::Pcap,
+            big_endian: false,
+            interfaces: Vec::new(),
+            names: Vec::new(),
+            comment: None,
+            buf: Vec::new(),
+            pending: None
+        };
+
+        if read_u32(&magic, true) == BT_SHB {
+            sf.format = Format::Pcapng;
+            sf.read_block_body(magic)?;
+            sf.parse_shb()?;
+            // Interfaces come before the packets that use them; read up to the
+            // first packet so they're known before anyone asks.
+            sf.pending = sf.next_packet_block()?;
+            return Ok(sf);
+        }
+
+        let nano = match (read_u32(&magic, false), read_u32(&magic, true)) {
+            (PCAP_MAGIC_MICRO, _) => false,
+            (PCAP_MAGIC_NANO, _) => true,
+            (_, PCAP_MAGIC_MICRO) => { sf.big_endian = true; false }
+            (_, PCAP_MAGIC_NANO) => { sf.big_endian = true; true }
+            _ => return Err(PcapError::Open("unknown file format".to_owned()))
+        };
+        let mut hdr = [0u8; 20];
+        sf.read_exact(&mut hdr)?;
+        // Skipping the version, thiszone (always 0 in practice) and sigfigs.
+        let snaplen = read_u32(&hdr[12..], sf.big_endian);
+        let linktype = read_u32(&hdr[16..], sf.big_endian) & LINKTYPE_MASK;
+        let tsresol = Resolution::Dec(if nano { 9 } else { 6 });
+        sf.interfaces.push(Interface::with_resolution(linktype, snaplen, tsresol));
+        Ok(sf)
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// The interfaces described so far in the current section.
+    pub fn interfaces(&self) -> &[Interface] {
+        &self.interfaces
+    }
+
+    /// Name resolution records read so far.
+    pub fn name_records(&self) -> &[NameRecord] {
+        &self.names
+    }
+
+    /// The current section's comment, if it has one.
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_ref().map(|c| &c[..])
+    }
+
+    /// Read the next packet, or `None` at the end of the file.
+    pub fn next_packet(&mut self) -> Result<Option<Packet>, PcapError> {
+        match self.format {
+            Format::Pcap => self.next_pcap_packet(),
+            Format::Pcapng => {
+                let typ = match self.pending.take() {
+                    Some(typ) => Some(typ),
+                    None => self.next_packet_block()?
+                };
+                match typ {
+                    Some(typ) => self.parse_packet_block(typ).map(Some),
+                    None => Ok(None)
+                }
+            }
+        }
+    }
+
+    fn next_pcap_packet(&mut self) -> Result<Option<Packet>, PcapError> {
+        let mut rec = [0u8; 16];
+        match fill(&mut self.rdr, &mut rec) {
+            Ok(0) => return Ok(None),
+            Ok(16) => {}
+            Ok(_) => return Err(truncated()),
+            Err(e) => return Err(PcapError::Read(e.to_string()))
+        }
+        let big = self.big_endian;
+        let caplen = read_u32(&rec[8..], big) as usize;
+        if caplen > MAX_BLOCK_LEN {
+            return Err(bad("packet length out of range"));
+        }
+        self.buf.resize(caplen, 0);
+        fill_exact(&mut self.rdr, &mut self.buf)?;
+
+        let iface = &self.interfaces[0];
+        let frac = u64::from(read_u32(&rec[4..], big));
+        let (_, nsec) = iface.tsresol.split(frac);
+        Ok(Some(Packet {
+            interface: 0,
+            linktype: iface.linktype,
+            sec: i64::from(read_u32(&rec, big)),
+            nsec: nsec,
+            len: read_u32(&rec[12..], big),
+            data: &self.buf,
+            comment: None
+        }))
+    }
+
+    // Read blocks until one holding a packet turns up, taking note of interfaces
+    // and names along the way.  Returns the packet block's type, or `None` at the
+    // end of the file.
+    fn next_packet_block(&mut self) -> Result<Option<u32>, PcapError> {
+        loop {
+            let mut typ = [0u8; 4];
+            match fill(&mut self.rdr, &mut typ) {
+                Ok(0) => return Ok(None),
+                Ok(4) => {}
+                Ok(_) => return Err(truncated()),
+                Err(e) => return Err(PcapError::Read(e.to_string()))
+            }
+            match self.read_block_body(typ)? {
+                BT_SHB => self.parse_shb()?,
+                BT_IDB => self.parse_idb()?,
+                BT_NRB => self.parse_nrb()?,
+                typ @ BT_EPB | typ @ BT_SPB | typ @ BT_PB => return Ok(Some(typ)),
+                // Statistics, custom, decryption secrets and so on.
+                _ => {}
+            }
+        }
+    }
+
+    // Read the rest of a block whose type field has already been read, leaving its
+    // body in `buf`.  A section header also sets the byte order for the blocks
+    // that follow it.
+    fn read_block_body(&mut self, typ_bytes: [u8; 4]) -> Result<u32, PcapError> {
+        let mut len_bytes = [0u8; 4];
+        self.read_exact(&mut len_bytes)?;
+
+        let is_shb = read_u32(&typ_bytes, true) == BT_SHB;
+        if is_shb {
+            let mut bom = [0u8; 4];
+            self.read_exact(&mut bom)?;
+            self.big_endian = match read_u32(&bom, true) {
+                BYTE_ORDER_MAGIC => true,
+                m if m.swap_bytes() == BYTE_ORDER_MAGIC => false,
+                _ => return Err(bad("bad byte-order magic"))
+            };
+            self.buf.clear();
+            self.buf.extend_from_slice(&bom);
+        }
+
+        let big = self.big_endian;
+        let typ = read_u32(&typ_bytes, big);
+        let len = read_u32(&len_bytes, big) as usize;
+        let min_len = if is_shb { 28 } else { 12 };
+        if len < min_len || len % 4 != 0 || len > MAX_BLOCK_LEN {
+            return Err(bad("bad block length"));
+        }
+
+        let start = if is_shb { 4 } else { 0 };
+        self.buf.resize(len - 12, 0);
+        fill_exact(&mut self.rdr, &mut self.buf[start..])?;
+
+        let mut trailer = [0u8; 4];
+        self.read_exact(&mut trailer)?;
+        if read_u32(&trailer, big) as usize != len {
+            return Err(bad("block lengths don't match"));
+        }
+        Ok(typ)
+    }
+
+    fn parse_shb(&mut self) -> Result<(), PcapError> {
+        let major = read_u16(&self.buf[4..], self.big_endian);
+        if major != 1 {
+            return Err(bad(&format!("unsupported pcapng version {}", major)));
+        }
+        // A new section starts its interface numbering over.
+        self.interfaces.clear();
+        self.comment = None;
+        for (code, val) in parse_options(&self.buf[16..], self.big_endian)? {
+            if code == OPT_COMMENT {
+                self.comment = Some(String::from_utf8_lossy(val).into_owned());
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_idb(&mut self) -> Result<(), PcapError> {
+        let big = self.big_endian;
+        if self.buf.len() < 8 {
+            return Err(bad("interface description block too short"));
+        }
+        let linktype = u32::from(read_u16(&self.buf, big));
+        let snaplen = read_u32(&self.buf[4..], big);
+        let mut iface = Interface::new(linktype, snaplen);
+        for (code, val) in parse_options(&self.buf[8..], big)? {
+            match code {
+                IF_NAME => iface.name = Some(option_str(val)),
+                IF_DESCRIPTION => iface.description = Some(option_str(val)),
+                // The first byte says what kind of filter follows; 0 is a libpcap one.
+                IF_FILTER if val.first() == Some(&0) => {
+                    iface.filter = Some(option_str(&val[1..]))
+                }
+                IF_TSRESOL if !val.is_empty() => iface.tsresol = Resolution::from_raw(val[0])?,
+                IF_TSOFFSET if val.len() >= 8 => iface.tsoffset = read_u64(val, big) as i64,
+                _ => {}
+            }
+        }
+        self.interfaces.push(iface);
+        Ok(())
+    }
+
+    fn parse_nrb(&mut self) -> Result<(), PcapError> {
+        let big = self.big_endian;
+        let mut rest = &self.buf[..];
+        while rest.len() >= 4 {
+            let typ = read_u16(rest, big);
+            let len = usize::from(read_u16(&rest[2..], big));
+            if typ == NRES_ENDOFRECORD {
+                break;
+            }
+            let val = rest.get(4..4 + len).ok_or_else(truncated_block)?;
+            let (addr, names) = match typ {
+                NRES_IP4RECORD if len >= 4 => {
+                    let a = [val[0], val[1], val[2], val[3]];
+                    (IpAddr::V4(Ipv4Addr::from(a)), &val[4..])
+                }
+                NRES_IP6RECORD if len >= 16 => {
+                    let mut a = [0u8; 16];
+                    a.copy_from_slice(&val[..16]);
+                    (IpAddr::V6(Ipv6Addr::from(a)), &val[16..])
+                }
+                _ => {
+                    rest = rest.get(4 + pad4(len)..).unwrap_or(&[]);
+                    continue;
+                }
+            };
+            self.names.push(NameRecord {
+                addr: addr,
+                names: names.split(|&b| b == 0)
+                    .filter(|n| !n.is_empty())
+                    .map(|n| String::from_utf8_lossy(n).into_owned())
+                    .collect()
+            });
+            rest = rest.get(4 + pad4(len)..).unwrap_or(&[]);
+        }
+        Ok(())
+    }
+
+    fn parse_packet_block(&self, typ: u32) -> Result<Packet, PcapError> {
+        let big = self.big_endian;
+        let buf = &self.buf[..];
+
+        // (interface, timestamp, captured length, wire length, offset of the data)
+        let (id, ts, caplen, len, start) = match typ {
+            BT_EPB | BT_PB => {
+                if buf.len() < 20 {
+                    return Err(truncated_block());
+                }
+                // The obsolete packet block has a 16-bit interface id and a drop count
+                // where the enhanced packet block has a 32-bit interface id.
+                let id = if typ == BT_EPB {
+                    read_u32(buf, big)
+                } else {
+                    u32::from(read_u16(buf, big))
+                };
+                let ts = u64::from(read_u32(&buf[4..], big)) << 32 |
+                    u64::from(read_u32(&buf[8..], big));
+                (id, ts, read_u32(&buf[12..], big) as usize, read_u32(&buf[16..], big), 20)
+            }
+            _ => {
+                // Simple packet blocks are always from the first interface, carry no
+                // timestamp, and only give the captured length by way of the block
+                // length.
+                if buf.len() < 4 {
+                    return Err(truncated_block());
+                }
+                let len = read_u32(buf, big);
+                let snaplen = self.interfaces.first().map_or(0, |i| i.snaplen);
+                let mut caplen = (buf.len() - 4).min(len as usize);
+                if snaplen != 0 {
+                    caplen = caplen.min(snaplen as usize);
+                }
+                (0, 0, caplen, len, 4)
+            }
+        };
+
+        let iface = self.interfaces.get(id as usize)
+            .ok_or_else(|| bad(&format!("packet for unknown interface {}", id)))?;
+        let data = buf.get(start..start + caplen).ok_or_else(truncated_block)?;
+
+        let mut comment = None;
+        if typ != BT_SPB {
+            let opts = buf.get(start + pad4(caplen)..).unwrap_or(&[]);
+            for (code, val) in parse_options(opts, big)? {
+                if code == OPT_COMMENT {
+                    comment = Some(String::from_utf8_lossy(val).into_owned());
+                }
+            }
+        }
+
+        let (sec, nsec) = iface.tsresol.split(ts);
+        Ok(Packet {
+            interface: id,
+            linktype: iface.linktype,
+            sec: (sec as i64).wrapping_add(iface.tsoffset),
+            nsec: nsec,
+            len: len,
+            data: data,
+            comment: comment
+        })
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), PcapError> {
+        fill_exact(&mut self.rdr, buf)
+    }
+}
+
+fn bad(msg: &str) -> PcapError {
+    PcapError::Read(msg.to_owned())
+}
+
+fn truncated() -> PcapError {
+    bad("file ends in the middle of a packet")
+}
+
+fn truncated_block() -> PcapError {
+    bad("block is too short for its contents")
+}
+
+// Like read_exact, but tells a clean end of file (nothing read) from a short read
+// by returning how much was read.
+fn fill<R: Read>(rdr: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match rdr.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(n)
+}
+
+fn fill_exact<R: Read>(rdr: &mut R, buf: &mut [u8]) -> Result<(), PcapError> {
+    match fill(rdr, buf) {
+        Ok(n) if n == buf.len() => Ok(()),
+        Ok(_) => Err(truncated()),
+        Err(e) => Err(PcapError::Read(e.to_string()))
+    }
+}
+
+fn pad4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u16(b: &[u8], big: bool) -> u16 {
+    let v = u16::from(b[0]) | u16::from(b[1]) << 8;
+    if big { v.swap_bytes() } else { v }
+}
+
+fn read_u32(b: &[u8], big: bool) -> u32 {
+    let v = u32::from(b[0]) | u32::from(b[1]) << 8 | u32::from(b[2]) << 16 |
+        u32::from(b[3]) << 24;
+    if big { v.swap_bytes() } else { v }
+}
+
+fn read_u64(b: &[u8], big: bool) -> u64 {
+    let (lo, hi) = (u64::from(read_u32(b, big)), u64::from(read_u32(&b[4..], big)));
+    if big { lo << 32 | hi } else { hi << 32 | lo }
+}
+
+fn option_str(val: &[u8]) -> String {
+    // Some writers include a trailing nul.
+    let end = val.iter().position(|&b| b == 0).unwrap_or(val.len());
+    String::from_utf8_lossy(&val[..end]).into_owned()
+}
+
+// The options at the end of a block, as (code, value) pairs.
+fn parse_options(mut b: &[u8], big: bool) -> Result<Vec<(u16, &[u8])>, PcapError> {
+    let mut out = Vec::new();
+    while b.len() >= 4 {
+        let code = read_u16(b, big);
+        let len = usize::from(read_u16(&b[2..], big));
+        if code == OPT_ENDOFOPT {
+            break;
+        }
+        out.push((code, b.get(4..4 + len).ok_or_else(truncated_block)?));
+        b = b.get(4 + pad4(len)..).unwrap_or(&[]);
+    }
+    Ok(out)
+}
+
+/// Writes pcapng files, which unlike the files `PcapDumper` writes can describe
+/// where the packets came from and carry a comment on each packet.  Timestamps are
+/// always written with nanosecond resolution.
+pub struct PcapngWriter<W: Write> {
+    out: W,
+    interfaces: u32,
+    written: u64
+}
+
+impl PcapngWriter<BufWriter<File>> {
+    /// Start a new file at `path`, or with `append` add a new section to the end of
+    /// whatever's there.  Interfaces have to be added again for the new section.
+    pub fn create(path: &str,
+                  append: bool,
+                  app: &str) -> Result<PcapngWriter<BufWriter<File>>, PcapError> {
+        let f = OpenOptions::new().write(true).create(true).append(append).truncate(!append)
+            .open(path)
+            .map_err(|e| PcapError::Open(format!("{}: {}", path, e)))?;
+        let existing = f.metadata().map(|m| m.len()).unwrap_or(0);
+        let mut w = PcapngWriter::new(BufWriter::new(f), app)?;
+        w.written += existing;
+        Ok(w)
+    }
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Start a section, noting `app` as the application that wrote it.
+    pub fn new(out: W, app: &str) -> Result<PcapngWriter<W>, PcapError> {
+        let mut body = Vec::new();
+        put_u32(&mut body, BYTE_ORDER_MAGIC, false);
+        put_u16(&mut body, 1, false);
+        put_u16(&mut body, 0, false);
+        // Section length isn't known up front.
+        body.extend_from_slice(&[0xff; 8]);
+        put_option(&mut body, SHB_USERAPPL, app.as_bytes(), false);
+        put_option(&mut body, OPT_ENDOFOPT, b"", false);
+
+        let mut w = PcapngWriter { out: out, interfaces: 0, written: 0 };
+        w.write_block(BT_SHB, &body)?;
+        Ok(w)
+    }
+
+    /// Describe an interface, returning the id its packets are written with.
+    pub fn add_interface(&mut self, iface: &Interface) -> Result<u32, PcapError> {
+        let mut body = Vec::new();
+        put_u16(&mut body, iface.linktype as u16, false);
+        put_u16(&mut body, 0, false);
+        put_u32(&mut body, iface.snaplen, false);
+        if let Some(ref name) = iface.name {
+            put_option(&mut body, IF_NAME, name.as_bytes(), false);
+        }
+        if let Some(ref desc) = iface.description {
+            put_option(&mut body, IF_DESCRIPTION, desc.as_bytes(), false);
+        }
+        if let Some(ref filter) = iface.filter {
+            let mut val = vec![0];
+            val.extend_from_slice(filter.as_bytes());
+            put_option(&mut body, IF_FILTER, &val, false);
+        }
+        put_option(&mut body, IF_TSRESOL, &[9], false);
+        put_option(&mut body, OPT_ENDOFOPT, b"", false);
+
+        self.write_block(BT_IDB, &body)?;
+        self.interfaces += 1;
+        Ok(self.interfaces - 1)
+    }
+
+    /// Write a packet, with its comment if it has one.  Its interface has to have
+    /// been added already.
+    pub fn write_packet(&mut self, pkt: &Packet) -> Result<(), PcapError> {
+        if pkt.interface >= self.interfaces {
+            return Err(PcapError::Dump(format!("no interface {}", pkt.interface)));
+        }
+        let ts = (pkt.sec as u64).wrapping_mul(1_000_000_000).wrapping_add(u64::from(pkt.nsec));
+        let mut body = Vec::with_capacity(20 + pad4(pkt.data.len()) + 8);
+        put_u32(&mut body, pkt.interface, false);
+        put_u32(&mut body, (ts >> 32) as u32, false);
+        put_u32(&mut body, ts as u32, false);
+        put_u32(&mut body, pkt.data.len() as u32, false);
+        put_u32(&mut body, pkt.len, false);
+        body.extend_from_slice(pkt.data);
+        while body.len() % 4 != 0 {
+            body.push(0);
+        }
+        if let Some(ref comment) = pkt.comment {
+            // Option lengths are 16 bits, and the comment has to stay valid UTF-8.
+            let mut end = comment.len().min(0xfffc);
+            while !comment.is_char_boundary(end) {
+                end -= 1;
+            }
+            put_option(&mut body, OPT_COMMENT, &comment.as_bytes()[..end], false);
+            put_option(&mut body, OPT_ENDOFOPT, b"", false);
+        }
+        self.write_block(BT_EPB, &body)
+    }
+
+    pub fn flush(&mut self) -> Result<(), PcapError> {
+        self.out.flush().map_err(|e| PcapError::Dump(e.to_string()))
+    }
+
+    /// Bytes written so far, including anything that was in the file before.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    fn write_block(&mut self, typ: u32, body: &[u8]) -> Result<(), PcapError> {
+        let block = block(typ, body, false);
+        self.out.write_all(&block).map_err(|e| PcapError::Dump(e.to_string()))?;
+        self.written += block.len() as u64;
+        Ok(())
+    }
+}
+
+// Encoding for the writer, and for building files in the tests.
+
+fn put_u16(out: &mut Vec<u8>, v: u16, big: bool) {
+    let v = if big { v.swap_bytes() } else { v };
+    out.extend_from_slice(&[v as u8, (v >> 8) as u8]);
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32, big: bool) {
+    put_u16(out, if big { (v >> 16) as u16 } else { v as u16 }, big);
+    put_u16(out, if big { v as u16 } else { (v >> 16) as u16 }, big);
+}
+
+fn put_option(out: &mut Vec<u8>, code: u16, val: &[u8], big: bool) {
+    put_u16(out, code, big);
+    put_u16(out, val.len() as u16, big);
+    out.extend_from_slice(val);
+    while out.len() % 4 != 0 {
+        out.push(0);
+    }
+}
+
+fn block(typ: u32, body: &[u8], big: bool) -> Vec<u8> {
+    let len = 12 + pad4(body.len()) as u32;
+    let mut out = Vec::new();
+    put_u32(&mut out, typ, big);
+    put_u32(&mut out, len, big);
+    out.extend_from_slice(body);
+    while out.len() % 4 != 0 {
+        out.push(0);
+    }
+    put_u32(&mut out, len, big);
+    out
+}
+
+#[cfg(test)]
+fn shb(big: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_u32(&mut body, BYTE_ORDER_MAGIC, big);
+    put_u16(&mut body, 1, big);
+    put_u16(&mut body, 0, big);
+    body.extend_from_slice(&[0xff; 8]);
+    put_option(&mut body, OPT_COMMENT, b"test section", big);
+    put_option(&mut body, OPT_ENDOFOPT, b"", big);
+    block(BT_SHB, &body, big)
+}
+
+#[cfg(test)]
+fn idb(linktype: u16, name: &str, tsresol: Option<u8>, big: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_u16(&mut body, linktype, big);
+    put_u16(&mut body, 0, big);
+    put_u32(&mut body, 65535, big);
+    put_option(&mut body, IF_NAME, name.as_bytes(), big);
+    if let Some(r) = tsresol {
+        put_option(&mut body, IF_TSRESOL, &[r], big);
+    }
+    put_option(&mut body, OPT_ENDOFOPT, b"", big);
+    block(BT_IDB, &body, big)
+}
+
+#[cfg(test)]
+fn epb(iface: u32, ts: u64, data: &[u8], comment: Option<&str>, big: bool) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_u32(&mut body, iface, big);
+    put_u32(&mut body, (ts >> 32) as u32, big);
+    put_u32(&mut body, ts as u32, big);
+    put_u32(&mut body, data.len() as u32, big);
+    put_u32(&mut body, data.len() as u32 + 10, big);
+    body.extend_from_slice(data);
+    while body.len() % 4 != 0 {
+        body.push(0);
+    }
+    if let Some(c) = comment {
+        put_option(&mut body, OPT_COMMENT, c.as_bytes(), big);
+        put_option(&mut body, OPT_ENDOFOPT, b"", big);
+    }
+    block(BT_EPB, &body, big)
+}
+
+#[cfg(test)]
+fn classic(magic: u32, big: bool, frac: u32) -> Vec<u8> {
+    let mut out = Vec::new();
+    put_u32(&mut out, magic, big);
+    put_u16(&mut out, 2, big);
+    put_u16(&mut out, 4, big);
+    put_u32(&mut out, 0, big);
+    put_u32(&mut out, 0, big);
+    put_u32(&mut out, 65535, big);
+    put_u32(&mut out, 1, big);
+    for data in &[&[1u8, 2, 3][..], &[4u8, 5, 6, 7, 8][..]] {
+        put_u32(&mut out, 1_500_000_000, big);
+        put_u32(&mut out, frac, big);
+        put_u32(&mut out, data.len() as u32, big);
+        put_u32(&mut out, 60, big);
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+#[test]
+fn test_classic_byte_orders_and_precisions() {
+    for &(magic, big, frac, nsec) in &[(PCAP_MAGIC_MICRO, false, 250_000, 250_000_000),
+                                       (PCAP_MAGIC_MICRO, true, 250_000, 250_000_000),
+                                       (PCAP_MAGIC_NANO, false, 123_456_789, 123_456_789),
+                                       (PCAP_MAGIC_NANO, true, 123_456_789, 123_456_789)] {
+        let bytes = classic(magic, big, frac);
+        let mut sf = Savefile::new(&bytes[..]).unwrap();
+        assert_eq!(sf.format(), Format::Pcap);
+        assert_eq!(sf.interfaces().len(), 1);
+        assert_eq!(sf.interfaces()[0].linktype, 1);
+        assert_eq!(sf.interfaces()[0].snaplen, 65535);
+        {
+            let p = sf.next_packet().unwrap().unwrap();
+            assert_eq!(p.sec, 1_500_000_000);
+            assert_eq!(p.nsec, nsec);
+            assert_eq!(p.len, 60);
+            assert_eq!(p.data, &[1, 2, 3]);
+        }
+        assert_eq!(sf.next_packet().unwrap().unwrap().data, &[4, 5, 6, 7, 8]);
+        assert!(sf.next_packet().unwrap().is_none());
+    }
+}
+
+#[test]
+fn test_classic_truncated() {
+    let bytes = classic(PCAP_MAGIC_MICRO, false, 0);
+    let mut sf = Savefile::new(&bytes[..bytes.len() - 2]).unwrap();
+    assert!(sf.next_packet().unwrap().is_some());
+    assert!(sf.next_packet().is_err());
+}
+
+#[test]
+fn test_unknown_format() {
+    assert!(Savefile::new(&b"not a capture file"[..]).is_err());
+    assert!(Savefile::new(&b"ab"[..]).is_err());
+}
+
+#[test]
+fn test_pcapng_interfaces() {
+    for &big in &[false, true] {
+        let mut bytes = shb(big);
+        bytes.extend(idb(1, "eth0", None, big));
+        bytes.extend(idb(127, "wlan0", Some(9), big));
+        bytes.extend(epb(1, 1_500_000_000_123_456_789, &[9, 9, 9, 9, 9], None, big));
+        bytes.extend(epb(0, 1_500_000_000_250_000, &[1, 2, 3], Some("hello"), big));
+
+        let mut sf = Savefile::new(&bytes[..]).unwrap();
+        assert_eq!(sf.format(), Format::Pcapng);
+        assert_eq!(sf.comment(), Some("test section"));
+        assert_eq!(sf.interfaces().len(), 2);
+        assert_eq!(sf.interfaces()[0].name, Some("eth0".to_owned()));
+        assert_eq!(sf.interfaces()[1].linktype, 127);
+        {
+            let p = sf.next_packet().unwrap().unwrap();
+            assert_eq!(p.interface, 1);
+            assert_eq!(p.linktype, 127);
+            assert_eq!((p.sec, p.nsec), (1_500_000_000, 123_456_789));
+            assert_eq!(p.len, 15);
+            assert_eq!(p.data, &[9, 9, 9, 9, 9]);
+            assert_eq!(p.comment, None);
+        }
+        {
+            let p = sf.next_packet().unwrap().unwrap();
+            assert_eq!(p.interface, 0);
+            assert_eq!(p.linktype, 1);
+            assert_eq!((p.sec, p.nsec), (1_500_000_000, 250_000_000));
+            assert_eq!(p.data, &[1, 2, 3]);
+            assert_eq!(p.comment, Some("hello".to_owned()));
+        }
+        assert!(sf.next_packet().unwrap().is_none());
+    }
+}
+
+#[test]
+fn test_pcapng_simple_packets_and_names() {
+    let big = false;
+    let mut bytes = shb(big);
+    bytes.extend(idb(1, "eth0", Some(0x80 | 10), big));
+
+    let mut nrb = Vec::new();
+    put_u16(&mut nrb, NRES_IP4RECORD, big);
+    put_u16(&mut nrb, 4 + 10, big);
+    nrb.extend_from_slice(&[10, 0, 0, 1]);
+    nrb.extend_from_slice(b"a.example\0");
+    while nrb.len() % 4 != 0 {
+        nrb.push(0);
+    }
+    put_u16(&mut nrb, NRES_ENDOFRECORD, big);
+    put_u16(&mut nrb, 0, big);
+    bytes.extend(block(BT_NRB, &nrb, big));
+
+    let mut spb = Vec::new();
+    put_u32(&mut spb, 3, big);
+    spb.extend_from_slice(&[7, 7, 7, 0]);
+    bytes.extend(block(BT_SPB, &spb, big));
+    // Blocks we don't know about are skipped.
+    bytes.extend(block(0x0000_0005, &[0; 8], big));
+    bytes.extend(epb(0, (5 << 10) | 512, &[1], None, big));
+
+    let mut sf = Savefile::new(&bytes[..]).unwrap();
+    assert_eq!(sf.name_records(), &[NameRecord {
+        addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+        names: vec!["a.example".to_owned()]
+    }]);
+    assert_eq!(sf.next_packet().unwrap().unwrap().data, &[7, 7, 7]);
+    {
+        let p = sf.next_packet().unwrap().unwrap();
+        assert_eq!((p.sec, p.nsec), (5, 500_000_000));
+    }
+    assert!(sf.next_packet().unwrap().is_none());
+}
+
+#[test]
+fn test_pcapng_new_section() {
+    let mut bytes = shb(false);
+    bytes.extend(idb(1, "eth0", None, false));
+    bytes.extend(epb(0, 0, &[1], None, false));
+    bytes.extend(shb(true));
+    bytes.extend(idb(101, "tun0", None, true));
+    bytes.extend(epb(0, 0, &[2], None, true));
+
+    let mut sf = Savefile::new(&bytes[..]).unwrap();
+    assert_eq!(sf.next_packet().unwrap().unwrap().linktype, 1);
+    {
+        let p = sf.next_packet().unwrap().unwrap();
+        assert_eq!(p.linktype, 101);
+        assert_eq!(p.data, &[2]);
+    }
+    assert_eq!(sf.interfaces().len(), 1);
+}
+
+#[test]
+fn test_pcapng_unknown_interface() {
+    let mut bytes = shb(false);
+    bytes.extend(idb(1, "eth0", None, false));
+    bytes.extend(epb(3, 0, &[1], None, false));
+    let mut sf = Savefile::new(&bytes[..]).unwrap();
+    assert!(sf.next_packet().is_err());
+}
+
+#[test]
+fn test_pcapng_write_read() {
+    let mut out = Vec::new();
+    {
+        let mut w = PcapngWriter::new(&mut out, "test").unwrap();
+        let mut eth = Interface::new(1, 65535);
+        eth.name = Some("eth0".to_owned());
+        eth.filter = Some("tcp port 80".to_owned());
+        assert_eq!(w.add_interface(&eth).unwrap(), 0);
+        assert_eq!(w.add_interface(&Interface::new(127, 2048)).unwrap(), 1);
+        for &(iface, comment) in &[(1, None), (0, Some("src: router"))] {
+            w.write_packet(&Packet {
+                interface: iface,
+                linktype: 0,
+                sec: 1_500_000_000,
+                nsec: 123_456_789,
+                len: 100,
+                data: &[1, 2, 3, 4, 5],
+                comment: comment.map(String::from)
+            }).unwrap();
+        }
+        assert!(w.write_packet(&Packet {
+            interface: 2, linktype: 0, sec: 0, nsec: 0, len: 0, data: &[], comment: None
+        }).is_err());
+        assert_eq!(w.written(), out.len() as u64);
+    }
+
+    let mut sf = Savefile::new(&out[..]).unwrap();
+    assert_eq!(sf.interfaces().len(), 2);
+    assert_eq!(sf.interfaces()[0].name, Some("eth0".to_owned()));
+    assert_eq!(sf.interfaces()[0].filter, Some("tcp port 80".to_owned()));
+    assert_eq!(sf.interfaces()[1].snaplen, 2048);
+    {
+        let p = sf.next_packet().unwrap().unwrap();
+        assert_eq!(p.linktype, 127);
+        assert_eq!((p.sec, p.nsec, p.len), (1_500_000_000, 123_456_789, 100));
+        assert_eq!(p.data, &[1, 2, 3, 4, 5]);
+        assert_eq!(p.comment, None);
+    }
+    assert_eq!(sf.next_packet().unwrap().unwrap().comment, Some("src: router".to_owned()));
+    assert!(sf.next_packet().unwrap().is_none());
+}
+
+#[test]
+fn test_pcapng_long_comment() {
+    let mut out = Vec::new();
+    let short = "a".repeat(0xfffb);
+    {
+        let mut w = PcapngWriter::new(&mut out, "test").unwrap();
+        w.add_interface(&Interface::new(1, 65535)).unwrap();
+        // The two bytes of the é straddle the longest comment an option can hold.
+        w.write_packet(&Packet {
+            interface: 0, linktype: 0, sec: 0, nsec: 0, len: 1, data: &[1],
+            comment: Some(format!("{}é", short))
+        }).unwrap();
+    }
+    let mut sf = Savefile::new(&out[..]).unwrap();
+    assert_eq!(sf.next_packet().unwrap().unwrap().comment, Some(short));
+}